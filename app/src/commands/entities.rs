@@ -0,0 +1,149 @@
+use clap::Subcommand;
+use eyre::{bail, eyre};
+use lib_db::PsqlDriver;
+use lib_db::migrations::MigrationState;
+use lib_shared::Res;
+use lib_shared::env_service::EnvService;
+use lib_shared::metrics::Metrics;
+use std::collections::BTreeMap;
+use std::io::ErrorKind;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+///tables mapped to entities, the queue and outbox tables are only used through sqlx
+const TABLES: &str = "users,sessions";
+
+#[derive(Subcommand)]
+pub enum EntitiesAction {
+    ///migrate the database and regenerate the entities from its schema
+    Generate,
+    ///fail if the entities are out of sync with the schema, only migrates with `--migrate`
+    Check {
+        ///apply the pending migrations first, e.g. on the throwaway database of a CI job
+        #[arg(long)]
+        migrate: bool,
+    },
+}
+
+pub async fn run(action: EntitiesAction) -> Res {
+    let env = EnvService::load()?;
+    let psql = PsqlDriver::new(&env.database.url, &[], &env.database.pool, Metrics::new()).await?;
+    match action {
+        EntitiesAction::Generate | EntitiesAction::Check { migrate: true } => {
+            psql.connection.migrate_up().await?
+        }
+        EntitiesAction::Check { migrate: false } => {
+            let status = psql.connection.migration_status().await?;
+            if status.iter().any(|m| m.state != MigrationState::Applied) {
+                bail!(
+                    "the schema doesn't match the migrations, run `app migrate up` or pass `--migrate`"
+                );
+            }
+        }
+    }
+
+    let generated = std::env::temp_dir().join(format!("entities-{}", std::process::id()));
+    let result = generate(&env.database.url, &generated).and_then(|_| {
+        let entities = entities_dir();
+        match action {
+            EntitiesAction::Generate => replace(&generated, &entities),
+            EntitiesAction::Check { .. } => check(&generated, &entities),
+        }
+    });
+    _ = std::fs::remove_dir_all(&generated);
+    result
+}
+
+fn entities_dir() -> PathBuf {
+    Path::new(env!("CARGO_MANIFEST_DIR")).join("../lib-db/src/entities")
+}
+
+fn generate(url: &str, out: &Path) -> Res {
+    let status = Command::new("sea-orm-cli")
+        .args([
+            "generate",
+            "entity",
+            "--database-url",
+            url,
+            "--tables",
+            TABLES,
+        ])
+        .args(["--with-serde", "both", "--date-time-crate", "chrono"])
+        .arg("--output-dir")
+        .arg(out)
+        .status()
+        .map_err(|e| match e.kind() {
+            ErrorKind::NotFound => {
+                eyre!("sea-orm-cli not found, install it with `cargo install sea-orm-cli`")
+            }
+            _ => e.into(),
+        })?;
+    if !status.success() {
+        bail!("sea-orm-cli failed with {status}");
+    }
+
+    //same formatting as the committed files
+    let status = Command::new("rustfmt")
+        .args(["--edition", "2024"])
+        .args(sources(out)?.values())
+        .status()?;
+    if !status.success() {
+        bail!("rustfmt failed with {status}");
+    }
+    Ok(())
+}
+
+fn replace(generated: &Path, entities: &Path) -> Res {
+    for path in sources(entities)?.values() {
+        std::fs::remove_file(path)?;
+    }
+    for (name, path) in sources(generated)? {
+        std::fs::copy(path, entities.join(&name))?;
+        println!("generated {name}");
+    }
+    Ok(())
+}
+
+fn check(generated: &Path, entities: &Path) -> Res {
+    let generated = sources(generated)?;
+    let entities = sources(entities)?;
+    let mut stale = vec![];
+    for (name, path) in &generated {
+        match entities.get(name) {
+            Some(committed) if std::fs::read(path)? == std::fs::read(committed)? => {}
+            Some(_) => stale.push(format!("{name} differs")),
+            None => stale.push(format!("{name} is missing")),
+        }
+    }
+    stale.extend(
+        entities
+            .keys()
+            .filter(|name| !generated.contains_key(*name))
+            .map(|name| format!("{name} has no table")),
+    );
+
+    if !stale.is_empty() {
+        for s in &stale {
+            println!("{s}");
+        }
+        bail!("entities are out of sync, run `app entities generate`");
+    }
+    println!("entities are up to date");
+    Ok(())
+}
+
+fn sources(dir: &Path) -> eyre::Result<BTreeMap<String, PathBuf>> {
+    let mut sources = BTreeMap::new();
+    for entry in std::fs::read_dir(dir)? {
+        let path = entry?.path();
+        if path.extension().is_some_and(|e| e == "rs") {
+            let name = path
+                .file_name()
+                .unwrap_or_default()
+                .to_string_lossy()
+                .into_owned();
+            sources.insert(name, path);
+        }
+    }
+    Ok(sources)
+}
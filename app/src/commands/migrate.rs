@@ -0,0 +1,65 @@
+use clap::Subcommand;
+use lib_db::PsqlDriver;
+use lib_db::migrations::{MigrationState, create_migration};
+use lib_shared::Res;
+use lib_shared::env_service::EnvService;
+use lib_shared::metrics::Metrics;
+
+#[derive(Subcommand)]
+pub enum MigrateAction {
+    ///apply all pending migrations
+    Up,
+    ///revert the latest applied migrations
+    Down {
+        #[arg(default_value_t = 1)]
+        steps: usize,
+    },
+    ///list migrations and whether they are applied
+    Status,
+    ///create a new empty migration in lib-db/migrations
+    New { name: String },
+}
+
+pub async fn run(action: MigrateAction) -> Res {
+    if let MigrateAction::New { name } = action {
+        let (up, down) = create_migration(&name)?;
+        println!("created {}", up.display());
+        println!("created {}", down.display());
+        return Ok(());
+    }
+
+    let env = EnvService::load()?;
+    let psql = PsqlDriver::new(&env.database.url, &[], &env.database.pool, Metrics::new()).await?;
+    match action {
+        MigrateAction::Up => psql.connection.migrate_up().await?,
+        MigrateAction::Down { steps } => psql.connection.migrate_down(steps).await?,
+        MigrateAction::Status => {
+            let status = psql.connection.migration_status().await?;
+            for m in &status {
+                println!(
+                    "{:<16} {:<18} {}",
+                    m.version,
+                    state(&m.state),
+                    m.description
+                );
+            }
+            if status
+                .iter()
+                .any(|m| m.state == MigrationState::ChecksumMismatch)
+            {
+                eyre::bail!("applied migrations were edited");
+            }
+        }
+        MigrateAction::New { .. } => unreachable!(),
+    }
+    Ok(())
+}
+
+fn state(state: &MigrationState) -> &'static str {
+    match state {
+        MigrationState::Applied => "applied",
+        MigrationState::Pending => "pending",
+        MigrationState::ChecksumMismatch => "checksum mismatch",
+        MigrationState::Missing => "missing",
+    }
+}
@@ -0,0 +1,56 @@
+use clap::Subcommand;
+use eyre::{bail, eyre};
+use lib_db::PsqlDriver;
+use lib_db::tenant::with_tenant;
+use lib_shared::Res;
+use lib_shared::env_service::EnvService;
+use lib_shared::metrics::Metrics;
+use std::io::BufRead;
+
+///the password is read from the first line of stdin, so it stays out of the shell history
+#[derive(Subcommand)]
+pub enum UsersAction {
+    ///create a user
+    Add {
+        #[arg(long)]
+        tenant: String,
+        identity: String,
+    },
+    ///replace the password of a user
+    SetPassword {
+        #[arg(long)]
+        tenant: String,
+        identity: String,
+    },
+}
+
+pub async fn run(action: UsersAction) -> Res {
+    let env = EnvService::load()?;
+    let psql = PsqlDriver::new(&env.database.url, &[], &env.database.pool, Metrics::new()).await?;
+    let users = psql.user_auth_driver;
+    let pwd = read_password()?;
+    match action {
+        UsersAction::Add { tenant, identity } => {
+            let user =
+                with_tenant(Some(tenant.clone()), users.create_user(&identity, &pwd)).await?;
+            println!("created {identity} ({}) in {tenant}", user.id);
+        }
+        UsersAction::SetPassword { tenant, identity } => {
+            if !with_tenant(Some(tenant.clone()), users.set_password(&identity, &pwd)).await? {
+                bail!("{identity} doesn't exist in {tenant}");
+            }
+            println!("updated the password of {identity} in {tenant}");
+        }
+    }
+    Ok(())
+}
+
+fn read_password() -> eyre::Result<String> {
+    let mut line = String::new();
+    std::io::stdin().lock().read_line(&mut line)?;
+    let pwd = line.trim_end_matches(['\r', '\n']);
+    match pwd.is_empty() {
+        true => Err(eyre!("expected the password on stdin")),
+        false => Ok(pwd.to_owned()),
+    }
+}
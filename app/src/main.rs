@@ -1,12 +1,49 @@
+use clap::{Parser, Subcommand};
 use lib_shared::{Res, instrument};
 use tracing::error;
 
+mod commands;
+
+#[derive(Parser)]
+#[command(version, about)]
+struct Cli {
+    #[command(subcommand)]
+    command: Option<Command>,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    ///run the api (default)
+    Serve,
+    ///manage database migrations
+    Migrate {
+        #[command(subcommand)]
+        action: commands::migrate::MigrateAction,
+    },
+    ///manage the seaorm entities in lib-db/src/entities
+    Entities {
+        #[command(subcommand)]
+        action: commands::entities::EntitiesAction,
+    },
+    ///manage the users of a tenant
+    Users {
+        #[command(subcommand)]
+        action: commands::users::UsersAction,
+    },
+}
+
 #[tokio::main]
 #[instrument]
 async fn main() -> Res {
+    let cli = Cli::parse();
     lib_shared::init();
 
-    lib_api::run()
-        .await
-        .inspect_err(|e| error!(error = e.to_string(), "api crashed"))
+    match cli.command.unwrap_or(Command::Serve) {
+        Command::Serve => lib_api::run()
+            .await
+            .inspect_err(|e| error!(error = e.to_string(), "api crashed")),
+        Command::Migrate { action } => commands::migrate::run(action).await,
+        Command::Entities { action } => commands::entities::run(action).await,
+        Command::Users { action } => commands::users::run(action).await,
+    }
 }
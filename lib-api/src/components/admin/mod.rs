@@ -0,0 +1,108 @@
+use crate::components::ApiResult;
+use crate::components::admin::models::{AuditChainResponse, AuditEntryResponse, AuditQuery};
+use crate::middlewares::auth::{require_admin, require_authentication};
+use crate::models::ValidQuery;
+use crate::{data, get_or_return_err};
+use axum::extract::State;
+use axum::middleware::from_fn_with_state;
+use axum::routing::get;
+use axum::{Extension, Router};
+use axum_client_ip::ClientIp;
+use lib_core::app_state::AppState;
+use lib_core::services::audit_service::{AuditRecord, Outcome, actions};
+use lib_core::services::auth_service::token_claims::TokenClaims;
+use lib_db::audit_log_driver::AuditFilter;
+use serde_json::json;
+
+mod models;
+
+const DEFAULT_LIMIT: i64 = 50;
+
+pub fn routes(state: AppState) -> Router {
+    Router::new()
+        .route("/audit", get(audit))
+        .route("/audit/verify", get(verify_audit))
+        .layer(from_fn_with_state(state.clone(), require_admin))
+        .layer(from_fn_with_state(state.clone(), require_authentication))
+        .with_state(state)
+}
+
+async fn audit(
+    s: State<AppState>,
+    user: Extension<TokenClaims>,
+    ClientIp(ip): ClientIp,
+    q: ValidQuery<AuditQuery>,
+) -> ApiResult {
+    let q = q.0.0;
+    let filter = AuditFilter {
+        actor: q.actor,
+        action: q.action,
+        target: q.target,
+        outcome: q.outcome,
+        from: q.from,
+        to: q.to,
+        before_id: q.before_id,
+        limit: q.limit.unwrap_or(DEFAULT_LIMIT),
+    };
+    //reading the log is audited as well
+    get_or_return_err!(
+        s.audit
+            .record(
+                AuditRecord::new(actions::AUDIT_QUERY, Outcome::Success)
+                    .actor(&user.sub)
+                    .ip(ip)
+                    .details(json!({
+                        "actor": filter.actor,
+                        "action": filter.action,
+                        "target": filter.target,
+                        "outcome": filter.outcome,
+                        "from": filter.from,
+                        "to": filter.to,
+                        "before_id": filter.before_id,
+                    })),
+            )
+            .await
+    );
+    let entries = get_or_return_err!(s.audit.query(&filter).await);
+    let entries: Vec<_> = entries
+        .into_iter()
+        .map(|e| AuditEntryResponse {
+            id: e.id,
+            occurred_at: e.occurred_at,
+            actor: e.actor,
+            ip: e.ip,
+            action: e.action,
+            target: e.target,
+            outcome: e.outcome,
+            details: e.details,
+            hash: e.hash,
+        })
+        .collect();
+    Ok(data!(entries))
+}
+
+async fn verify_audit(
+    s: State<AppState>,
+    user: Extension<TokenClaims>,
+    ClientIp(ip): ClientIp,
+) -> ApiResult {
+    let status = get_or_return_err!(s.audit.verify().await);
+    let outcome = match status.broken_at {
+        None => Outcome::Success,
+        Some(_) => Outcome::Failure,
+    };
+    get_or_return_err!(
+        s.audit
+            .record(
+                AuditRecord::new(actions::AUDIT_VERIFY, outcome)
+                    .actor(&user.sub)
+                    .ip(ip)
+                    .details(json!({ "checked": status.checked, "broken_at": status.broken_at })),
+            )
+            .await
+    );
+    Ok(data!(AuditChainResponse {
+        checked: status.checked,
+        broken_at: status.broken_at,
+    }))
+}
@@ -0,0 +1,43 @@
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use ts_rs::TS;
+use validify::{Payload, Validify};
+
+#[derive(Deserialize, Validify, Payload, TS)]
+#[ts(export, export_to = "models/admin/")]
+pub struct AuditQuery {
+    pub actor: Option<String>,
+    pub action: Option<String>,
+    pub target: Option<String>,
+    pub outcome: Option<String>,
+    ///inclusive
+    pub from: Option<DateTime<Utc>>,
+    ///exclusive
+    pub to: Option<DateTime<Utc>>,
+    ///id of the last entry of the previous page
+    pub before_id: Option<i64>,
+    #[validate(range(min = 1., max = 500.))]
+    pub limit: Option<i64>,
+}
+
+#[derive(Serialize, TS)]
+#[ts(export, export_to = "models/admin/")]
+pub struct AuditEntryResponse {
+    pub id: i64,
+    pub occurred_at: DateTime<Utc>,
+    pub actor: Option<String>,
+    pub ip: Option<String>,
+    pub action: String,
+    pub target: Option<String>,
+    pub outcome: String,
+    #[ts(type = "Object | null")]
+    pub details: serde_json::Value,
+    pub hash: String,
+}
+
+#[derive(Serialize, TS)]
+#[ts(export, export_to = "models/admin/")]
+pub struct AuditChainResponse {
+    pub checked: u64,
+    pub broken_at: Option<i64>,
+}
@@ -1,40 +1,142 @@
 use crate::components::ApiResult;
-use crate::components::auth::models::{LoginRequest, LoginResponse};
+use crate::components::auth::models::{
+    LoginRequest, LoginResponse, ProfileResponse, UpdateProfileRequest,
+};
 use crate::middlewares::auth::require_authentication;
+use crate::middlewares::response_cache::{ResponseCache, response_cache};
 use crate::models::ValidJson;
 use crate::models::api_response::ApiResponse;
+use crate::utils::preconditions::IfMatch;
 use crate::{data, get_or_return_err, internal};
 use axum::extract::State;
 use axum::middleware::from_fn_with_state;
 use axum::routing::{get, post};
 use axum::{Extension, Router};
+use axum_client_ip::ClientIp;
 use lib_core::app_state::AppState;
-use lib_core::services::auth_service::create_jwt_token;
+use lib_core::managers::cache_manager::tenant_key;
+use lib_core::services::audit_service::{AuditRecord, Outcome, actions};
+use lib_core::services::auth_service::events::UserLoggedIn;
 use lib_core::services::auth_service::token_claims::TokenClaims;
+use lib_core::services::auth_service::{ADMIN_ROLE, create_jwt_token};
+use lib_db::tenant::current_tenant;
+use serde_json::json;
+use std::time::Duration;
 
 mod models;
+
+const PROFILE_CACHE_TTL: Duration = Duration::from_secs(60);
+
 pub fn routes(state: AppState) -> Router {
+    let profile_cache = ResponseCache::private(&state, PROFILE_CACHE_TTL);
     Router::new()
         .route("/info", get(info))
+        .route(
+            "/profile",
+            get(profile)
+                .put(update_profile)
+                .layer(from_fn_with_state(profile_cache, response_cache)),
+        )
         .layer(from_fn_with_state(state.clone(), require_authentication))
         .route("/login", post(login))
         .with_state(state.clone())
 }
-async fn login(s: State<AppState>, r: ValidJson<LoginRequest>) -> ApiResult {
-    let is_valid = get_or_return_err!(
-        s.psql
-            .user_auth_driver
-            .login(&r.0.0.identity, &r.0.0.pwd)
-            .await
-    );
+async fn login(
+    s: State<AppState>,
+    ClientIp(ip): ClientIp,
+    r: ValidJson<LoginRequest>,
+) -> ApiResult {
+    let is_valid = get_or_return_err!(s.user_auth.login(&r.0.0.identity, &r.0.0.pwd).await);
 
-    if is_valid {
+    if !is_valid {
+        get_or_return_err!(
+            s.audit
+                .record(
+                    AuditRecord::new(actions::LOGIN, Outcome::Failure)
+                        .actor(&r.0.0.identity)
+                        .ip(ip)
+                )
+                .await
+        );
         return Ok(data!(LoginResponse::InvalidCredentials));
     }
 
-    let token = create_jwt_token(r.0.0.identity).ok_or(internal!("[X] internal"))?;
+    //the middleware scoped the request to the tenant it's sent to
+    let tenant = current_tenant();
+    let is_admin = tenant
+        .as_deref()
+        .is_some_and(|tenant| s.env.auth.is_admin(tenant, &r.0.0.identity));
+    let roles = match is_admin {
+        true => vec![ADMIN_ROLE.to_owned()],
+        false => vec![],
+    };
+    get_or_return_err!(
+        s.audit
+            .record(
+                AuditRecord::new(actions::LOGIN, Outcome::Success)
+                    .actor(&r.0.0.identity)
+                    .ip(ip)
+            )
+            .await
+    );
+    let token = create_jwt_token(r.0.0.identity.clone(), roles.clone(), tenant)
+        .ok_or(internal!("[X] internal"))?;
+    get_or_return_err!(
+        s.audit
+            .record(
+                AuditRecord::new(actions::TOKEN_ISSUED, Outcome::Success)
+                    .actor(&r.0.0.identity)
+                    .ip(ip)
+                    .target(&r.0.0.identity)
+                    .details(json!({ "roles": roles }))
+            )
+            .await
+    );
+    s.event_bus
+        .publish(UserLoggedIn {
+            sub: r.0.0.identity,
+        })
+        .await;
     Ok(data!(LoginResponse::Success { token }))
 }
 async fn info(user: Extension<TokenClaims>) -> ApiResponse {
     data!(user.0)
 }
+async fn profile(s: State<AppState>, user: Extension<TokenClaims>) -> ApiResult {
+    let key = tenant_key(&user.sub);
+    let lookup = s
+        .cache_manager
+        .users
+        .get_or_try_insert_with(&key, || s.user_auth.find_by_identity(&user.sub));
+    let profile =
+        get_or_return_err!(lookup.await).ok_or(ApiResponse::not_found("user not found"))?;
+    Ok(data!(ProfileResponse {
+        identity: profile.identity,
+        display_name: profile.display_name,
+    })
+    .with_version(profile.version))
+}
+async fn update_profile(
+    s: State<AppState>,
+    user: Extension<TokenClaims>,
+    if_match: IfMatch,
+    r: ValidJson<UpdateProfileRequest>,
+) -> ApiResult {
+    let profile = get_or_return_err!(s.user_auth.find_by_identity(&user.sub).await)
+        .ok_or(ApiResponse::not_found("user not found"))?;
+    let display_name = r.0.0.display_name;
+    let version = get_or_return_err!(
+        s.user_auth
+            .update_display_name(profile.id, if_match.0, display_name.clone())
+            .await
+    );
+    s.cache_manager
+        .users
+        .invalidate(&tenant_key(&user.sub))
+        .await;
+    Ok(data!(ProfileResponse {
+        identity: profile.identity,
+        display_name,
+    })
+    .with_version(version))
+}
@@ -13,6 +13,21 @@ pub struct LoginRequest {
     pub pwd: String,
 }
 
+#[derive(Deserialize, Validify, Payload, TS)]
+#[ts(export, export_to = "models/auth/")]
+pub struct UpdateProfileRequest {
+    #[validate(length(max = 100))]
+    #[modify(trim)]
+    pub display_name: Option<String>,
+}
+
+#[derive(Serialize, TS)]
+#[ts(export, export_to = "models/auth/")]
+pub struct ProfileResponse {
+    pub identity: String,
+    pub display_name: Option<String>,
+}
+
 #[derive(Serialize, TS)]
 #[ts(export, export_to = "models/auth/")]
 #[serde(tag = "type", content = "value")]
@@ -2,10 +2,13 @@ use crate::models::api_response::ApiResponse;
 use axum::Router;
 use lib_core::app_state::AppState;
 
+pub mod admin;
 pub mod auth;
 
 pub type ApiResult = eyre::Result<ApiResponse, ApiResponse>;
 
 pub fn routes(state: AppState) -> Router {
-    Router::new().nest("/auth", auth::routes(state.clone()))
+    Router::new()
+        .nest("/auth", auth::routes(state.clone()))
+        .nest("/admin", admin::routes(state.clone()))
 }
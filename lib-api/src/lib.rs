@@ -4,7 +4,7 @@ use axum::middleware::from_fn_with_state;
 use axum_client_ip::ClientIpSource;
 use axum_helmet::{Helmet, HelmetLayer};
 use bytes::Bytes;
-use http::{Response, header};
+use http::{HeaderValue, Response, header};
 use http_body_util::Full;
 use lib_core::app_state::AppState;
 use lib_shared::{Res, instrument};
@@ -13,17 +13,26 @@ use std::net::SocketAddr;
 use std::time::Duration;
 use tokio::net::TcpListener;
 use tower::{BoxError, ServiceBuilder};
-use tower_http::cors::CorsLayer;
+use tower_http::cors::{AllowOrigin, CorsLayer};
 use tower_http::trace::{DefaultOnRequest, DefaultOnResponse};
 use tracing::{Level, error, info};
 
+///how long the active job runs get to finish once the server stopped
+const JOB_SHUTDOWN_GRACE: Duration = Duration::from_secs(30);
+
 pub mod components;
 pub mod middlewares;
 pub mod models;
 pub mod utils;
 
+///used by the exported macros, so they expand outside of this crate
+#[doc(hidden)]
+pub mod __private {
+    pub use serde_json;
+}
+
 pub async fn run() -> Res {
-    let app_state = AppState::new().await;
+    let app_state = AppState::new().await?;
 
     let req_tracing = tower_http::trace::TraceLayer::new_for_http()
         .on_request(DefaultOnRequest::new().level(Level::INFO))
@@ -38,29 +47,54 @@ pub async fn run() -> Res {
                 .layer(tower_http::catch_panic::CatchPanicLayer::custom(
                     handle_panic,
                 ))
-                .layer(tower_http::timeout::TimeoutLayer::new(Duration::from_secs(
-                    10,
-                )))
+                .layer(tower_http::timeout::TimeoutLayer::with_status_code(
+                    StatusCode::REQUEST_TIMEOUT,
+                    app_state.env.http.request_timeout,
+                ))
                 .layer(HelmetLayer::new(build_helmet()))
-                .layer(cors())
+                .layer(cors(&app_state.env.http.cors_origins))
                 .layer(ClientIpSource::ConnectInfo.into_extension())
-                .layer(tower::buffer::BufferLayer::new(1024)),
+                .layer(tower::buffer::BufferLayer::new(
+                    app_state.env.http.buffer_size,
+                ))
+                //after the buffer so the handlers are polled inside the scope
+                .layer(axum::middleware::from_fn(
+                    middlewares::read_your_writes::read_your_writes_middleware,
+                ))
+                .layer(from_fn_with_state(
+                    app_state.clone(),
+                    middlewares::tenant::tenant_middleware,
+                )),
         )
         .layer(from_fn_with_state(
             app_state.clone(),
             middlewares::metrics::metrics_middleware,
         ));
 
-    info!(port = app_state.env.api_port, "api running");
+    info!(port = app_state.env.http.port, "api running");
 
     axum::serve(
-        TcpListener::bind(format!("0.0.0.0:{}", app_state.env.api_port)).await?,
+        TcpListener::bind(format!("0.0.0.0:{}", app_state.env.http.port)).await?,
         app.into_make_service_with_connect_info::<SocketAddr>(),
     )
+    .with_graceful_shutdown(shutdown_signal())
     .await?;
+
+    info!("shutting down");
+    app_state.shutdown(JOB_SHUTDOWN_GRACE).await;
     Ok(())
 }
 
+async fn shutdown_signal() {
+    if let Err(e) = tokio::signal::ctrl_c().await {
+        error!(
+            error = e.to_string(),
+            "failed to listen for the shutdown signal"
+        );
+        std::future::pending::<()>().await;
+    }
+}
+
 fn build_helmet() -> Helmet {
     Helmet::new()
         .add(axum_helmet::XContentTypeOptions::nosniff())
@@ -95,11 +129,17 @@ fn handle_panic(_: Box<dyn Any + Send + 'static>) -> Response<Full<Bytes>> {
         .unwrap()
 }
 
-#[inline]
-fn cors() -> CorsLayer {
-    CorsLayer::new()
+///every origin if `origins` is empty
+fn cors(origins: &[String]) -> CorsLayer {
+    let layer = CorsLayer::new()
         .allow_headers(tower_http::cors::Any)
         .allow_methods(tower_http::cors::Any)
-        .allow_origin(tower_http::cors::Any)
-        .allow_private_network(true)
+        .allow_private_network(true);
+    match origins.is_empty() {
+        true => layer.allow_origin(tower_http::cors::Any),
+        //validated with the config
+        false => layer.allow_origin(AllowOrigin::list(
+            origins.iter().filter_map(|o| HeaderValue::from_str(o).ok()),
+        )),
+    }
 }
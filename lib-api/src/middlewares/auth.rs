@@ -1,14 +1,22 @@
 use crate::models::api_response::ApiResponse;
+use axum::Extension;
 use axum::{
     extract::{Request, State},
     middleware::Next,
     response::{IntoResponse, Response},
 };
+use axum_client_ip::ClientIp;
 use http::header;
 use lib_core::app_state::AppState;
+use lib_core::services::audit_service::{AuditRecord, Outcome, actions};
+use lib_core::services::auth_service::ADMIN_ROLE;
 use lib_core::services::auth_service::extract_claims;
 use lib_core::services::auth_service::token_claims::TokenClaims;
+use lib_db::audit::with_principal;
+use lib_db::tenant::{current_tenant, with_tenant};
 
+///requests without a resolved tenant get the one of the token, requests sent to another tenant
+///than the token's are rejected
 pub async fn require_authentication(
     _s: State<AppState>,
     mut req: Request,
@@ -16,8 +24,38 @@ pub async fn require_authentication(
 ) -> eyre::Result<Response, Response> {
     let claims = get_claims(&req).map_err(|e| e.into_response())?;
     //_s: probably some db call here to get some data about the user
+    let tenant = match (&claims.tenant_id, current_tenant()) {
+        (Some(claimed), Some(resolved)) if *claimed != resolved => {
+            return Err(
+                ApiResponse::forbidden("the token belongs to another tenant").into_response(),
+            );
+        }
+        (claimed, resolved) => claimed.clone().or(resolved),
+    };
+    let principal = claims.sub.clone();
     req.extensions_mut().insert(claims);
-    Ok(next.run(req).await)
+    Ok(with_tenant(tenant, with_principal(Some(principal), next.run(req))).await)
+}
+
+///goes after [`require_authentication`], denied attempts end up in the audit log
+pub async fn require_admin(
+    s: State<AppState>,
+    user: Extension<TokenClaims>,
+    ClientIp(ip): ClientIp,
+    req: Request,
+    next: Next,
+) -> eyre::Result<Response, Response> {
+    if user.has_role(ADMIN_ROLE) {
+        return Ok(next.run(req).await);
+    }
+    let record = AuditRecord::new(actions::ADMIN_ACCESS, Outcome::Denied)
+        .actor(&user.sub)
+        .ip(ip)
+        .target(req.uri().path())
+        .details(serde_json::json!({ "method": req.method().as_str() }));
+    //denied either way, a failed write is already logged
+    _ = s.audit.record(record).await;
+    Err(ApiResponse::forbidden("admin role is required").into_response())
 }
 
 fn get_claims(req: &Request) -> eyre::Result<TokenClaims, ApiResponse> {
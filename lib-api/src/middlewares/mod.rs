@@ -1,2 +1,5 @@
 pub mod auth;
 pub mod metrics;
+pub mod read_your_writes;
+pub mod response_cache;
+pub mod tenant;
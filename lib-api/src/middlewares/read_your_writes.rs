@@ -0,0 +1,9 @@
+use axum::extract::Request;
+use axum::middleware::Next;
+use axum::response::Response;
+use lib_db::replicas::read_your_writes;
+
+///once a handler touches the primary, the rest of its reads skip the replicas
+pub async fn read_your_writes_middleware(req: Request, next: Next) -> Response {
+    read_your_writes(next.run(req)).await
+}
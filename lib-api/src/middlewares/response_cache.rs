@@ -0,0 +1,368 @@
+use axum::body::{Body, HttpBody, to_bytes};
+use axum::extract::{OriginalUri, Request, State};
+use axum::middleware::Next;
+use axum::response::{IntoResponse, Response};
+use chrono::Utc;
+use http::response::Parts;
+use http::{HeaderMap, HeaderName, HeaderValue, Method, StatusCode, header};
+use lib_core::app_state::AppState;
+use lib_core::managers::cache_manager::{CachedResponse, tenant_key};
+use lib_core::services::auth_service::token_claims::TokenClaims;
+use lib_shared::warn;
+use sha2::{Digest, Sha256};
+use std::fmt::Write;
+use std::time::Duration;
+
+///larger responses are passed through
+const MAX_BODY: usize = 1024 * 1024;
+
+///settings of one cached route, layered with `from_fn_with_state(cache, response_cache)`
+#[derive(Clone)]
+pub struct ResponseCache {
+    state: AppState,
+    ttl: Duration,
+    per_principal: bool,
+}
+
+impl ResponseCache {
+    ///one entry for every caller, the response must not depend on who is asking
+    pub fn public(state: &AppState, ttl: Duration) -> Self {
+        Self {
+            state: state.clone(),
+            ttl,
+            per_principal: false,
+        }
+    }
+
+    ///one entry per principal, goes after `require_authentication`
+    pub fn private(state: &AppState, ttl: Duration) -> Self {
+        Self {
+            state: state.clone(),
+            ttl,
+            per_principal: true,
+        }
+    }
+}
+
+///caches the `200` responses of `GET` requests and answers `If-None-Match` with `304`.
+///`Cache-Control: no-store` on either side skips the cache, `no-cache` or `max-age=0` on the
+///request refreshes it and `max-age` on the response shortens the ttl of the route.
+///a successful request with any other method drops the cached responses of its path
+pub async fn response_cache(State(rc): State<ResponseCache>, req: Request, next: Next) -> Response {
+    let uri = req
+        .extensions()
+        .get::<OriginalUri>()
+        .map_or_else(|| req.uri().clone(), |u| u.0.clone());
+    let principal = match rc.per_principal {
+        true => match req.extensions().get::<TokenClaims>() {
+            Some(claims) => claims.sub.clone(),
+            //nothing to vary on, so nothing is shared
+            None => return next.run(req).await,
+        },
+        false => String::new(),
+    };
+    let prefix = tenant_key(&format!("{principal}|GET {}?", uri.path()));
+    let responses = &rc.state.cache_manager.responses;
+
+    if req.method() != Method::GET {
+        let response = next.run(req).await;
+        if response.status().is_success() {
+            responses.invalidate_prefix(&prefix).await;
+        }
+        return response;
+    }
+
+    let directives = CacheControl::parse(req.headers());
+    if directives.no_store {
+        return next.run(req).await;
+    }
+    let if_none_match = req.headers().get(header::IF_NONE_MATCH).cloned();
+    let key = format!("{prefix}{}", uri.query().unwrap_or_default());
+    if !directives.no_cache && directives.max_age != Some(0) {
+        let cached = responses.get(&key).await;
+        if let Some(cached) = cached.filter(|c| c.expires_at > Utc::now().timestamp_millis()) {
+            return respond(cached, if_none_match.as_ref());
+        }
+    }
+
+    let (parts, body) = next.run(req).await.into_parts();
+    let Some(ttl) = rc.ttl_of(&parts, &body) else {
+        let response = Response::from_parts(parts, body);
+        return match response_etag(response.headers()) {
+            Some(etag) if matches(if_none_match.as_ref(), etag) => not_modified(response.headers()),
+            _ => response,
+        };
+    };
+    let body = match to_bytes(body, MAX_BODY).await {
+        Ok(body) => body,
+        Err(e) => {
+            warn!(
+                uri = uri.to_string(),
+                "failed to read the response to cache: {e}"
+            );
+            return StatusCode::INTERNAL_SERVER_ERROR.into_response();
+        }
+    };
+    let cached = rc.to_cached(parts.headers, body.to_vec(), ttl);
+    if !ttl.is_zero() {
+        responses.insert(key, cached.clone()).await;
+    }
+    respond(cached, if_none_match.as_ref())
+}
+
+impl ResponseCache {
+    ///`None` if the response can't be cached
+    fn ttl_of(&self, parts: &Parts, body: &Body) -> Option<Duration> {
+        let directives = CacheControl::parse(&parts.headers);
+        let private = directives.private && !self.per_principal;
+        if parts.status != StatusCode::OK
+            || parts.headers.contains_key(header::SET_COOKIE)
+            || directives.no_store
+            || private
+            || body
+                .size_hint()
+                .upper()
+                .is_none_or(|len| len > MAX_BODY as u64)
+        {
+            return None;
+        }
+        let max_age = match self.per_principal {
+            true => directives.max_age,
+            false => directives.s_maxage.or(directives.max_age),
+        };
+        Some(max_age.map_or(self.ttl, |secs| self.ttl.min(Duration::from_secs(secs))))
+    }
+
+    ///keeps the `ETag` set by the handler, e.g. the version of a row, otherwise hashes the body
+    fn to_cached(&self, mut headers: HeaderMap, body: Vec<u8>, ttl: Duration) -> CachedResponse {
+        let etag = match response_etag(&headers) {
+            Some(etag) => etag.to_owned(),
+            None => body_etag(&body),
+        };
+        if let Ok(value) = HeaderValue::from_str(&etag) {
+            headers.insert(header::ETAG, value);
+        }
+        //clients revalidate with the etag, so invalidations reach them too
+        if !headers.contains_key(header::CACHE_CONTROL) {
+            let value = match self.per_principal {
+                true => "private, no-cache",
+                false => "public, no-cache",
+            };
+            headers.insert(header::CACHE_CONTROL, HeaderValue::from_static(value));
+        }
+        if self.per_principal {
+            headers.append(header::VARY, HeaderValue::from_static("authorization"));
+        }
+        CachedResponse {
+            headers: headers
+                .iter()
+                .map(|(name, value)| (name.to_string(), value.as_bytes().to_vec()))
+                .collect(),
+            body,
+            etag,
+            expires_at: Utc::now().timestamp_millis() + ttl.as_millis() as i64,
+        }
+    }
+}
+
+fn respond(cached: CachedResponse, if_none_match: Option<&HeaderValue>) -> Response {
+    let mut headers = HeaderMap::with_capacity(cached.headers.len());
+    for (name, value) in cached.headers {
+        if let (Ok(name), Ok(value)) = (HeaderName::try_from(name), HeaderValue::from_bytes(&value))
+        {
+            headers.append(name, value);
+        }
+    }
+    if matches(if_none_match, &cached.etag) {
+        return not_modified(&headers);
+    }
+    let mut response = Response::new(Body::from(cached.body));
+    *response.headers_mut() = headers;
+    response
+}
+
+///only with the headers a `304` must repeat
+fn not_modified(headers: &HeaderMap) -> Response {
+    let mut response = StatusCode::NOT_MODIFIED.into_response();
+    for name in [header::ETAG, header::CACHE_CONTROL, header::VARY] {
+        for value in headers.get_all(&name) {
+            response.headers_mut().append(&name, value.clone());
+        }
+    }
+    response
+}
+
+fn response_etag(headers: &HeaderMap) -> Option<&str> {
+    headers
+        .get(header::ETAG)
+        .and_then(|v| v.to_str().ok())
+        .filter(|etag| !etag.starts_with("W/"))
+}
+
+///first 128 bits of the sha256 of the body
+fn body_etag(body: &[u8]) -> String {
+    let digest = Sha256::digest(body);
+    let mut etag = String::with_capacity(34);
+    etag.push('"');
+    for b in &digest[..16] {
+        _ = write!(etag, "{b:02x}");
+    }
+    etag.push('"');
+    etag
+}
+
+///`If-None-Match` uses the weak comparison, so `W/` tags match too
+fn matches(if_none_match: Option<&HeaderValue>, etag: &str) -> bool {
+    let Some(value) = if_none_match.and_then(|v| v.to_str().ok()) else {
+        return false;
+    };
+    value
+        .split(',')
+        .map(str::trim)
+        .any(|tag| tag == "*" || tag.trim_start_matches("W/") == etag)
+}
+
+///the `Cache-Control` directives this middleware cares about
+#[derive(Default)]
+struct CacheControl {
+    no_store: bool,
+    no_cache: bool,
+    private: bool,
+    max_age: Option<u64>,
+    s_maxage: Option<u64>,
+}
+
+impl CacheControl {
+    fn parse(headers: &HeaderMap) -> Self {
+        let mut directives = Self::default();
+        let values = headers
+            .get_all(header::CACHE_CONTROL)
+            .iter()
+            .filter_map(|v| v.to_str().ok());
+        for directive in values.flat_map(|v| v.split(',')) {
+            let directive = directive.trim().to_ascii_lowercase();
+            let (name, value) = match directive.split_once('=') {
+                Some((name, value)) => (name, value.trim_matches('"').parse().ok()),
+                None => (directive.as_str(), None),
+            };
+            match name {
+                "no-store" => directives.no_store = true,
+                "no-cache" => directives.no_cache = true,
+                "private" => directives.private = true,
+                "max-age" => directives.max_age = value,
+                "s-maxage" => directives.s_maxage = value,
+                _ => {}
+            }
+        }
+        directives
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn headers(pairs: &[(HeaderName, &'static str)]) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        for (name, value) in pairs {
+            headers.append(name, HeaderValue::from_static(value));
+        }
+        headers
+    }
+
+    fn cached(etag: &str) -> CachedResponse {
+        CachedResponse {
+            headers: vec![
+                ("etag".to_owned(), etag.as_bytes().to_vec()),
+                ("cache-control".to_owned(), b"private, no-cache".to_vec()),
+                ("vary".to_owned(), b"authorization".to_vec()),
+                ("content-type".to_owned(), b"application/json".to_vec()),
+            ],
+            body: b"{}".to_vec(),
+            etag: etag.to_owned(),
+            expires_at: i64::MAX,
+        }
+    }
+
+    #[test]
+    fn body_etags_are_stable_and_quoted() {
+        let etag = body_etag(b"hello");
+        assert_eq!(etag, "\"2cf24dba5fb0a30e26e83b2ac5b9e29e\"");
+        assert_eq!(body_etag(b"hello"), etag);
+        assert_ne!(body_etag(b"hello!"), etag);
+    }
+
+    #[test]
+    fn weak_response_etags_are_not_reused() {
+        let strong = headers(&[(header::ETAG, "\"v3\"")]);
+        assert_eq!(response_etag(&strong), Some("\"v3\""));
+        let weak = headers(&[(header::ETAG, "W/\"v3\"")]);
+        assert_eq!(response_etag(&weak), None);
+        assert_eq!(response_etag(&HeaderMap::new()), None);
+    }
+
+    #[test]
+    fn if_none_match_uses_the_weak_comparison() {
+        let matches =
+            |value: &'static str| matches(Some(&HeaderValue::from_static(value)), "\"a\"");
+        assert!(matches("\"a\""));
+        assert!(matches("W/\"a\""));
+        assert!(matches("*"));
+        assert!(matches("\"b\", \"a\""));
+        assert!(matches("\"b\",W/\"a\""));
+        assert!(!matches("\"b\""));
+        assert!(!matches("\"b\", \"c\""));
+        assert!(!matches("a"));
+        assert!(!super::matches(None, "\"a\""));
+    }
+
+    #[test]
+    fn matching_requests_get_a_304_with_the_validators_only() {
+        let if_none_match = HeaderValue::from_static("W/\"x\", \"v1\"");
+        let response = respond(cached("\"v1\""), Some(&if_none_match));
+        assert_eq!(response.status(), StatusCode::NOT_MODIFIED);
+        let headers = response.headers();
+        assert_eq!(headers[header::ETAG], "\"v1\"");
+        assert_eq!(headers[header::CACHE_CONTROL], "private, no-cache");
+        assert_eq!(headers[header::VARY], "authorization");
+        assert!(!headers.contains_key(header::CONTENT_TYPE));
+    }
+
+    #[tokio::test]
+    async fn other_requests_get_the_cached_response() {
+        let if_none_match = HeaderValue::from_static("\"v0\"");
+        for if_none_match in [Some(&if_none_match), None] {
+            let response = respond(cached("\"v1\""), if_none_match);
+            assert_eq!(response.status(), StatusCode::OK);
+            assert_eq!(response.headers()[header::CONTENT_TYPE], "application/json");
+            let body = to_bytes(response.into_body(), MAX_BODY).await.unwrap();
+            assert_eq!(&body[..], b"{}");
+        }
+    }
+
+    #[test]
+    fn parses_cache_control() {
+        let c = CacheControl::parse(&headers(&[(header::CACHE_CONTROL, "No-Store")]));
+        assert!(c.no_store && !c.no_cache && !c.private);
+
+        let c = CacheControl::parse(&headers(&[(
+            header::CACHE_CONTROL,
+            "private, max-age=\"60\", s-maxage=30",
+        )]));
+        assert!(c.private && !c.no_store);
+        assert_eq!((c.max_age, c.s_maxage), (Some(60), Some(30)));
+
+        //directives can be split across headers
+        let c = CacheControl::parse(&headers(&[
+            (header::CACHE_CONTROL, "no-cache"),
+            (header::CACHE_CONTROL, "max-age=0"),
+        ]));
+        assert!(c.no_cache);
+        assert_eq!(c.max_age, Some(0));
+
+        let c = CacheControl::parse(&headers(&[(header::CACHE_CONTROL, "max-age=soon, public")]));
+        assert_eq!(c.max_age, None);
+        let c = CacheControl::parse(&HeaderMap::new());
+        assert!(!c.no_store && !c.no_cache && !c.private && c.max_age.is_none());
+    }
+}
@@ -0,0 +1,51 @@
+use crate::models::api_response::ApiResponse;
+use axum::extract::{Request, State};
+use axum::middleware::Next;
+use axum::response::{IntoResponse, Response};
+use http::header;
+use lib_core::app_state::AppState;
+use lib_db::tenant::with_tenant;
+use lib_shared::env_service::EnvService;
+
+const MAX_TENANT_LEN: usize = 64;
+
+///scopes the request to the tenant named by the tenant header or the subdomain, in that order.
+///authenticated routes fall back to the tenant of the token, see `require_authentication`
+pub async fn tenant_middleware(
+    s: State<AppState>,
+    req: Request,
+    next: Next,
+) -> eyre::Result<Response, Response> {
+    let tenant = resolve_tenant(&req, &s.env).map_err(|e| e.into_response())?;
+    Ok(with_tenant(tenant, next.run(req)).await)
+}
+
+fn resolve_tenant(req: &Request, env: &EnvService) -> eyre::Result<Option<String>, ApiResponse> {
+    let from_header = req
+        .headers()
+        .get(env.tenant.header.as_str())
+        .map(|v| {
+            v.to_str()
+                .map_err(|_| ApiResponse::bad_request("invalid tenant header"))
+        })
+        .transpose()?;
+    let tenant = from_header.or_else(|| {
+        let base = env.tenant.base_domain.as_deref()?;
+        let host = req.headers().get(header::HOST)?.to_str().ok()?;
+        let host = host.split(':').next().unwrap_or(host);
+        host.strip_suffix(base)?.strip_suffix('.')
+    });
+    match tenant {
+        Some(tenant) if !valid_tenant(tenant) => Err(ApiResponse::bad_request("invalid tenant")),
+        tenant => Ok(tenant.map(ToOwned::to_owned)),
+    }
+}
+
+///ends up in a postgres setting and in urls
+fn valid_tenant(tenant: &str) -> bool {
+    !tenant.is_empty()
+        && tenant.len() <= MAX_TENANT_LEN
+        && tenant
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+}
@@ -1,10 +1,12 @@
 use axum::Json;
 use axum::response::{IntoResponse, Response};
-use http::StatusCode;
+use http::{HeaderValue, StatusCode, header};
+use lib_db::versioning::Conflict;
 use serde_json::{Value, json};
+use tracing::error;
 use ts_rs::TS;
 
-#[derive(TS)]
+#[derive(TS, Debug)]
 #[ts(export, export_to = "models/rest/")]
 pub struct ApiResponse {
     pub message: Option<String>,
@@ -12,11 +14,15 @@ pub struct ApiResponse {
     pub data: Option<Value>,
     #[ts(type = "number")]
     pub status: StatusCode,
+    ///sent as the `ETag` header, see [`ApiResponse::with_version`]
+    #[ts(skip)]
+    pub etag: Option<String>,
 }
 
 impl ApiResponse {
-    pub(crate) fn data(data: Value) -> Self {
+    pub fn data(data: Value) -> Self {
         Self {
+            etag: None,
             message: None,
             status: StatusCode::OK,
             data: Some(data),
@@ -25,6 +31,7 @@ impl ApiResponse {
     #[allow(unused)]
     pub(crate) fn ok<M: Into<String>>(message: M, data: Option<Value>) -> Self {
         Self {
+            etag: None,
             message: Some(message.into()),
             data,
             status: StatusCode::OK,
@@ -33,14 +40,16 @@ impl ApiResponse {
     #[allow(unused)]
     pub fn bad_request<M: Into<String>>(message: M) -> Self {
         Self {
+            etag: None,
             data: None,
             message: Some(message.into()),
             status: StatusCode::BAD_REQUEST,
         }
     }
 
-    pub(crate) fn internal(msg: &str) -> Self {
+    pub fn internal(msg: &str) -> Self {
         Self {
+            etag: None,
             message: Some(msg.into()),
             data: None,
             status: StatusCode::INTERNAL_SERVER_ERROR,
@@ -49,14 +58,41 @@ impl ApiResponse {
 
     pub fn unauthorized(message: &str) -> Self {
         Self {
+            etag: None,
             data: None,
             message: Some(message.into()),
             status: StatusCode::UNAUTHORIZED,
         }
     }
-    #[allow(unused)]
+    pub fn forbidden(message: &str) -> Self {
+        Self {
+            etag: None,
+            data: None,
+            message: Some(message.into()),
+            status: StatusCode::FORBIDDEN,
+        }
+    }
+    pub fn not_found(message: &str) -> Self {
+        Self {
+            etag: None,
+            data: None,
+            message: Some(message.into()),
+            status: StatusCode::NOT_FOUND,
+        }
+    }
+
+    pub fn precondition_required(message: &str) -> Self {
+        Self {
+            etag: None,
+            data: None,
+            message: Some(message.into()),
+            status: StatusCode::PRECONDITION_REQUIRED,
+        }
+    }
+
     pub fn conflict(message: &str) -> Self {
         Self {
+            etag: None,
             data: None,
             message: Some(message.into()),
             status: StatusCode::CONFLICT,
@@ -64,16 +100,46 @@ impl ApiResponse {
     }
 }
 
+impl ApiResponse {
+    ///`409` for a [`Conflict`], tagged with the current version so the client can retry without
+    ///reading the row again. anything else is logged and hidden behind a `500`
+    pub fn from_report(e: eyre::Report) -> Self {
+        match e.downcast_ref::<Conflict>() {
+            Some(conflict) => Self::conflict(&conflict.to_string()).with_version(conflict.current),
+            None => {
+                error!("api level error: {e:?}");
+                Self::internal("internal error")
+            }
+        }
+    }
+
+    ///tags the response with the version of the returned row, clients send it back in `If-Match`
+    pub fn with_version(self, version: i64) -> Self {
+        Self {
+            etag: Some(etag(version)),
+            ..self
+        }
+    }
+}
+
+pub fn etag(version: i64) -> String {
+    format!("\"{version}\"")
+}
+
 impl IntoResponse for ApiResponse {
     fn into_response(self) -> Response {
-        (
+        let mut response = (
             self.status,
             Json(json!({
                 "message":self.message,
                 "data":self.data,
             })),
         )
-            .into_response()
+            .into_response();
+        if let Some(etag) = self.etag.and_then(|e| HeaderValue::from_str(&e).ok()) {
+            response.headers_mut().insert(header::ETAG, etag);
+        }
+        response
     }
 }
 
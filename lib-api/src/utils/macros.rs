@@ -1,7 +1,7 @@
 #[macro_export]
 macro_rules! data {
     ($i:expr) => {
-        $crate::models::api_response::ApiResponse::data(serde_json::json!($i))
+        $crate::models::api_response::ApiResponse::data($crate::__private::serde_json::json!($i))
     };
 }
 #[macro_export]
@@ -13,14 +13,60 @@ macro_rules! internal {
 #[macro_export]
 macro_rules! data_or_internal {
     ($i:expr) => {
-        $i.map(|f| data!(f))
+        $i.map(|f| $crate::data!(f))
             .map_err(|_| $crate::models::api_response::ApiResponse::internal("internal error"))
     };
 }
 #[macro_export]
 macro_rules! get_or_return_err {
     ($i:expr) => {
-        $i.inspect_err(|e| tracing::error!("api level error: {e:?}"))
-            .map_err(|_| $crate::models::api_response::ApiResponse::internal("internal error"))?
+        $i.map_err($crate::models::api_response::ApiResponse::from_report)?
     };
 }
+
+#[cfg(test)]
+mod tests {
+    use crate::components::ApiResult;
+    use eyre::WrapErr;
+    use http::StatusCode;
+    use lib_db::versioning::Conflict;
+
+    fn respond(result: eyre::Result<i64>) -> ApiResult {
+        let version = get_or_return_err!(result);
+        Ok(data!(version))
+    }
+
+    fn conflict() -> Conflict {
+        Conflict {
+            table: "users",
+            id: 1,
+            expected: 2,
+            current: 3,
+        }
+    }
+
+    #[test]
+    fn conflicts_are_409() {
+        let response = respond(Err(conflict().into())).unwrap_err();
+        assert_eq!(response.status, StatusCode::CONFLICT);
+        assert_eq!(
+            response.message.as_deref(),
+            Some("users 1 is at version 3, expected 2")
+        );
+        //the client retries with the current version, without reading the row again
+        assert_eq!(response.etag.as_deref(), Some("\"3\""));
+        //still found under some context
+        let wrapped = Err::<i64, _>(conflict()).wrap_err("updating the profile");
+        let response = respond(wrapped).unwrap_err();
+        assert_eq!(response.status, StatusCode::CONFLICT);
+    }
+
+    #[test]
+    fn other_errors_are_500() {
+        let response = respond(Err(eyre::eyre!("connection reset"))).unwrap_err();
+        assert_eq!(response.status, StatusCode::INTERNAL_SERVER_ERROR);
+        assert_eq!(response.message.as_deref(), Some("internal error"));
+        assert_eq!(response.etag, None);
+        assert_eq!(respond(Ok(4)).unwrap().status, StatusCode::OK);
+    }
+}
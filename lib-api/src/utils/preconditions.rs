@@ -0,0 +1,24 @@
+use crate::models::api_response::ApiResponse;
+use axum::extract::FromRequestParts;
+use http::header;
+use http::request::Parts;
+
+///version from the `If-Match` header, required by the endpoints that update versioned rows
+pub struct IfMatch(pub i64);
+
+impl<S: Send + Sync> FromRequestParts<S> for IfMatch {
+    type Rejection = ApiResponse;
+
+    async fn from_request_parts(parts: &mut Parts, _: &S) -> Result<Self, Self::Rejection> {
+        let value = parts
+            .headers
+            .get(header::IF_MATCH)
+            .ok_or_else(|| ApiResponse::precondition_required("If-Match header is required"))?;
+        value
+            .to_str()
+            .ok()
+            .and_then(|v| v.trim().strip_prefix('"')?.strip_suffix('"')?.parse().ok())
+            .map(IfMatch)
+            .ok_or_else(|| ApiResponse::bad_request("If-Match must be a strong etag"))
+    }
+}
@@ -1,10 +1,25 @@
+use crate::managers::cache_backend::{CacheBackend, RedisBackend};
 use crate::managers::cache_manager::CacheManager;
+use crate::managers::event_bus::EventBus;
+use crate::managers::job_queue::JobQueue;
+use crate::managers::leader_election::LeaderElection;
+use crate::managers::outbox_relay::OutboxRelay;
+use crate::managers::scheduler::{Job, Schedule, Scheduler};
 use crate::managers::thread_manager::ThreadManager;
+use crate::services::audit_service::AuditService;
+use crate::services::auth_service;
+use eyre::WrapErr;
 use lib_db::PsqlDriver;
+use lib_db::repositories::user_auth::UserAuthRepository;
 use lib_shared::env_service::EnvService;
 use lib_shared::metrics::Metrics;
 use std::ops::Deref;
 use std::sync::Arc;
+use std::time::Duration;
+
+const LEADER_KEY: &str = "axum-template-leader";
+const POOL_METRIC_INTERVAL: Duration = Duration::from_secs(5);
+const REPLICA_HEALTH_INTERVAL: Duration = Duration::from_secs(5);
 
 #[derive(Clone)]
 pub struct AppState {
@@ -15,34 +30,182 @@ pub struct AppStateInner {
     pub thread_manager: ThreadManager,
     pub env: EnvService,
     pub metrics: Metrics,
-    pub psql: PsqlDriver,
+    psql: PsqlDriver,
     pub cache_manager: CacheManager,
+    pub scheduler: Scheduler,
+    pub job_queue: JobQueue,
+    pub leader: LeaderElection,
+    pub event_bus: EventBus,
+    pub outbox_relay: OutboxRelay,
+    pub user_auth: Arc<dyn UserAuthRepository>,
+    pub audit: AuditService,
 }
 
 impl AppState {
-    pub async fn new() -> Self {
-        let cache_manager = CacheManager::new();
-        let env = EnvService::new();
-        let metrics = Metrics::new();
+    pub async fn new() -> eyre::Result<Self> {
+        let env = EnvService::load()?;
+        let psql = PsqlDriver::new(
+            &env.database.url,
+            &env.database.replica_urls,
+            &env.database.pool,
+            Metrics::new(),
+        )
+        .await
+        .wrap_err("failed to connect to postgres")?;
+        psql.connection.check_row_security().await?;
+        let user_auth = Arc::new(psql.user_auth_driver.clone());
+        Ok(Self::with_user_auth(env, psql, user_auth).await)
+    }
+
+    ///[`AppState::new`] with another user repository, e.g. a `MemoryUserAuthRepository` next to
+    ///a `PsqlDriver::connect_lazy`, so the services and routes can be tested without a database
+    pub async fn with_user_auth(
+        env: EnvService,
+        psql: PsqlDriver,
+        user_auth: Arc<dyn UserAuthRepository>,
+    ) -> Self {
+        auth_service::configure(&env.auth);
+        let metrics = psql.connection.metrics.clone();
 
         let thread_manager = ThreadManager::new();
-        let psql = PsqlDriver::new(&env.psql_url, metrics.clone()).await;
         let handle = psql.connection.run_metric_provider();
         thread_manager.add("db-metric", handle).await;
+        let handle = psql
+            .connection
+            .run_pool_metric_provider(POOL_METRIC_INTERVAL);
+        thread_manager.add("db-pool-metric", handle).await;
+        if !env.database.replica_urls.is_empty() {
+            let handle = psql
+                .connection
+                .run_replica_health_check(REPLICA_HEALTH_INTERVAL);
+            thread_manager.add("db-replica-health", handle).await;
+        }
 
         let metrics_handle = metrics.run_generic_metric_provider();
         thread_manager.add("generic-metric", metrics_handle).await;
 
+        if env.database.migrate_on_startup {
+            psql.connection
+                .migrate_up()
+                .await
+                .expect("failed to run migrations");
+        }
+
+        let remote: Option<Arc<dyn CacheBackend>> = match &env.cache.redis_url {
+            Some(url) => Some(Arc::new(
+                RedisBackend::connect(url)
+                    .await
+                    .expect("failed to connect to redis"),
+            )),
+            None => None,
+        };
+        let cache_manager =
+            CacheManager::new(&metrics, &env.cache, psql.connection.clone(), remote);
+        let handle = cache_manager.run_invalidation_listener();
+        thread_manager.add("cache-invalidation", handle).await;
+
+        let job_queue = JobQueue::new(psql.job_queue_driver.clone(), metrics.clone());
+        job_queue.start(&thread_manager, 4).await;
+
+        let event_bus = EventBus::new(thread_manager.clone(), metrics.clone());
+        auth_service::register_handlers(&event_bus, &metrics);
+
+        let outbox_relay = OutboxRelay::new(
+            psql.outbox_driver.clone(),
+            Arc::new(event_bus.clone()),
+            metrics.clone(),
+        );
+        thread_manager.add("outbox-relay", outbox_relay.run()).await;
+
+        let (leader, leader_handle) =
+            LeaderElection::new(psql.connection.clone(), LEADER_KEY, metrics.clone());
+        thread_manager.add("leader-election", leader_handle).await;
+
+        let scheduler = Scheduler::new(thread_manager.clone(), metrics.clone(), leader.clone());
+
+        let audit = AuditService::new(psql.audit_log_driver.clone());
+
+        let inner = AppStateInner {
+            cache_manager,
+            psql,
+            metrics,
+            thread_manager,
+            env,
+            scheduler,
+            job_queue,
+            leader,
+            event_bus,
+            outbox_relay,
+            user_auth,
+            audit,
+        };
+        register_jobs(&inner).await;
+
         Self {
-            inner: Arc::new(AppStateInner {
-                cache_manager,
-                psql,
-                metrics,
-                thread_manager,
-                env,
-            }),
+            inner: Arc::new(inner),
         }
     }
+
+    ///stops the scheduled jobs, giving the running ones `grace` to finish, then flushes the db
+    ///metrics
+    pub async fn shutdown(&self, grace: Duration) {
+        self.scheduler.shutdown(grace).await;
+        self.psql.connection.shutdown_metrics();
+        self.thread_manager.join("db-metric").await;
+    }
+}
+
+async fn register_jobs(state: &AppStateInner) {
+    let cache = state.cache_manager.clone();
+    state
+        .scheduler
+        .register(Job::new(
+            "cache-maintenance",
+            Schedule::every(Duration::from_secs(60)),
+            move || {
+                let cache = cache.clone();
+                async move {
+                    cache.maintain().await;
+                    Ok(())
+                }
+            },
+        ))
+        .await;
+
+    let queue = state.job_queue.clone();
+    state
+        .scheduler
+        .register(
+            Job::new(
+                "job-queue-maintenance",
+                Schedule::every(Duration::from_secs(60)),
+                move || {
+                    let queue = queue.clone();
+                    async move { queue.maintain(Duration::from_secs(7 * 24 * 60 * 60)).await }
+                },
+            )
+            .jitter(Duration::from_secs(10))
+            .timeout(Duration::from_secs(30))
+            .singleton(),
+        )
+        .await;
+
+    let relay = state.outbox_relay.clone();
+    state
+        .scheduler
+        .register(
+            Job::new(
+                "outbox-purge",
+                Schedule::cron("0 0 * * * *").expect("valid cron expression"),
+                move || {
+                    let relay = relay.clone();
+                    async move { relay.purge(Duration::from_secs(7 * 24 * 60 * 60)).await }
+                },
+            )
+            .timeout(Duration::from_secs(60))
+            .singleton(),
+        )
+        .await;
 }
 
 impl Deref for AppState {
@@ -51,3 +214,34 @@ impl Deref for AppState {
         &self.inner
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use lib_db::repositories::user_auth::MemoryUserAuthRepository;
+    use lib_db::tenant::with_tenant;
+
+    #[tokio::test]
+    async fn runs_on_an_injected_repository() {
+        //nothing listens there, every query would fail
+        unsafe { std::env::set_var("DATABASE_URL", "postgres://localhost:1/none") };
+        let env = EnvService::load().unwrap();
+        let psql =
+            PsqlDriver::connect_lazy(&env.database.url, &[], &env.database.pool, Metrics::new())
+                .unwrap();
+        let users = MemoryUserAuthRepository::new().with_user("acme", "alice", "hunter22");
+        let state = AppState::with_user_auth(env, psql, Arc::new(users)).await;
+
+        let acme = Some("acme".to_owned());
+        assert!(
+            with_tenant(acme.clone(), state.user_auth.login("alice", "hunter22"))
+                .await
+                .unwrap()
+        );
+        let alice = with_tenant(acme, state.user_auth.find_by_identity("alice"))
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(alice.tenant_id, "acme");
+    }
+}
@@ -0,0 +1,529 @@
+use futures_util::future::BoxFuture;
+use moka::Expiry;
+use moka::future::{Cache, CacheBuilder};
+use redis::Script;
+use redis::aio::ConnectionManager;
+use std::sync::Arc;
+use std::time::Duration;
+
+const SCAN_COUNT: usize = 500;
+///increments and sets the expiry of a new key in one round trip. the key is new if it didn't
+///exist before the increment, a counter coming back to `by` keeps its expiry
+const INCR_SCRIPT: &str = r"
+local created = redis.call('EXISTS', KEYS[1]) == 0
+local value = redis.call('INCRBY', KEYS[1], ARGV[1])
+if created and tonumber(ARGV[2]) > 0 then
+    redis.call('PEXPIRE', KEYS[1], ARGV[2])
+end
+return value";
+
+///byte storage shared by the caches, values are serialized by the caller.
+///`MokaBackend` keeps them on this instance, `RedisBackend` on a redis compatible server
+///and `FakeBackend` in a map, in the tests
+pub trait CacheBackend: Send + Sync {
+    fn get<'a>(&'a self, key: &'a str) -> BoxFuture<'a, eyre::Result<Option<Vec<u8>>>>;
+    ///`None` keeps the value until it's deleted or evicted
+    fn set<'a>(
+        &'a self,
+        key: &'a str,
+        value: Vec<u8>,
+        ttl: Option<Duration>,
+    ) -> BoxFuture<'a, eyre::Result<()>>;
+    fn delete<'a>(&'a self, key: &'a str) -> BoxFuture<'a, eyre::Result<()>>;
+    fn delete_prefix<'a>(&'a self, prefix: &'a str) -> BoxFuture<'a, eyre::Result<()>>;
+    ///atomically adds `by` to the integer at `key` and returns it, for counters like rate limits.
+    ///`ttl` only applies when the key is created
+    fn incr<'a>(
+        &'a self,
+        key: &'a str,
+        by: i64,
+        ttl: Option<Duration>,
+    ) -> BoxFuture<'a, eyre::Result<i64>>;
+}
+
+#[derive(Clone)]
+struct Stored {
+    bytes: Arc<Vec<u8>>,
+    ttl: Option<Duration>,
+    ///counters keep the expiry of their first increment
+    keep_ttl: bool,
+}
+
+struct StoredExpiry;
+
+impl Expiry<String, Stored> for StoredExpiry {
+    fn expire_after_create(
+        &self,
+        _: &String,
+        value: &Stored,
+        _: std::time::Instant,
+    ) -> Option<Duration> {
+        value.ttl
+    }
+
+    fn expire_after_update(
+        &self,
+        _: &String,
+        value: &Stored,
+        _: std::time::Instant,
+        current: Option<Duration>,
+    ) -> Option<Duration> {
+        match value.keep_ttl {
+            true => current,
+            false => value.ttl,
+        }
+    }
+}
+
+///keeps the values on this instance, what `CacheManager::shared` uses without redis
+#[derive(Clone)]
+pub struct MokaBackend {
+    cache: Cache<String, Stored>,
+}
+
+impl MokaBackend {
+    pub fn new(capacity: u64) -> Self {
+        Self {
+            cache: CacheBuilder::new(capacity)
+                .expire_after(StoredExpiry)
+                .support_invalidation_closures()
+                .build(),
+        }
+    }
+}
+
+impl CacheBackend for MokaBackend {
+    fn get<'a>(&'a self, key: &'a str) -> BoxFuture<'a, eyre::Result<Option<Vec<u8>>>> {
+        Box::pin(async move { Ok(self.cache.get(key).await.map(|v| v.bytes.to_vec())) })
+    }
+
+    fn set<'a>(
+        &'a self,
+        key: &'a str,
+        value: Vec<u8>,
+        ttl: Option<Duration>,
+    ) -> BoxFuture<'a, eyre::Result<()>> {
+        Box::pin(async move {
+            let stored = Stored {
+                bytes: Arc::new(value),
+                ttl,
+                keep_ttl: false,
+            };
+            self.cache.insert(key.to_owned(), stored).await;
+            Ok(())
+        })
+    }
+
+    fn delete<'a>(&'a self, key: &'a str) -> BoxFuture<'a, eyre::Result<()>> {
+        Box::pin(async move {
+            self.cache.invalidate(key).await;
+            Ok(())
+        })
+    }
+
+    fn delete_prefix<'a>(&'a self, prefix: &'a str) -> BoxFuture<'a, eyre::Result<()>> {
+        Box::pin(async move {
+            let prefix = prefix.to_owned();
+            self.cache
+                .invalidate_entries_if(move |key, _| key.starts_with(&prefix))?;
+            Ok(())
+        })
+    }
+
+    fn incr<'a>(
+        &'a self,
+        key: &'a str,
+        by: i64,
+        ttl: Option<Duration>,
+    ) -> BoxFuture<'a, eyre::Result<i64>> {
+        Box::pin(async move {
+            let entry = self
+                .cache
+                .entry_by_ref(key)
+                .and_upsert_with(|current| {
+                    let (value, keep_ttl) = match current {
+                        Some(entry) => (parse_counter(&entry.into_value().bytes) + by, true),
+                        None => (by, false),
+                    };
+                    std::future::ready(Stored {
+                        bytes: Arc::new(value.to_string().into_bytes()),
+                        ttl,
+                        keep_ttl,
+                    })
+                })
+                .await;
+            Ok(parse_counter(&entry.into_value().bytes))
+        })
+    }
+}
+
+///counters are stored as decimal strings, like redis does
+fn parse_counter(bytes: &[u8]) -> i64 {
+    std::str::from_utf8(bytes)
+        .ok()
+        .and_then(|s| s.parse().ok())
+        .unwrap_or(0)
+}
+
+///any server speaking the redis protocol (redis, valkey, dragonfly...), reconnects by itself
+#[derive(Clone)]
+pub struct RedisBackend {
+    conn: ConnectionManager,
+}
+
+impl RedisBackend {
+    pub async fn connect(url: &str) -> eyre::Result<Self> {
+        let conn = redis::Client::open(url)?.get_connection_manager().await?;
+        Ok(Self { conn })
+    }
+}
+
+impl CacheBackend for RedisBackend {
+    fn get<'a>(&'a self, key: &'a str) -> BoxFuture<'a, eyre::Result<Option<Vec<u8>>>> {
+        Box::pin(async move {
+            let mut conn = self.conn.clone();
+            Ok(redis::cmd("GET").arg(key).query_async(&mut conn).await?)
+        })
+    }
+
+    fn set<'a>(
+        &'a self,
+        key: &'a str,
+        value: Vec<u8>,
+        ttl: Option<Duration>,
+    ) -> BoxFuture<'a, eyre::Result<()>> {
+        Box::pin(async move {
+            let mut conn = self.conn.clone();
+            let mut cmd = redis::cmd("SET");
+            cmd.arg(key).arg(value);
+            if let Some(ttl) = ttl {
+                cmd.arg("PX").arg(ttl.as_millis() as u64);
+            }
+            let _: () = cmd.query_async(&mut conn).await?;
+            Ok(())
+        })
+    }
+
+    fn delete<'a>(&'a self, key: &'a str) -> BoxFuture<'a, eyre::Result<()>> {
+        Box::pin(async move {
+            let mut conn = self.conn.clone();
+            let _: () = redis::cmd("UNLINK").arg(key).query_async(&mut conn).await?;
+            Ok(())
+        })
+    }
+
+    ///walks the keyspace with `SCAN`, keys written meanwhile may survive
+    fn delete_prefix<'a>(&'a self, prefix: &'a str) -> BoxFuture<'a, eyre::Result<()>> {
+        Box::pin(async move {
+            let mut conn = self.conn.clone();
+            let pattern = format!("{}*", escape_glob(prefix));
+            let mut cursor = 0u64;
+            loop {
+                let (next, keys): (u64, Vec<String>) = redis::cmd("SCAN")
+                    .arg(cursor)
+                    .arg("MATCH")
+                    .arg(&pattern)
+                    .arg("COUNT")
+                    .arg(SCAN_COUNT)
+                    .query_async(&mut conn)
+                    .await?;
+                if !keys.is_empty() {
+                    let _: () = redis::cmd("UNLINK")
+                        .arg(keys)
+                        .query_async(&mut conn)
+                        .await?;
+                }
+                if next == 0 {
+                    return Ok(());
+                }
+                cursor = next;
+            }
+        })
+    }
+
+    fn incr<'a>(
+        &'a self,
+        key: &'a str,
+        by: i64,
+        ttl: Option<Duration>,
+    ) -> BoxFuture<'a, eyre::Result<i64>> {
+        Box::pin(async move {
+            let mut conn = self.conn.clone();
+            let ttl = ttl.map_or(0, |ttl| ttl.as_millis() as u64);
+            Ok(Script::new(INCR_SCRIPT)
+                .key(key)
+                .arg(by)
+                .arg(ttl)
+                .invoke_async(&mut conn)
+                .await?)
+        })
+    }
+}
+
+///`SCAN MATCH` patterns are globs
+fn escape_glob(prefix: &str) -> String {
+    let mut escaped = String::with_capacity(prefix.len());
+    for c in prefix.chars() {
+        if matches!(c, '*' | '?' | '[' | ']' | '\\') {
+            escaped.push('\\');
+        }
+        escaped.push(c);
+    }
+    escaped
+}
+
+#[cfg(test)]
+pub use fake::FakeBackend;
+
+#[cfg(test)]
+mod fake {
+    use super::*;
+    use hashbrown::HashMap;
+    use parking_lot::Mutex;
+    use std::time::Instant;
+
+    ///value and deadline
+    type FakeEntries = HashMap<String, (Vec<u8>, Option<Instant>)>;
+
+    ///in-process stand-in for [`RedisBackend`] with the same semantics, for tests.
+    ///expired keys are dropped when they're read
+    #[derive(Clone, Default)]
+    pub struct FakeBackend {
+        entries: Arc<Mutex<FakeEntries>>,
+    }
+
+    impl FakeBackend {
+        pub fn new() -> Self {
+            Self::default()
+        }
+
+        pub fn len(&self) -> usize {
+            let now = Instant::now();
+            self.entries
+                .lock()
+                .values()
+                .filter(|(_, deadline)| deadline.is_none_or(|d| d > now))
+                .count()
+        }
+
+        pub fn is_empty(&self) -> bool {
+            self.len() == 0
+        }
+
+        fn live<'a>(
+            entries: &'a mut FakeEntries,
+            key: &str,
+        ) -> Option<&'a mut (Vec<u8>, Option<Instant>)> {
+            if entries
+                .get(key)
+                .is_some_and(|(_, deadline)| deadline.is_some_and(|d| d <= Instant::now()))
+            {
+                entries.remove(key);
+            }
+            entries.get_mut(key)
+        }
+    }
+
+    impl CacheBackend for FakeBackend {
+        fn get<'a>(&'a self, key: &'a str) -> BoxFuture<'a, eyre::Result<Option<Vec<u8>>>> {
+            let mut entries = self.entries.lock();
+            let value = Self::live(&mut entries, key).map(|(value, _)| value.clone());
+            Box::pin(std::future::ready(Ok(value)))
+        }
+
+        fn set<'a>(
+            &'a self,
+            key: &'a str,
+            value: Vec<u8>,
+            ttl: Option<Duration>,
+        ) -> BoxFuture<'a, eyre::Result<()>> {
+            let deadline = ttl.map(|ttl| Instant::now() + ttl);
+            self.entries
+                .lock()
+                .insert(key.to_owned(), (value, deadline));
+            Box::pin(std::future::ready(Ok(())))
+        }
+
+        fn delete<'a>(&'a self, key: &'a str) -> BoxFuture<'a, eyre::Result<()>> {
+            self.entries.lock().remove(key);
+            Box::pin(std::future::ready(Ok(())))
+        }
+
+        fn delete_prefix<'a>(&'a self, prefix: &'a str) -> BoxFuture<'a, eyre::Result<()>> {
+            self.entries
+                .lock()
+                .retain(|key, _| !key.starts_with(prefix));
+            Box::pin(std::future::ready(Ok(())))
+        }
+
+        fn incr<'a>(
+            &'a self,
+            key: &'a str,
+            by: i64,
+            ttl: Option<Duration>,
+        ) -> BoxFuture<'a, eyre::Result<i64>> {
+            let mut entries = self.entries.lock();
+            let value = match Self::live(&mut entries, key) {
+                Some((bytes, _)) => {
+                    let value = parse_counter(bytes) + by;
+                    *bytes = value.to_string().into_bytes();
+                    value
+                }
+                None => {
+                    let deadline = ttl.map(|ttl| Instant::now() + ttl);
+                    entries.insert(key.to_owned(), (by.to_string().into_bytes(), deadline));
+                    by
+                }
+            };
+            Box::pin(std::future::ready(Ok(value)))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TTL: Duration = Duration::from_millis(100);
+
+    ///the local backend must behave like the remote one it stands in for
+    fn backends() -> [(&'static str, Box<dyn CacheBackend>); 2] {
+        [
+            ("fake", Box::new(FakeBackend::new())),
+            ("moka", Box::new(MokaBackend::new(100))),
+        ]
+    }
+
+    #[tokio::test]
+    async fn incr_keeps_the_ttl_of_the_first_increment() {
+        for (name, backend) in backends() {
+            assert_eq!(
+                backend.incr("hits", 1, Some(TTL)).await.unwrap(),
+                1,
+                "{name}"
+            );
+            tokio::time::sleep(TTL / 2).await;
+            //a later increment with its own ttl doesn't push the deadline
+            assert_eq!(
+                backend.incr("hits", 2, Some(TTL * 10)).await.unwrap(),
+                3,
+                "{name}"
+            );
+            tokio::time::sleep(TTL).await;
+            assert_eq!(backend.get("hits").await.unwrap(), None, "{name}");
+            //starts over once expired
+            assert_eq!(
+                backend.incr("hits", 1, Some(TTL)).await.unwrap(),
+                1,
+                "{name}"
+            );
+        }
+    }
+
+    ///a counter coming back to its first increment isn't new, its deadline stays
+    async fn assert_incr_back_to_by_keeps_the_ttl(name: &str, backend: &dyn CacheBackend) {
+        let key = format!("test:back-to-by:{}", rand::random::<u32>());
+        assert_eq!(backend.incr(&key, 1, Some(TTL)).await.unwrap(), 1, "{name}");
+        assert_eq!(
+            backend.incr(&key, -1, Some(TTL * 10)).await.unwrap(),
+            0,
+            "{name}"
+        );
+        assert_eq!(
+            backend.incr(&key, 1, Some(TTL * 10)).await.unwrap(),
+            1,
+            "{name}"
+        );
+        tokio::time::sleep(TTL * 2).await;
+        assert_eq!(backend.get(&key).await.unwrap(), None, "{name}");
+    }
+
+    #[tokio::test]
+    async fn incr_back_to_by_keeps_the_ttl() {
+        for (name, backend) in backends() {
+            assert_incr_back_to_by_keeps_the_ttl(name, backend.as_ref()).await;
+        }
+    }
+
+    #[tokio::test]
+    #[ignore = "needs a redis server in REDIS_URL"]
+    async fn redis_incr_back_to_by_keeps_the_ttl() {
+        let url = std::env::var("REDIS_URL").unwrap();
+        let backend = RedisBackend::connect(&url).await.unwrap();
+        assert_incr_back_to_by_keeps_the_ttl("redis", &backend).await;
+    }
+
+    #[tokio::test]
+    async fn incr_without_ttl_never_expires() {
+        for (name, backend) in backends() {
+            backend.incr("total", 5, None).await.unwrap();
+            tokio::time::sleep(TTL).await;
+            assert_eq!(backend.incr("total", -2, None).await.unwrap(), 3, "{name}");
+            assert_eq!(
+                backend.get("total").await.unwrap(),
+                Some(b"3".to_vec()),
+                "{name}"
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn set_expires_after_its_ttl() {
+        for (name, backend) in backends() {
+            backend.set("k", b"v".to_vec(), Some(TTL)).await.unwrap();
+            backend.set("kept", b"v".to_vec(), None).await.unwrap();
+            assert_eq!(
+                backend.get("k").await.unwrap(),
+                Some(b"v".to_vec()),
+                "{name}"
+            );
+            tokio::time::sleep(TTL * 2).await;
+            assert_eq!(backend.get("k").await.unwrap(), None, "{name}");
+            assert_eq!(
+                backend.get("kept").await.unwrap(),
+                Some(b"v".to_vec()),
+                "{name}"
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn delete_prefix_only_drops_matching_keys() {
+        for (name, backend) in backends() {
+            for key in [
+                "cache:users:a",
+                "cache:users:b",
+                "cache:usersx:a",
+                "cache:*:a",
+            ] {
+                backend.set(key, b"v".to_vec(), None).await.unwrap();
+            }
+            backend.delete_prefix("cache:users:").await.unwrap();
+            backend.delete_prefix("cache:*").await.unwrap();
+            assert_eq!(backend.get("cache:users:a").await.unwrap(), None, "{name}");
+            assert_eq!(backend.get("cache:users:b").await.unwrap(), None, "{name}");
+            //glob characters are literal
+            assert_eq!(backend.get("cache:*:a").await.unwrap(), None, "{name}");
+            assert!(
+                backend.get("cache:usersx:a").await.unwrap().is_some(),
+                "{name}"
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn delete_drops_one_key() {
+        let backend = FakeBackend::new();
+        backend.set("a", b"1".to_vec(), None).await.unwrap();
+        backend.set("b", b"2".to_vec(), None).await.unwrap();
+        backend.delete("a").await.unwrap();
+        assert_eq!(backend.get("a").await.unwrap(), None);
+        assert_eq!(backend.len(), 1);
+    }
+
+    #[test]
+    fn escapes_glob_characters() {
+        assert_eq!(escape_glob("cache:users:"), "cache:users:");
+        assert_eq!(escape_glob(r"a*b?[c]\"), r"a\*b\?\[c\]\\");
+    }
+}
@@ -1,38 +1,685 @@
-use derived::Gtor;
+use crate::managers::cache_backend::{CacheBackend, MokaBackend};
+use crate::managers::single_flight::SingleFlight;
+use futures_util::future::BoxFuture;
+use lib_db::psql_connection::PsqlConnection;
+use lib_db::tenant::current_tenant;
+use lib_db::user_auth_driver::UserProfile;
+use lib_shared::env_service::{CacheConfig, CacheEnv};
+use lib_shared::metrics::Metrics;
+use lib_shared::{Res, debug, instrument, warn};
 use moka::future::{Cache, CacheBuilder};
+use moka::notification::RemovalCause;
+use opentelemetry::KeyValue;
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use sqlx::postgres::PgListener;
+use std::future::Future;
 use std::ops::Deref;
 use std::sync::Arc;
 use std::time::Duration;
+use tokio::task::JoinHandle;
+use tokio::time::sleep;
+
+const INVALIDATION_CHANNEL: &str = "cache_invalidation";
+const LISTEN_RETRY_INTERVAL: Duration = Duration::from_secs(5);
+///namespace of the named caches in the remote backend
+const REMOTE_PREFIX: &str = "cache";
+///entries of [`CacheManagerInner::shared`] when it's kept on this instance
+const SHARED_CAPACITY: u64 = 100_000;
 
 #[derive(Clone)]
 pub struct CacheManager {
     inner: Arc<CacheManagerInner>,
 }
-#[derive(Gtor)]
+
+///every cache is declared here, with its default settings in [`CacheManager::new`]
 pub struct CacheManagerInner {
-    #[allow(unused)]
-    some_cache: Cache<u32, u32>,
+    ///by [`tenant_key`] of the identity, `None` for identities without a user. never holds the
+    ///password hash, the entries may end up in the remote backend
+    pub users: NamedCache<Option<UserProfile>>,
+    ///filled by the response cache middleware of the api, keyed by tenant, principal and uri
+    pub responses: NamedCache<CachedResponse>,
+    ///raw storage for state every instance must see, like rate limit counters. it's the remote
+    ///backend when there is one, otherwise it's local to this instance
+    pub shared: Arc<dyn CacheBackend>,
+    registry: Vec<Arc<dyn CacheHandle>>,
+    bus: InvalidationBus,
 }
 
 impl CacheManager {
-    pub fn new() -> Self {
+    ///`connection` carries the invalidations to the other instances, see
+    ///[`CacheManager::run_invalidation_listener`]. with a `remote` backend every named cache
+    ///becomes two-tier: this instance first, then the backend shared by every instance
+    pub fn new(
+        metrics: &Metrics,
+        config: &CacheConfig,
+        connection: PsqlConnection,
+        remote: Option<Arc<dyn CacheBackend>>,
+    ) -> Self {
+        let bus = InvalidationBus {
+            connection,
+            origin: rand::random(),
+        };
+        let mut registry = Registry::new(metrics, config, &bus, remote.clone());
+        let users = registry.declare(
+            "users",
+            CacheEnv {
+                capacity: 10_000,
+                ttl: Some(Duration::from_secs(5 * 60)),
+                tti: None,
+            },
+        );
+        //upper bound, each route sets its own ttl
+        let responses = registry.declare(
+            "responses",
+            CacheEnv {
+                capacity: 10_000,
+                ttl: Some(Duration::from_secs(10 * 60)),
+                tti: None,
+            },
+        );
         Self {
             inner: Arc::new(CacheManagerInner {
-                some_cache: CacheBuilder::new(2000)
-                    .time_to_live(Duration::from_secs(100))
-                    .build(),
+                users,
+                responses,
+                shared: remote.unwrap_or_else(|| Arc::new(MokaBackend::new(SHARED_CAPACITY))),
+                registry: registry.caches,
+                bus,
             }),
         }
     }
+
+    pub fn caches(&self) -> impl Iterator<Item = &dyn CacheHandle> {
+        self.registry.iter().map(|c| c.as_ref())
+    }
+
+    pub fn find(&self, name: &str) -> Option<&dyn CacheHandle> {
+        self.caches().find(|c| c.name() == name)
+    }
+
+    ///runs the pending evictions of every cache and records their size
+    pub async fn maintain(&self) {
+        for cache in self.caches() {
+            cache.run_pending_tasks().await;
+        }
+    }
+
+    ///applies the invalidations published by the other instances to the local caches. whenever
+    ///the channel is lost every cache is cleared, since some invalidations may have been missed
+    pub fn run_invalidation_listener(&self) -> JoinHandle<Res> {
+        let slf = self.clone();
+        tokio::spawn(async move {
+            loop {
+                match slf.bus.connection.listen(INVALIDATION_CHANNEL).await {
+                    Ok(listener) => slf.receive(listener).await,
+                    Err(e) => warn!("failed to listen for cache invalidations: {e}"),
+                }
+                slf.clear_local().await;
+                sleep(LISTEN_RETRY_INTERVAL).await;
+            }
+        })
+    }
+
+    ///returns once the listener fails
+    async fn receive(&self, mut listener: PgListener) {
+        loop {
+            match listener.try_recv().await {
+                Ok(Some(notification)) => self.apply_remote(notification.payload()).await,
+                //reconnected by the next `try_recv`
+                Ok(None) => {
+                    warn!("lost the cache invalidation channel, clearing every cache");
+                    self.clear_local().await;
+                }
+                Err(e) => {
+                    warn!("failed to receive cache invalidations: {e}");
+                    return;
+                }
+            }
+        }
+    }
+
+    #[instrument(skip(self))]
+    async fn apply_remote(&self, payload: &str) {
+        let message = match serde_json::from_str::<InvalidationMessage>(payload) {
+            Ok(message) => message,
+            Err(e) => return warn!("invalid cache invalidation: {e}"),
+        };
+        if message.origin == self.bus.origin {
+            return;
+        }
+        match self.find(&message.cache) {
+            Some(cache) => cache.apply(&message.invalidation).await,
+            //declared by another version of the app
+            None => debug!(cache = message.cache, "invalidation for an unknown cache"),
+        }
+    }
+
+    async fn clear_local(&self) {
+        for cache in self.caches() {
+            cache.apply(&Invalidation::All).await;
+        }
+    }
+}
+
+///a `200` response with its headers, stored once the body is fully read
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct CachedResponse {
+    pub headers: Vec<(String, Vec<u8>)>,
+    pub body: Vec<u8>,
+    ///strong, quoted
+    pub etag: String,
+    ///unix millis, the route or the `Cache-Control` of the response can expire it before the cache does
+    pub expires_at: i64,
+}
+
+///what to drop from a cache, sent as is to the other instances
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(tag = "scope", content = "value", rename_all = "snake_case")]
+pub enum Invalidation {
+    Key(String),
+    ///every key starting with it
+    Prefix(String),
+    All,
+}
+
+#[derive(Serialize, Deserialize)]
+struct InvalidationMessage {
+    ///instance that published it, it already applied it
+    origin: u64,
+    cache: String,
+    invalidation: Invalidation,
+}
+
+#[derive(Clone)]
+struct InvalidationBus {
+    connection: PsqlConnection,
+    origin: u64,
+}
+
+impl InvalidationBus {
+    ///the local caches are already invalidated, a failure only leaves the other instances stale
+    ///until their entries expire
+    async fn publish(&self, cache: &'static str, invalidation: Invalidation) {
+        let message = InvalidationMessage {
+            origin: self.origin,
+            cache: cache.to_owned(),
+            invalidation,
+        };
+        let result = match serde_json::to_string(&message) {
+            Ok(payload) => self.connection.notify(INVALIDATION_CHANNEL, &payload).await,
+            Err(e) => Err(e.into()),
+        };
+        if let Err(e) = result {
+            warn!(cache, "failed to publish a cache invalidation: {e}");
+        }
+    }
+}
+
+struct Registry<'a> {
+    metrics: &'a Metrics,
+    config: &'a CacheConfig,
+    bus: &'a InvalidationBus,
+    remote: Option<Arc<dyn CacheBackend>>,
+    caches: Vec<Arc<dyn CacheHandle>>,
 }
-impl Default for CacheManager {
-    fn default() -> Self {
-        Self::new()
+
+impl<'a> Registry<'a> {
+    fn new(
+        metrics: &'a Metrics,
+        config: &'a CacheConfig,
+        bus: &'a InvalidationBus,
+        remote: Option<Arc<dyn CacheBackend>>,
+    ) -> Self {
+        Self {
+            metrics,
+            config,
+            bus,
+            remote,
+            caches: vec![],
+        }
+    }
+
+    fn declare<V>(&mut self, name: &'static str, default: CacheEnv) -> NamedCache<V>
+    where
+        V: Cacheable,
+    {
+        let config = self.config.named(name, default);
+        let cache = NamedCache::new(
+            name,
+            config,
+            self.metrics,
+            self.bus.clone(),
+            self.remote.clone(),
+        );
+        self.caches.push(Arc::new(cache.clone()));
+        cache
     }
 }
+
+///prefixes `key` with the current tenant, so tenants never share an entry and
+///`invalidate_prefix(&tenant_key(""))` drops the entries of one tenant
+pub fn tenant_key(key: &str) -> String {
+    format!("{}:{key}", current_tenant().unwrap_or_default())
+}
+
+///values of a [`NamedCache`], serialized as json in the remote tier
+pub trait Cacheable: Clone + Serialize + DeserializeOwned + Send + Sync + 'static {}
+
+impl<V> Cacheable for V where V: Clone + Serialize + DeserializeOwned + Send + Sync + 'static {}
+
+///a moka cache with a name, its settings come from [`CacheConfig::named`] and its hits, misses and
+///evictions are exported with the `cache` label. invalidations reach every instance.
+///with a remote tier, local misses are read from it and inserts are written to both
+#[derive(Clone)]
+pub struct NamedCache<V> {
+    name: &'static str,
+    cache: Cache<String, V>,
+    remote: Option<Arc<dyn CacheBackend>>,
+    ///of the remote entries, which can't expire on idle so the tti is used as a ttl
+    remote_ttl: Option<Duration>,
+    ///concurrent misses of [`NamedCache::get_or_try_insert_with`] on one key compute it once
+    flights: SingleFlight<String, V>,
+    metrics: Metrics,
+    bus: InvalidationBus,
+}
+
+impl<V> NamedCache<V>
+where
+    V: Cacheable,
+{
+    fn new(
+        name: &'static str,
+        config: CacheEnv,
+        metrics: &Metrics,
+        bus: InvalidationBus,
+        remote: Option<Arc<dyn CacheBackend>>,
+    ) -> Self {
+        let evictions = metrics.cache_evictions.clone();
+        let mut builder = CacheBuilder::new(config.capacity)
+            .name(name)
+            .support_invalidation_closures()
+            .eviction_listener(move |_, _, cause| {
+                let cause = match cause {
+                    RemovalCause::Expired => "expired",
+                    RemovalCause::Size => "size",
+                    RemovalCause::Explicit | RemovalCause::Replaced => return,
+                };
+                evictions.add(
+                    1,
+                    &[KeyValue::new("cache", name), KeyValue::new("cause", cause)],
+                );
+            });
+        if let Some(ttl) = config.ttl {
+            builder = builder.time_to_live(ttl);
+        }
+        if let Some(tti) = config.tti {
+            builder = builder.time_to_idle(tti);
+        }
+        Self {
+            name,
+            cache: builder.build(),
+            remote,
+            remote_ttl: config.ttl.or(config.tti),
+            flights: SingleFlight::new(name, metrics),
+            metrics: metrics.clone(),
+            bus,
+        }
+    }
+
+    ///hits are labeled with the `tier` that had the value
+    pub async fn get(&self, key: &str) -> Option<V> {
+        let cache = KeyValue::new("cache", self.name);
+        if let Some(value) = self.cache.get(key).await {
+            let tier = KeyValue::new("tier", "local");
+            self.metrics.cache_hits.add(1, &[cache, tier]);
+            return Some(value);
+        }
+        if let Some(value) = self.get_remote(key).await {
+            self.cache.insert(key.to_owned(), value.clone()).await;
+            let tier = KeyValue::new("tier", "remote");
+            self.metrics.cache_hits.add(1, &[cache, tier]);
+            return Some(value);
+        }
+        self.metrics.cache_misses.add(1, &[cache]);
+        None
+    }
+
+    pub async fn insert(&self, key: impl Into<String>, value: V) {
+        let key = key.into();
+        self.set_remote(&key, &value).await;
+        self.cache.insert(key, value).await;
+    }
+
+    ///read-through: returns the cached value or stores the one computed by `f`. concurrent misses
+    ///of a key wait for a single call of `f`, its errors are returned to all of them as a
+    ///[`SharedError`](crate::managers::single_flight::SharedError) and nothing gets cached
+    pub async fn get_or_try_insert_with<F, Fut>(&self, key: &str, f: F) -> eyre::Result<V>
+    where
+        F: FnOnce() -> Fut,
+        Fut: Future<Output = eyre::Result<V>>,
+    {
+        if let Some(value) = self.get(key).await {
+            return Ok(value);
+        }
+        //inserted before the flight completes, so later callers find it in the cache
+        let compute = || async {
+            //a flight may have completed since the lookup
+            if let Some(value) = self.cache.get(key).await {
+                return Ok(value);
+            }
+            let value = f().await?;
+            self.insert(key, value.clone()).await;
+            Ok(value)
+        };
+        self.flights.run(key.to_owned(), compute).await
+    }
+
+    pub async fn invalidate(&self, key: &str) {
+        self.invalidate_with(Invalidation::Key(key.to_owned()))
+            .await
+    }
+
+    pub async fn invalidate_prefix(&self, prefix: &str) {
+        self.invalidate_with(Invalidation::Prefix(prefix.to_owned()))
+            .await
+    }
+
+    pub async fn invalidate_all(&self) {
+        self.invalidate_with(Invalidation::All).await
+    }
+
+    ///locally first, then in the remote tier and on the other instances, which would otherwise
+    ///read the stale entry back from the remote tier
+    async fn invalidate_with(&self, invalidation: Invalidation) {
+        self.apply(&invalidation).await;
+        self.delete_remote(&invalidation).await;
+        self.bus.publish(self.name, invalidation).await;
+    }
+
+    fn remote_key(&self, key: &str) -> String {
+        format!("{REMOTE_PREFIX}:{}:{key}", self.name)
+    }
+
+    ///the remote tier is best effort, failures are logged and read as misses
+    async fn get_remote(&self, key: &str) -> Option<V> {
+        let remote = self.remote.as_ref()?;
+        let bytes = match remote.get(&self.remote_key(key)).await {
+            Ok(bytes) => bytes?,
+            Err(e) => {
+                warn!(cache = self.name, "failed to read the remote tier: {e}");
+                return None;
+            }
+        };
+        //written by another version of the app
+        serde_json::from_slice(&bytes)
+            .inspect_err(|e| debug!(cache = self.name, "undecodable remote entry: {e}"))
+            .ok()
+    }
+
+    async fn set_remote(&self, key: &str, value: &V) {
+        let Some(remote) = &self.remote else {
+            return;
+        };
+        let result = match serde_json::to_vec(value) {
+            Ok(bytes) => {
+                remote
+                    .set(&self.remote_key(key), bytes, self.remote_ttl)
+                    .await
+            }
+            Err(e) => Err(e.into()),
+        };
+        if let Err(e) = result {
+            warn!(cache = self.name, "failed to write the remote tier: {e}");
+        }
+    }
+
+    async fn delete_remote(&self, invalidation: &Invalidation) {
+        let Some(remote) = &self.remote else {
+            return;
+        };
+        let result = match invalidation {
+            Invalidation::Key(key) => remote.delete(&self.remote_key(key)).await,
+            Invalidation::Prefix(prefix) => remote.delete_prefix(&self.remote_key(prefix)).await,
+            Invalidation::All => remote.delete_prefix(&self.remote_key("")).await,
+        };
+        if let Err(e) = result {
+            warn!(
+                cache = self.name,
+                "failed to invalidate the remote tier: {e}"
+            );
+        }
+    }
+}
+
+///the operations that don't need the value type, to handle every cache the same way
+pub trait CacheHandle: Send + Sync {
+    fn name(&self) -> &'static str;
+    ///on this instance only, prefixes are dropped lazily: the entries are gone for readers right
+    ///away but the memory is freed by the next maintenance
+    fn apply<'a>(&'a self, invalidation: &'a Invalidation) -> BoxFuture<'a, ()>;
+    fn entry_count(&self) -> u64;
+    fn run_pending_tasks(&self) -> BoxFuture<'_, ()>;
+}
+
+impl<V> CacheHandle for NamedCache<V>
+where
+    V: Cacheable,
+{
+    fn name(&self) -> &'static str {
+        self.name
+    }
+
+    fn apply<'a>(&'a self, invalidation: &'a Invalidation) -> BoxFuture<'a, ()> {
+        Box::pin(async move {
+            match invalidation {
+                Invalidation::Key(key) => self.cache.invalidate(key).await,
+                Invalidation::Prefix(prefix) => {
+                    let prefix = prefix.clone();
+                    if let Err(e) = self
+                        .cache
+                        .invalidate_entries_if(move |key, _| key.starts_with(&prefix))
+                    {
+                        warn!(cache = self.name, "failed to invalidate by prefix: {e}");
+                    }
+                }
+                Invalidation::All => self.cache.invalidate_all(),
+            }
+        })
+    }
+
+    fn entry_count(&self) -> u64 {
+        self.cache.entry_count()
+    }
+
+    fn run_pending_tasks(&self) -> BoxFuture<'_, ()> {
+        Box::pin(async move {
+            self.cache.run_pending_tasks().await;
+            self.metrics.cache_entries.record(
+                self.cache.entry_count(),
+                &[KeyValue::new("cache", self.name)],
+            );
+        })
+    }
+}
+
 impl Deref for CacheManager {
     type Target = CacheManagerInner;
     fn deref(&self) -> &Self::Target {
         &self.inner
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::managers::cache_backend::FakeBackend;
+    use sqlx::postgres::PgPoolOptions;
+
+    ///two instances sharing `remote`, their invalidations can't be published without a database
+    ///and are only logged
+    fn instance<V: Cacheable>(remote: &FakeBackend) -> NamedCache<V> {
+        let metrics = Metrics::new();
+        let db = PgPoolOptions::new()
+            .acquire_timeout(Duration::from_millis(10))
+            .connect_lazy("postgres://localhost:1/none")
+            .unwrap();
+        let bus = InvalidationBus {
+            connection: PsqlConnection::new(db, metrics.clone()),
+            origin: rand::random(),
+        };
+        let config = CacheEnv {
+            capacity: 100,
+            ttl: Some(Duration::from_secs(60)),
+            tti: None,
+        };
+        NamedCache::new(
+            "test",
+            config,
+            &metrics,
+            bus,
+            Some(Arc::new(remote.clone())),
+        )
+    }
+
+    #[tokio::test]
+    async fn insert_writes_both_tiers() {
+        let remote = FakeBackend::new();
+        let (a, b) = (instance::<String>(&remote), instance::<String>(&remote));
+        a.insert("k", "v".to_owned()).await;
+        assert_eq!(a.cache.get("k").await.as_deref(), Some("v"));
+        assert_eq!(
+            remote.get("cache:test:k").await.unwrap(),
+            Some(br#""v""#.to_vec())
+        );
+        //the other instance misses locally and reads it from the remote tier
+        assert_eq!(b.cache.get("k").await, None);
+        assert_eq!(b.get("k").await.as_deref(), Some("v"));
+    }
+
+    #[tokio::test]
+    async fn remote_hits_fill_the_local_tier() {
+        let remote = FakeBackend::new();
+        let cache = instance::<String>(&remote);
+        remote
+            .set("cache:test:k", br#""v""#.to_vec(), None)
+            .await
+            .unwrap();
+        assert_eq!(cache.get("k").await.as_deref(), Some("v"));
+        remote.delete("cache:test:k").await.unwrap();
+        assert_eq!(cache.get("k").await.as_deref(), Some("v"));
+    }
+
+    #[tokio::test]
+    async fn undecodable_remote_entries_are_misses() {
+        let remote = FakeBackend::new();
+        let cache = instance::<u64>(&remote);
+        remote
+            .set("cache:test:k", b"not json".to_vec(), None)
+            .await
+            .unwrap();
+        assert_eq!(cache.get("k").await, None);
+        let value = cache.get_or_try_insert_with("k", || async { Ok(7) }).await;
+        assert_eq!(value.unwrap(), 7);
+        assert_eq!(
+            remote.get("cache:test:k").await.unwrap(),
+            Some(b"7".to_vec())
+        );
+    }
+
+    #[tokio::test]
+    async fn invalidate_clears_both_tiers() {
+        let remote = FakeBackend::new();
+        let (a, b) = (instance::<String>(&remote), instance::<String>(&remote));
+        a.insert("k", "v".to_owned()).await;
+        a.insert("other", "v".to_owned()).await;
+        a.invalidate("k").await;
+        assert_eq!(a.get("k").await, None);
+        //without the remote delete the other instance would read the stale entry back
+        assert_eq!(b.get("k").await, None);
+        assert_eq!(b.get("other").await.as_deref(), Some("v"));
+    }
+
+    #[tokio::test]
+    async fn invalidate_prefix_and_all() {
+        let remote = FakeBackend::new();
+        let cache = instance::<String>(&remote);
+        for key in ["t1:a", "t1:b", "t2:a"] {
+            cache.insert(key, key.to_owned()).await;
+        }
+        remote
+            .set("cache:other:t1:a", b"1".to_vec(), None)
+            .await
+            .unwrap();
+        cache.invalidate_prefix("t1:").await;
+        assert_eq!(cache.get("t1:a").await, None);
+        assert_eq!(cache.get("t1:b").await, None);
+        assert_eq!(cache.get("t2:a").await.as_deref(), Some("t2:a"));
+        cache.invalidate_all().await;
+        assert_eq!(cache.get("t2:a").await, None);
+        //the entries of the other caches are left alone
+        assert_eq!(remote.len(), 1);
+    }
+
+    #[tokio::test]
+    async fn cached_responses_round_trip() {
+        let remote = FakeBackend::new();
+        let (a, b) = (
+            instance::<CachedResponse>(&remote),
+            instance::<CachedResponse>(&remote),
+        );
+        let response = CachedResponse {
+            headers: vec![("content-type".to_owned(), b"application/json".to_vec())],
+            body: vec![0, 159, 146, 150],
+            etag: r#""abc""#.to_owned(),
+            expires_at: 1_700_000_000_000,
+        };
+        a.insert("k", response.clone()).await;
+        let read = b.get("k").await.unwrap();
+        assert_eq!(read.headers, response.headers);
+        assert_eq!(read.body, response.body);
+        assert_eq!(read.etag, response.etag);
+        assert_eq!(read.expires_at, response.expires_at);
+    }
+
+    #[tokio::test]
+    async fn users_round_trip() {
+        let remote = FakeBackend::new();
+        let (a, b) = (
+            instance::<Option<UserProfile>>(&remote),
+            instance::<Option<UserProfile>>(&remote),
+        );
+        let now = chrono::Utc::now().fixed_offset();
+        let user = UserProfile {
+            id: 1,
+            tenant_id: "acme".to_owned(),
+            identity: "alice".to_owned(),
+            display_name: Some("Alice".to_owned()),
+            version: 3,
+            created_at: now,
+            updated_at: now,
+        };
+        a.insert("acme:alice", Some(user.clone())).await;
+        //identities without a user are cached too
+        a.insert("acme:bob", None).await;
+        assert_eq!(b.get("acme:alice").await, Some(Some(user)));
+        assert_eq!(b.get("acme:bob").await, Some(None));
+    }
+
+    #[test]
+    fn invalidations_keep_their_wire_format() {
+        let message = InvalidationMessage {
+            origin: 1,
+            cache: "users".to_owned(),
+            invalidation: Invalidation::Prefix("acme:".to_owned()),
+        };
+        let payload = serde_json::to_string(&message).unwrap();
+        assert_eq!(
+            payload,
+            r#"{"origin":1,"cache":"users","invalidation":{"scope":"prefix","value":"acme:"}}"#
+        );
+        let all = serde_json::to_string(&Invalidation::All).unwrap();
+        assert_eq!(all, r#"{"scope":"all"}"#);
+        let read: InvalidationMessage = serde_json::from_str(&payload).unwrap();
+        assert!(matches!(read.invalidation, Invalidation::Prefix(p) if p == "acme:"));
+    }
+}
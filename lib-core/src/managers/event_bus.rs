@@ -0,0 +1,405 @@
+use crate::managers::scheduler::JobFuture;
+use crate::managers::thread_manager::ThreadManager;
+use eyre::eyre;
+use lib_shared::metrics::Metrics;
+use lib_shared::{Res, error, instrument, warn};
+use opentelemetry::KeyValue;
+use parking_lot::RwLock;
+use serde::Serialize;
+use serde::de::DeserializeOwned;
+use std::any::Any;
+use std::future::Future;
+use std::panic::AssertUnwindSafe;
+use std::sync::Arc;
+use std::time::Instant;
+use tokio::sync::mpsc;
+use tokio::sync::mpsc::error::TrySendError;
+
+///a domain event, `NAME` identifies it across the bus, metrics and the outbox
+pub trait Event: Serialize + DeserializeOwned + Clone + Send + Sync + 'static {
+    const NAME: &'static str;
+}
+
+///what `publish` does when an async subscriber's queue is full
+#[derive(Clone, Copy, Debug)]
+pub enum Backpressure {
+    ///wait until the subscriber catches up
+    Block,
+    ///drop the event for this subscriber and count it
+    Drop,
+}
+
+type SyncHandler<E> = Arc<dyn Fn(&E) -> Res + Send + Sync>;
+type JsonPublisher = Arc<dyn Fn(EventBus, serde_json::Value) -> JobFuture + Send + Sync>;
+///an event and the [`idempotency_key`] it was published with
+type Delivery<E> = (E, Option<String>);
+
+tokio::task_local! {
+    static IDEMPOTENCY_KEY: Option<String>;
+}
+
+///the key of the stored event being delivered, inside the subscribers. the outbox delivers
+///at-least-once, subscribers with side effects use it to drop the duplicates. `None` for events
+///published directly
+pub fn idempotency_key() -> Option<String> {
+    IDEMPOTENCY_KEY.try_with(Clone::clone).ok().flatten()
+}
+
+struct Subscribers<E> {
+    sync: Vec<(String, SyncHandler<E>)>,
+    async_: Vec<(String, Backpressure, mpsc::Sender<Delivery<E>>)>,
+}
+
+impl<E> Clone for Subscribers<E> {
+    fn clone(&self) -> Self {
+        Self {
+            sync: self.sync.clone(),
+            async_: self.async_.clone(),
+        }
+    }
+}
+
+///typed in-process publish/subscribe, subscribers are registered at startup
+#[derive(Clone)]
+pub struct EventBus {
+    inner: Arc<RwLock<InnerMut>>,
+    thread_manager: ThreadManager,
+    metrics: Metrics,
+}
+
+#[derive(Default)]
+struct InnerMut {
+    subscribers: hashbrown::HashMap<&'static str, Arc<dyn Any + Send + Sync>>,
+    json_publishers: hashbrown::HashMap<&'static str, JsonPublisher>,
+}
+
+impl EventBus {
+    pub fn new(thread_manager: ThreadManager, metrics: Metrics) -> Self {
+        Self {
+            inner: Default::default(),
+            thread_manager,
+            metrics,
+        }
+    }
+
+    ///runs inline inside `publish`, keep it cheap
+    pub fn subscribe<E, F>(&self, name: &str, handler: F)
+    where
+        E: Event,
+        F: Fn(&E) -> Res + Send + Sync + 'static,
+    {
+        self.update::<E>(|subs| subs.sync.push((name.to_owned(), Arc::new(handler))));
+    }
+
+    ///runs on its own task fed by a bounded queue of `capacity` events
+    #[instrument(skip(self, handler), fields(event = E::NAME))]
+    pub async fn subscribe_async<E, F, Fut>(
+        &self,
+        name: &str,
+        capacity: usize,
+        backpressure: Backpressure,
+        handler: F,
+    ) where
+        E: Event,
+        F: Fn(E) -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = Res> + Send + 'static,
+    {
+        let (tx, mut rx) = mpsc::channel::<Delivery<E>>(capacity);
+        self.update::<E>(|subs| subs.async_.push((name.to_owned(), backpressure, tx)));
+
+        let metrics = self.metrics.clone();
+        let subscriber = name.to_owned();
+        let handler = Arc::new(handler);
+        let handle = tokio::spawn(async move {
+            while let Some((event, key)) = rx.recv().await {
+                let start = Instant::now();
+                //a panicking subscriber only loses the current event
+                let result = tokio::spawn(IDEMPOTENCY_KEY.scope(key, handler(event)))
+                    .await
+                    .unwrap_or_else(|e| Err(eyre!("subscriber panicked: {e}")));
+                record(&metrics, E::NAME, &subscriber, start, result);
+            }
+            Ok(())
+        });
+        self.thread_manager
+            .add(&format!("event-subscriber:{}:{name}", E::NAME), handle)
+            .await;
+    }
+
+    pub async fn publish<E: Event>(&self, event: E) {
+        let labels = [KeyValue::new("event", E::NAME)];
+        self.metrics.event_published_count.add(1, &labels);
+        let Some(subs) = self.subscribers::<E>() else {
+            return;
+        };
+
+        for (name, handler) in &subs.sync {
+            let start = Instant::now();
+            let result = std::panic::catch_unwind(AssertUnwindSafe(|| handler(&event)))
+                .unwrap_or_else(|_| Err(eyre!("subscriber panicked")));
+            record(&self.metrics, E::NAME, name, start, result);
+        }
+
+        let key = idempotency_key();
+        for (name, backpressure, tx) in &subs.async_ {
+            let delivery = (event.clone(), key.clone());
+            let sent = match backpressure {
+                Backpressure::Block => tx.send(delivery).await.is_ok(),
+                Backpressure::Drop => match tx.try_send(delivery) {
+                    Ok(_) => true,
+                    Err(TrySendError::Full(_)) => {
+                        warn!(event = E::NAME, subscriber = name, "subscriber is full");
+                        false
+                    }
+                    Err(TrySendError::Closed(_)) => false,
+                },
+            };
+            if !sent {
+                self.metrics.event_dropped_count.add(
+                    1,
+                    &[
+                        KeyValue::new("event", E::NAME),
+                        KeyValue::new("subscriber", name.clone()),
+                    ],
+                );
+            }
+        }
+    }
+
+    ///publishes an event from its serialized form, used to replay stored events. subscribers
+    ///read `idempotency_key` with [`idempotency_key`]
+    pub async fn publish_json(
+        &self,
+        name: &str,
+        payload: serde_json::Value,
+        idempotency_key: Option<String>,
+    ) -> Res {
+        let publisher = self.inner.read().json_publishers.get(name).cloned();
+        match publisher {
+            Some(publisher) => {
+                IDEMPOTENCY_KEY
+                    .scope(idempotency_key, publisher(self.clone(), payload))
+                    .await
+            }
+            None => {
+                warn!(event = name, "no subscribers for event");
+                Ok(())
+            }
+        }
+    }
+
+    fn subscribers<E: Event>(&self) -> Option<Arc<Subscribers<E>>> {
+        let subs = self.inner.read().subscribers.get(E::NAME).cloned()?;
+        subs.downcast::<Subscribers<E>>().ok()
+    }
+
+    fn update<E: Event>(&self, f: impl FnOnce(&mut Subscribers<E>)) {
+        let mut inner = self.inner.write();
+        let mut subs = inner
+            .subscribers
+            .get(E::NAME)
+            .and_then(|subs| subs.clone().downcast::<Subscribers<E>>().ok())
+            .map(|subs| subs.as_ref().clone())
+            .unwrap_or(Subscribers {
+                sync: vec![],
+                async_: vec![],
+            });
+        f(&mut subs);
+        inner.subscribers.insert(E::NAME, Arc::new(subs));
+        inner.json_publishers.entry(E::NAME).or_insert_with(|| {
+            Arc::new(|bus, payload| {
+                Box::pin(async move {
+                    bus.publish(serde_json::from_value::<E>(payload)?).await;
+                    Ok(())
+                })
+            })
+        });
+    }
+}
+
+fn record(metrics: &Metrics, event: &'static str, subscriber: &str, start: Instant, result: Res) {
+    let labels = [
+        KeyValue::new("event", event),
+        KeyValue::new("subscriber", subscriber.to_owned()),
+    ];
+    metrics
+        .event_handler_duration
+        .record(start.elapsed().as_secs_f64(), &labels);
+    if let Err(e) = result {
+        error!(
+            event,
+            subscriber,
+            error = e.to_string(),
+            "event subscriber failed"
+        );
+        metrics.event_handler_failures.add(1, &labels);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use lib_shared::metrics::testing::MetricsReader;
+    use parking_lot::Mutex;
+    use serde::Deserialize;
+    use std::time::Duration;
+    use tokio::sync::Semaphore;
+    use tokio::time::timeout;
+
+    #[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+    struct Ping {
+        n: u32,
+    }
+
+    impl Event for Ping {
+        const NAME: &'static str = "test.ping";
+    }
+
+    fn bus() -> (EventBus, MetricsReader) {
+        let (metrics, reader) = MetricsReader::new();
+        (EventBus::new(ThreadManager::new(), metrics), reader)
+    }
+
+    fn labels(subscriber: &str) -> [KeyValue; 2] {
+        [
+            KeyValue::new("event", Ping::NAME),
+            KeyValue::new("subscriber", subscriber.to_owned()),
+        ]
+    }
+
+    ///an async subscriber that reports every event it starts and then waits for a permit of `gate`
+    async fn gated(
+        bus: &EventBus,
+        capacity: usize,
+        backpressure: Backpressure,
+    ) -> (Arc<Semaphore>, mpsc::UnboundedReceiver<u32>) {
+        let gate = Arc::new(Semaphore::new(0));
+        let (tx, rx) = mpsc::unbounded_channel();
+        let handler_gate = gate.clone();
+        bus.subscribe_async::<Ping, _, _>("gated", capacity, backpressure, move |ping| {
+            let (gate, tx) = (handler_gate.clone(), tx.clone());
+            async move {
+                _ = tx.send(ping.n);
+                gate.acquire().await?.forget();
+                Ok(())
+            }
+        })
+        .await;
+        (gate, rx)
+    }
+
+    #[tokio::test]
+    async fn drop_counts_the_events_of_a_full_subscriber() {
+        let (bus, reader) = bus();
+        let (gate, mut started) = gated(&bus, 1, Backpressure::Drop).await;
+        bus.publish(Ping { n: 1 }).await;
+        //the subscriber holds the first one, the second one waits in its queue
+        assert_eq!(started.recv().await, Some(1));
+        bus.publish(Ping { n: 2 }).await;
+        bus.publish(Ping { n: 3 }).await;
+        bus.publish(Ping { n: 4 }).await;
+        assert_eq!(reader.sum("events.dropped.count", &labels("gated")), 2);
+        assert_eq!(reader.sum("events.published.count", &[]), 4);
+
+        gate.add_permits(4);
+        assert_eq!(started.recv().await, Some(2));
+        bus.publish(Ping { n: 5 }).await;
+        assert_eq!(started.recv().await, Some(5));
+        assert_eq!(reader.sum("events.dropped.count", &labels("gated")), 2);
+    }
+
+    #[tokio::test]
+    async fn block_waits_for_a_full_subscriber() {
+        let (bus, reader) = bus();
+        let (gate, mut started) = gated(&bus, 1, Backpressure::Block).await;
+        bus.publish(Ping { n: 1 }).await;
+        assert_eq!(started.recv().await, Some(1));
+        bus.publish(Ping { n: 2 }).await;
+        let blocked = timeout(Duration::from_millis(50), bus.publish(Ping { n: 3 })).await;
+        assert!(blocked.is_err(), "publish didn't wait for the subscriber");
+
+        gate.add_permits(3);
+        timeout(Duration::from_secs(1), bus.publish(Ping { n: 4 }))
+            .await
+            .expect("publish is still blocked");
+        assert_eq!(started.recv().await, Some(2));
+        assert_eq!(started.recv().await, Some(4));
+        assert_eq!(reader.sum("events.dropped.count", &[]), 0);
+    }
+
+    #[tokio::test]
+    async fn a_panicking_sync_subscriber_doesnt_stop_the_others() {
+        let (bus, reader) = bus();
+        let seen = Arc::new(Mutex::new(vec![]));
+        bus.subscribe::<Ping, _>("panics", |_| panic!("boom"));
+        let sub_seen = seen.clone();
+        bus.subscribe::<Ping, _>("records", move |ping| {
+            sub_seen.lock().push(ping.n);
+            Ok(())
+        });
+        bus.subscribe::<Ping, _>("fails", |_| Err(eyre!("boom")));
+
+        bus.publish(Ping { n: 1 }).await;
+        bus.publish(Ping { n: 2 }).await;
+        assert_eq!(*seen.lock(), [1, 2]);
+        assert_eq!(reader.sum("events.handler.failures", &labels("panics")), 2);
+        assert_eq!(reader.sum("events.handler.failures", &labels("fails")), 2);
+        assert_eq!(reader.sum("events.handler.failures", &labels("records")), 0);
+        assert_eq!(
+            reader.count("events.handler.duration", &labels("records")),
+            2
+        );
+    }
+
+    #[tokio::test]
+    async fn async_subscriber_failures_are_counted_and_it_keeps_running() {
+        let (bus, reader) = bus();
+        let (tx, mut rx) = mpsc::unbounded_channel();
+        bus.subscribe_async::<Ping, _, _>("flaky", 8, Backpressure::Block, move |ping| {
+            let tx = tx.clone();
+            async move {
+                match ping.n {
+                    1 => Err(eyre!("boom")),
+                    2 => panic!("boom"),
+                    n => {
+                        _ = tx.send(n);
+                        Ok(())
+                    }
+                }
+            }
+        })
+        .await;
+        for n in 1..=3 {
+            bus.publish(Ping { n }).await;
+        }
+        //handled in order, so the failures are recorded once 3 arrives
+        assert_eq!(rx.recv().await, Some(3));
+        assert_eq!(reader.sum("events.handler.failures", &labels("flaky")), 2);
+        assert_eq!(reader.count("events.handler.duration", &labels("flaky")), 3);
+    }
+
+    #[tokio::test]
+    async fn publish_json_decodes_the_event() {
+        let (bus, _) = bus();
+        let seen = Arc::new(Mutex::new(vec![]));
+        let sub_seen = seen.clone();
+        bus.subscribe::<Ping, _>("records", move |ping| {
+            sub_seen.lock().push((ping.clone(), idempotency_key()));
+            Ok(())
+        });
+
+        let payload = serde_json::json!({ "n": 7 });
+        bus.publish_json(Ping::NAME, payload, Some("ping:7".to_owned()))
+            .await
+            .unwrap();
+        let invalid = serde_json::json!({ "n": "seven" });
+        assert!(bus.publish_json(Ping::NAME, invalid, None).await.is_err());
+        //nobody subscribed to it, nothing to decode
+        let unknown = serde_json::json!({ "n": "seven" });
+        bus.publish_json("test.unknown", unknown, None)
+            .await
+            .unwrap();
+
+        assert_eq!(*seen.lock(), [(Ping { n: 7 }, Some("ping:7".to_owned()))]);
+    }
+}
@@ -0,0 +1,363 @@
+use crate::managers::scheduler::JobFuture;
+use crate::managers::thread_manager::ThreadManager;
+use chrono::Utc;
+use eyre::eyre;
+use lib_db::JobQueueDriver;
+use lib_db::job_queue_driver::QueuedJob;
+use lib_shared::metrics::Metrics;
+use lib_shared::{Res, error, instrument, warn};
+use opentelemetry::KeyValue;
+use parking_lot::RwLock;
+use serde::Serialize;
+use serde::de::DeserializeOwned;
+use std::future::Future;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::time::{sleep, timeout};
+use tracing::info;
+
+const POLL_INTERVAL: Duration = Duration::from_secs(1);
+const BASE_BACKOFF: Duration = Duration::from_secs(10);
+const MAX_BACKOFF: Duration = Duration::from_secs(60 * 60);
+///the [`QueueJob::TIMEOUT`] unless a kind sets its own
+pub const DEFAULT_TIMEOUT: Duration = Duration::from_secs(5 * 60);
+///jobs locked for longer than their timeout plus this margin are considered abandoned by a
+///crashed worker, so a slow job is never picked up again while it still runs
+const LOCK_MARGIN: Duration = Duration::from_secs(5 * 60);
+
+///a serializable unit of background work, stored in the `job_queue` table until a worker runs it
+pub trait QueueJob: Serialize + DeserializeOwned + Send + 'static {
+    ///unique name used to route stored jobs to their handler
+    const KIND: &'static str;
+    ///handlers running longer are aborted and the job retried
+    const TIMEOUT: Duration = DEFAULT_TIMEOUT;
+}
+
+pub struct EnqueueOptions {
+    ///higher runs first
+    pub priority: i16,
+    pub delay: Duration,
+    pub max_attempts: i32,
+}
+
+impl Default for EnqueueOptions {
+    fn default() -> Self {
+        Self {
+            priority: 0,
+            delay: Duration::ZERO,
+            max_attempts: 5,
+        }
+    }
+}
+
+type Handler = Arc<dyn Fn(serde_json::Value) -> JobFuture + Send + Sync>;
+
+#[derive(Clone)]
+struct Registered {
+    handler: Handler,
+    timeout: Duration,
+}
+
+#[derive(Clone)]
+pub struct JobQueue {
+    driver: JobQueueDriver,
+    metrics: Metrics,
+    inner: Arc<RwLock<InnerMut>>,
+}
+
+#[derive(Default)]
+struct InnerMut {
+    handlers: hashbrown::HashMap<&'static str, Registered>,
+}
+
+impl JobQueue {
+    pub fn new(driver: JobQueueDriver, metrics: Metrics) -> Self {
+        Self {
+            driver,
+            metrics,
+            inner: Default::default(),
+        }
+    }
+
+    #[instrument(skip(self, job, options), fields(kind = J::KIND))]
+    pub async fn enqueue<J: QueueJob>(
+        &self,
+        job: &J,
+        options: EnqueueOptions,
+    ) -> eyre::Result<i64> {
+        let run_at = Utc::now() + options.delay;
+        self.driver
+            .enqueue(
+                J::KIND,
+                serde_json::to_value(job)?,
+                options.priority,
+                run_at,
+                options.max_attempts,
+            )
+            .await
+    }
+
+    ///workers only claim kinds that have a handler on this instance. a handler running longer than
+    ///[`QueueJob::TIMEOUT`] is aborted and its job retried
+    pub fn register<J, F, Fut>(&self, handler: F)
+    where
+        J: QueueJob,
+        F: Fn(J) -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = Res> + Send + 'static,
+    {
+        let handler = Arc::new(handler);
+        let handler: Handler = Arc::new(move |payload| {
+            let handler = handler.clone();
+            Box::pin(async move { handler(serde_json::from_value::<J>(payload)?).await })
+        });
+        let registered = Registered {
+            handler,
+            timeout: J::TIMEOUT,
+        };
+        self.inner.write().handlers.insert(J::KIND, registered);
+    }
+
+    #[instrument(skip(self, thread_manager))]
+    pub async fn start(&self, thread_manager: &ThreadManager, workers: usize) {
+        for i in 0..workers {
+            let handle = tokio::spawn(self.clone().run_worker());
+            thread_manager
+                .add(&format!("job-queue-worker-{i}"), handle)
+                .await;
+        }
+    }
+
+    ///resets jobs whose worker died mid-run, buries the ones out of attempts and deletes old
+    ///finished jobs
+    pub async fn maintain(&self, keep_finished: Duration) -> Res {
+        let windows = self
+            .inner
+            .read()
+            .handlers
+            .iter()
+            .map(|(kind, registered)| (kind.to_string(), registered.timeout + LOCK_MARGIN))
+            .collect::<Vec<_>>();
+        let stale = self
+            .driver
+            .release_stale(&windows, DEFAULT_TIMEOUT + LOCK_MARGIN)
+            .await?;
+        if stale.released > 0 {
+            warn!(stale.released, "released stale queue jobs");
+        }
+        if stale.buried > 0 {
+            error!(
+                stale.buried,
+                "buried stale queue jobs that were out of attempts"
+            );
+        }
+        let purged = self.driver.purge_finished(keep_finished).await?;
+        info!(purged, "purged finished queue jobs");
+        Ok(())
+    }
+
+    async fn run_worker(self) -> Res {
+        loop {
+            let kinds = self.kinds();
+            if kinds.is_empty() {
+                sleep(POLL_INTERVAL).await;
+                continue;
+            }
+            let jobs = match self.driver.claim(&kinds, 1).await {
+                Ok(jobs) => jobs,
+                Err(e) => {
+                    error!(error = e.to_string(), "failed to claim queue jobs");
+                    sleep(POLL_INTERVAL).await;
+                    continue;
+                }
+            };
+            if jobs.is_empty() {
+                sleep(POLL_INTERVAL).await;
+                continue;
+            }
+            for job in jobs {
+                self.process(job).await;
+            }
+        }
+    }
+
+    #[instrument(skip_all, fields(id = job.id, kind = job.kind, attempt = job.attempts))]
+    async fn process(&self, job: QueuedJob) {
+        let start = Instant::now();
+        let result = match self.registered(&job.kind) {
+            Some(registered) => run(registered, job.payload).await,
+            None => Err(eyre!("no handler registered for {}", job.kind)),
+        };
+
+        let outcome = Outcome::of(&result, job.attempts, job.max_attempts);
+        let stored = match (outcome, result) {
+            (Outcome::Done, _) => self.driver.complete(job.id, job.attempts).await,
+            (Outcome::Dead, result) => {
+                let e = result.err().map(|e| e.to_string()).unwrap_or_default();
+                error!(error = e, "job exhausted its attempts");
+                self.driver.bury(job.id, job.attempts, &e).await
+            }
+            (Outcome::Retry, result) => {
+                let e = result.err().map(|e| e.to_string()).unwrap_or_default();
+                warn!(error = e, "job failed, scheduling a retry");
+                let run_at = Utc::now() + backoff(job.attempts);
+                self.driver.retry(job.id, job.attempts, &e, run_at).await
+            }
+        };
+        let outcome = match stored {
+            Ok(true) => outcome.as_str(),
+            Ok(false) => {
+                warn!("job was released as stale while it ran, dropping its result");
+                "lost"
+            }
+            Err(e) => {
+                error!(error = e.to_string(), "failed to store job outcome");
+                "unknown"
+            }
+        };
+
+        let labels = [
+            KeyValue::new("kind", job.kind),
+            KeyValue::new("outcome", outcome),
+        ];
+        self.metrics.queue_job_count.add(1, &labels);
+        self.metrics
+            .queue_job_duration
+            .record(start.elapsed().as_secs_f64(), &labels);
+    }
+
+    fn kinds(&self) -> Vec<String> {
+        self.inner
+            .read()
+            .handlers
+            .keys()
+            .map(ToString::to_string)
+            .collect()
+    }
+
+    fn registered(&self, kind: &str) -> Option<Registered> {
+        self.inner.read().handlers.get(kind).cloned()
+    }
+}
+
+///runs the handler on its own task, panics and timeouts are reported as a failed attempt
+async fn run(registered: Registered, payload: serde_json::Value) -> Res {
+    let mut task = tokio::spawn((registered.handler)(payload));
+    match timeout(registered.timeout, &mut task).await {
+        Ok(result) => result.unwrap_or_else(|e| Err(eyre!("job panicked: {e}"))),
+        Err(_) => {
+            task.abort();
+            Err(eyre!("job timed out after {:?}", registered.timeout))
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Outcome {
+    Done,
+    Retry,
+    Dead,
+}
+
+impl Outcome {
+    fn of(result: &Res, attempts: i32, max_attempts: i32) -> Self {
+        match result {
+            Ok(_) => Self::Done,
+            Err(_) if attempts >= max_attempts => Self::Dead,
+            Err(_) => Self::Retry,
+        }
+    }
+
+    fn as_str(self) -> &'static str {
+        match self {
+            Self::Done => "done",
+            Self::Retry => "retry",
+            Self::Dead => "dead",
+        }
+    }
+}
+
+///exponential backoff with up to 20% jitter
+pub(crate) fn backoff(attempts: i32) -> Duration {
+    let exp = BASE_BACKOFF.saturating_mul(2u32.saturating_pow(attempts.max(1) as u32 - 1));
+    let delay = exp.min(MAX_BACKOFF);
+    delay + delay.mul_f64(rand::random_range(0.0..0.2))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn registered<F>(timeout: Duration, handler: F) -> Registered
+    where
+        F: Fn() -> Res + Send + Sync + 'static,
+    {
+        let handler = Arc::new(handler);
+        Registered {
+            handler: Arc::new(move |_| {
+                let handler = handler.clone();
+                Box::pin(async move { handler() })
+            }),
+            timeout,
+        }
+    }
+
+    #[test]
+    fn backoff_doubles_up_to_the_cap_with_jitter() {
+        for attempts in [-1, 0, 1] {
+            let delay = backoff(attempts);
+            assert!(delay >= BASE_BACKOFF && delay <= BASE_BACKOFF.mul_f64(1.2));
+        }
+        for attempts in 2..8 {
+            let base = BASE_BACKOFF * 2u32.pow(attempts as u32 - 1);
+            let delay = backoff(attempts);
+            assert!(delay >= base && delay <= base.mul_f64(1.2), "{attempts}");
+        }
+        for attempts in [20, 40, i32::MAX] {
+            let delay = backoff(attempts);
+            assert!(delay >= MAX_BACKOFF && delay <= MAX_BACKOFF.mul_f64(1.2));
+        }
+        //the jitter spreads retries of jobs that failed together
+        let delays = (0..20)
+            .map(|_| backoff(3))
+            .collect::<hashbrown::HashSet<_>>();
+        assert!(delays.len() > 1);
+    }
+
+    #[test]
+    fn outcomes_follow_the_attempts() {
+        let failed = || Err(eyre!("boom"));
+        assert_eq!(Outcome::of(&Ok(()), 5, 5), Outcome::Done);
+        assert_eq!(Outcome::of(&failed(), 1, 5), Outcome::Retry);
+        assert_eq!(Outcome::of(&failed(), 4, 5), Outcome::Retry);
+        assert_eq!(Outcome::of(&failed(), 5, 5), Outcome::Dead);
+        //a job released as stale after its last attempt was claimed once more
+        assert_eq!(Outcome::of(&failed(), 6, 5), Outcome::Dead);
+    }
+
+    #[tokio::test]
+    async fn panics_and_timeouts_fail_the_attempt() {
+        let ok = registered(Duration::from_secs(1), || Ok(()));
+        assert!(run(ok, serde_json::Value::Null).await.is_ok());
+
+        let failed = registered(Duration::from_secs(1), || Err(eyre!("boom")));
+        let e = run(failed, serde_json::Value::Null).await.unwrap_err();
+        assert_eq!(e.to_string(), "boom");
+
+        let panicked = registered(Duration::from_secs(1), || panic!("boom"));
+        let e = run(panicked, serde_json::Value::Null).await.unwrap_err();
+        assert!(e.to_string().starts_with("job panicked"), "{e}");
+
+        let handler: Handler = Arc::new(|_| {
+            Box::pin(async {
+                sleep(Duration::from_secs(60)).await;
+                Ok(())
+            })
+        });
+        let slow = Registered {
+            handler,
+            timeout: Duration::from_millis(10),
+        };
+        let e = run(slow, serde_json::Value::Null).await.unwrap_err();
+        assert_eq!(e.to_string(), "job timed out after 10ms");
+    }
+}
@@ -0,0 +1,131 @@
+use lib_db::psql_connection::PsqlConnection;
+use lib_shared::metrics::Metrics;
+use lib_shared::{Res, error, instrument, warn};
+use std::time::Duration;
+use tokio::sync::watch;
+use tokio::task::JoinHandle;
+use tokio::time::sleep;
+use tracing::info;
+
+const RETRY_INTERVAL: Duration = Duration::from_secs(5);
+const HEARTBEAT_INTERVAL: Duration = Duration::from_secs(2);
+const HEARTBEAT_TIMEOUT: Duration = Duration::from_secs(3);
+
+///elects a single leader among the replicas through a postgres advisory lock
+#[derive(Clone)]
+pub struct LeaderElection {
+    rx: watch::Receiver<bool>,
+}
+
+impl LeaderElection {
+    ///the returned handle drives the election and should be tracked by the thread manager
+    pub fn new(connection: PsqlConnection, key: &str, metrics: Metrics) -> (Self, JoinHandle<Res>) {
+        let (tx, rx) = watch::channel(false);
+        let handle = tokio::spawn(campaign(connection, key.to_owned(), tx, metrics));
+        (Self { rx }, handle)
+    }
+
+    ///never changes, for tests
+    #[cfg(test)]
+    pub(crate) fn fixed(is_leader: bool) -> Self {
+        Self {
+            rx: watch::channel(is_leader).1,
+        }
+    }
+
+    ///changed through the returned sender, for tests
+    #[cfg(test)]
+    pub(crate) fn manual(is_leader: bool) -> (Self, watch::Sender<bool>) {
+        let (tx, rx) = watch::channel(is_leader);
+        (Self { rx }, tx)
+    }
+
+    pub fn is_leader(&self) -> bool {
+        *self.rx.borrow()
+    }
+
+    pub fn subscribe(&self) -> watch::Receiver<bool> {
+        self.rx.clone()
+    }
+
+    ///resolves once this instance isn't the leader, right away if it isn't one now. never resolves
+    ///once the election stopped
+    pub async fn lost(&self) {
+        let mut rx = self.rx.clone();
+        if rx.wait_for(|is_leader| !*is_leader).await.is_err() {
+            std::future::pending::<()>().await;
+        }
+    }
+}
+
+#[instrument(skip(connection, tx, metrics))]
+async fn campaign(
+    connection: PsqlConnection,
+    key: String,
+    tx: watch::Sender<bool>,
+    metrics: Metrics,
+) -> Res {
+    loop {
+        match connection.try_advisory_lock(&key).await {
+            Ok(Some(mut lock)) => {
+                info!("acquired leadership");
+                metrics.leader_status.record(1, &[]);
+                tx.send_replace(true);
+
+                while lock.is_alive(HEARTBEAT_TIMEOUT).await {
+                    sleep(HEARTBEAT_INTERVAL).await;
+                }
+
+                warn!("lost leadership, leader connection is gone");
+                metrics.leader_status.record(0, &[]);
+                tx.send_replace(false);
+            }
+            Ok(None) => metrics.leader_status.record(0, &[]),
+            Err(e) => error!(error = e.to_string(), "leader election failed"),
+        }
+        sleep(RETRY_INTERVAL).await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use lib_shared::metrics::testing::MetricsReader;
+    use sqlx::PgPool;
+    use tokio::time::timeout;
+
+    #[tokio::test]
+    #[ignore = "needs a postgres server in DATABASE_URL"]
+    async fn a_failed_heartbeat_loses_the_leadership() {
+        let url = std::env::var("DATABASE_URL").unwrap();
+        let pool = PgPool::connect(&url).await.unwrap();
+        let (metrics, reader) = MetricsReader::new();
+        let key = format!("leader-test-{}", rand::random::<u32>());
+        let connection = PsqlConnection::new(pool.clone(), metrics.clone());
+        let (leader, handle) = LeaderElection::new(connection, &key, metrics);
+
+        let mut rx = leader.subscribe();
+        timeout(Duration::from_secs(5), rx.wait_for(|is_leader| *is_leader))
+            .await
+            .expect("never became the leader")
+            .unwrap();
+        assert_eq!(reader.gauge("cluster.leader.status", &[]), 1);
+
+        //kills the session holding the lock, the next heartbeat fails
+        let terminated: Vec<bool> = sqlx::query_scalar(
+            "SELECT pg_terminate_backend(pid) FROM pg_locks
+             WHERE locktype = 'advisory' AND objid = (hashtext($1)::bigint & 4294967295)::oid",
+        )
+        .bind(&key)
+        .fetch_all(&pool)
+        .await
+        .unwrap();
+        assert_eq!(terminated, [true]);
+        timeout(HEARTBEAT_INTERVAL + HEARTBEAT_TIMEOUT * 2, leader.lost())
+            .await
+            .expect("the leadership was kept");
+        assert!(!leader.is_leader());
+        assert_eq!(reader.gauge("cluster.leader.status", &[]), 0);
+        handle.abort();
+    }
+}
@@ -1,2 +1,9 @@
+pub mod cache_backend;
 pub mod cache_manager;
+pub mod event_bus;
+pub mod job_queue;
+pub mod leader_election;
+pub mod outbox_relay;
+pub mod scheduler;
+pub mod single_flight;
 pub mod thread_manager;
@@ -0,0 +1,375 @@
+use crate::managers::event_bus::EventBus;
+use crate::managers::job_queue::backoff;
+use chrono::Utc;
+use eyre::eyre;
+use lib_db::OutboxDriver;
+use lib_db::outbox_driver::OutboxMessage;
+use lib_shared::metrics::Metrics;
+use lib_shared::{Res, error, warn};
+use opentelemetry::KeyValue;
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::task::JoinHandle;
+use tokio::time::{sleep, timeout};
+
+const BATCH_SIZE: i64 = 100;
+const POLL_INTERVAL: Duration = Duration::from_secs(1);
+///a message failing this many times is given up
+const MAX_ATTEMPTS: i32 = 10;
+///bounds the wait on the sink, e.g. a full event bus blocking `publish_json`
+const DISPATCH_TIMEOUT: Duration = Duration::from_secs(10);
+///claimed messages are left to this relay for that long, it stops dispatching before it runs out
+const CLAIM_LEASE: Duration = Duration::from_secs(2 * 60);
+
+///destination of the outbox messages, implement it for external brokers.
+///delivery is at-least-once, so sinks should use `idempotency_key` to drop duplicates
+pub trait OutboxSink: Send + Sync + 'static {
+    fn name(&self) -> &'static str;
+    fn send<'a>(
+        &'a self,
+        message: &'a OutboxMessage,
+    ) -> Pin<Box<dyn Future<Output = Res> + Send + 'a>>;
+}
+
+impl OutboxSink for EventBus {
+    fn name(&self) -> &'static str {
+        "event-bus"
+    }
+    fn send<'a>(
+        &'a self,
+        message: &'a OutboxMessage,
+    ) -> Pin<Box<dyn Future<Output = Res> + Send + 'a>> {
+        //subscribers read it with `event_bus::idempotency_key()`
+        let key = Some(message.idempotency_key.clone());
+        Box::pin(self.publish_json(&message.event, message.payload.clone(), key))
+    }
+}
+
+///moves committed outbox rows to the sink, multiple relays can run in parallel since rows are
+///claimed with `SKIP LOCKED`. failed messages are retried with a backoff, without holding back the
+///next ones, and given up after [`MAX_ATTEMPTS`]
+#[derive(Clone)]
+pub struct OutboxRelay {
+    driver: OutboxDriver,
+    sink: Arc<dyn OutboxSink>,
+    metrics: Metrics,
+}
+
+impl OutboxRelay {
+    pub fn new(driver: OutboxDriver, sink: Arc<dyn OutboxSink>, metrics: Metrics) -> Self {
+        Self {
+            driver,
+            sink,
+            metrics,
+        }
+    }
+
+    pub fn run(&self) -> JoinHandle<Res> {
+        let slf = self.clone();
+        tokio::spawn(async move {
+            loop {
+                match slf.relay_batch().await {
+                    Ok(dispatched) if dispatched > 0 => continue,
+                    Ok(_) => {}
+                    Err(e) => error!(error = e.to_string(), "outbox relay failed"),
+                }
+                sleep(POLL_INTERVAL).await;
+            }
+        })
+    }
+
+    pub async fn purge(&self, older_than: Duration) -> Res {
+        self.driver.purge_dispatched(older_than).await?;
+        Ok(())
+    }
+
+    ///returns the number of dispatched messages
+    async fn relay_batch(&self) -> eyre::Result<usize> {
+        let messages = self.driver.claim(BATCH_SIZE, CLAIM_LEASE).await?;
+        let deadline = Instant::now() + CLAIM_LEASE - DISPATCH_TIMEOUT;
+        let mut dispatched = Vec::with_capacity(messages.len());
+        let (mut retried, mut dead) = (0, 0);
+        let mut messages = messages.into_iter();
+        while Instant::now() < deadline
+            && let Some(message) = messages.next()
+        {
+            let result = match timeout(DISPATCH_TIMEOUT, self.sink.send(&message)).await {
+                Ok(result) => result,
+                Err(_) => Err(eyre!("dispatch timed out after {DISPATCH_TIMEOUT:?}")),
+            };
+            match result {
+                Ok(_) => dispatched.push(message.id),
+                Err(e) if message.attempts >= MAX_ATTEMPTS => {
+                    error!(
+                        id = message.id,
+                        error = e.to_string(),
+                        "outbox message exhausted its attempts"
+                    );
+                    dead += 1;
+                    if let Err(e) = self.driver.bury(message.id, &e.to_string()).await {
+                        error!(error = e.to_string(), "failed to bury outbox message");
+                    }
+                }
+                Err(e) => {
+                    warn!(
+                        id = message.id,
+                        error = e.to_string(),
+                        "failed to dispatch outbox message"
+                    );
+                    retried += 1;
+                    let next_attempt_at = Utc::now() + backoff(message.attempts);
+                    if let Err(e) = self
+                        .driver
+                        .retry(message.id, &e.to_string(), next_attempt_at)
+                        .await
+                    {
+                        error!(error = e.to_string(), "failed to schedule outbox retry");
+                    }
+                }
+            }
+        }
+        self.driver.mark_dispatched(&dispatched).await?;
+        let unsent: Vec<i64> = messages.map(|m| m.id).collect();
+        if !unsent.is_empty() {
+            self.driver.release(&unsent).await?;
+        }
+
+        let sink = KeyValue::new("sink", self.sink.name());
+        for (outcome, count) in [("ok", dispatched.len()), ("retry", retried), ("dead", dead)] {
+            self.metrics.outbox_dispatched_count.add(
+                count as u64,
+                &[sink.clone(), KeyValue::new("outcome", outcome)],
+            );
+        }
+        Ok(dispatched.len())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::managers::event_bus::{Event, idempotency_key};
+    use crate::managers::thread_manager::ThreadManager;
+    use lib_db::outbox_driver::NewOutboxMessage;
+    use lib_db::psql_connection::PsqlConnection;
+    use parking_lot::Mutex;
+    use serde::{Deserialize, Serialize};
+    use sqlx::PgPool;
+    use sqlx::postgres::PgPoolOptions;
+    use std::sync::atomic::{AtomicBool, Ordering};
+
+    #[derive(Serialize, Deserialize, Clone)]
+    struct Ping {
+        n: u32,
+    }
+
+    impl Event for Ping {
+        const NAME: &'static str = "test.ping";
+    }
+
+    #[derive(Default)]
+    struct RecordingSink {
+        fail: AtomicBool,
+        ///idempotency keys, in delivery order
+        sent: Mutex<Vec<String>>,
+    }
+
+    impl OutboxSink for RecordingSink {
+        fn name(&self) -> &'static str {
+            "test"
+        }
+        fn send<'a>(
+            &'a self,
+            message: &'a OutboxMessage,
+        ) -> Pin<Box<dyn Future<Output = Res> + Send + 'a>> {
+            Box::pin(async move {
+                if self.fail.load(Ordering::SeqCst) {
+                    return Err(eyre!("broker is down"));
+                }
+                self.sent.lock().push(message.idempotency_key.clone());
+                Ok(())
+            })
+        }
+    }
+
+    #[tokio::test]
+    async fn event_bus_subscribers_get_the_idempotency_key() {
+        let bus = EventBus::new(ThreadManager::new(), Metrics::new());
+        let seen = Arc::new(Mutex::new(vec![]));
+        let sync_seen = seen.clone();
+        bus.subscribe::<Ping, _>("sync", move |ping| {
+            sync_seen.lock().push((ping.n, idempotency_key()));
+            Ok(())
+        });
+        let (tx, mut rx) = tokio::sync::mpsc::unbounded_channel();
+        bus.subscribe_async::<Ping, _, _>(
+            "async",
+            8,
+            crate::managers::event_bus::Backpressure::Block,
+            move |ping| {
+                let tx = tx.clone();
+                async move {
+                    _ = tx.send((ping.n, idempotency_key()));
+                    Ok(())
+                }
+            },
+        )
+        .await;
+
+        let message = OutboxMessage {
+            id: 1,
+            event: Ping::NAME.to_owned(),
+            payload: serde_json::json!({ "n": 7 }),
+            idempotency_key: "ping:7".to_owned(),
+            attempts: 1,
+        };
+        bus.send(&message).await.unwrap();
+        //published directly, there is no key
+        bus.publish(Ping { n: 8 }).await;
+
+        let key = Some("ping:7".to_owned());
+        assert_eq!(*seen.lock(), vec![(7, key.clone()), (8, None)]);
+        assert_eq!(rx.recv().await, Some((7, key)));
+        assert_eq!(rx.recv().await, Some((8, None)));
+    }
+
+    ///a relay over a temporary `outbox` table. the pool has a single connection, so the table
+    ///shadows the real one for every query
+    async fn relay(sink: Arc<RecordingSink>) -> (OutboxRelay, PgPool) {
+        let url = std::env::var("DATABASE_URL").unwrap();
+        let pool = PgPoolOptions::new()
+            .max_connections(1)
+            .connect(&url)
+            .await
+            .unwrap();
+        sqlx::raw_sql("CREATE TEMP TABLE outbox (LIKE outbox INCLUDING ALL)")
+            .execute(&pool)
+            .await
+            .unwrap();
+        let driver = OutboxDriver::new(PsqlConnection::new(pool.clone(), Metrics::new()));
+        (OutboxRelay::new(driver, sink, Metrics::new()), pool)
+    }
+
+    ///`false` if the key is already there
+    async fn append(pool: &PgPool, key: &str) -> bool {
+        let message = NewOutboxMessage::new(Ping::NAME, &Ping { n: 1 }, key.to_owned()).unwrap();
+        let mut conn = pool.acquire().await.unwrap();
+        OutboxDriver::append(&mut conn, &message).await.unwrap()
+    }
+
+    #[derive(sqlx::FromRow, Debug)]
+    struct Row {
+        attempts: i32,
+        last_error: Option<String>,
+        dispatched: bool,
+        failed: bool,
+        claimed: bool,
+        due: bool,
+    }
+
+    async fn row(pool: &PgPool, key: &str) -> Row {
+        sqlx::query_as(
+            "SELECT attempts, last_error, dispatched_at IS NOT NULL AS dispatched,
+                    failed_at IS NOT NULL AS failed, claimed_until IS NOT NULL AS claimed,
+                    next_attempt_at <= now() AS due
+             FROM outbox WHERE idempotency_key = $1",
+        )
+        .bind(key)
+        .fetch_one(pool)
+        .await
+        .unwrap()
+    }
+
+    #[tokio::test]
+    #[ignore = "needs a postgres server in DATABASE_URL"]
+    async fn messages_are_dispatched_in_order_once() {
+        let sink = Arc::new(RecordingSink::default());
+        let (relay, pool) = relay(sink.clone()).await;
+        assert!(append(&pool, "a").await);
+        assert!(append(&pool, "b").await);
+        //the key is unique, appending it again is a no-op
+        assert!(!append(&pool, "a").await);
+
+        assert_eq!(relay.relay_batch().await.unwrap(), 2);
+        assert_eq!(*sink.sent.lock(), ["a", "b"]);
+        let a = row(&pool, "a").await;
+        assert!(a.dispatched && !a.claimed && a.attempts == 1);
+
+        assert_eq!(relay.relay_batch().await.unwrap(), 0);
+        assert_eq!(sink.sent.lock().len(), 2);
+    }
+
+    #[tokio::test]
+    #[ignore = "needs a postgres server in DATABASE_URL"]
+    async fn failed_messages_are_retried_after_a_backoff() {
+        let sink = Arc::new(RecordingSink::default());
+        let (relay, pool) = relay(sink.clone()).await;
+        assert!(append(&pool, "a").await);
+        sink.fail.store(true, Ordering::SeqCst);
+
+        assert_eq!(relay.relay_batch().await.unwrap(), 0);
+        let a = row(&pool, "a").await;
+        assert_eq!(a.attempts, 1);
+        assert_eq!(a.last_error.as_deref(), Some("broker is down"));
+        assert!(!a.dispatched && !a.failed && !a.claimed && !a.due);
+        //not due yet
+        assert_eq!(relay.relay_batch().await.unwrap(), 0);
+        assert_eq!(row(&pool, "a").await.attempts, 1);
+
+        sink.fail.store(false, Ordering::SeqCst);
+        sqlx::query("UPDATE outbox SET next_attempt_at = now()")
+            .execute(&pool)
+            .await
+            .unwrap();
+        assert_eq!(relay.relay_batch().await.unwrap(), 1);
+        let a = row(&pool, "a").await;
+        assert!(a.dispatched && a.attempts == 2);
+        assert_eq!(*sink.sent.lock(), ["a"]);
+    }
+
+    #[tokio::test]
+    #[ignore = "needs a postgres server in DATABASE_URL"]
+    async fn exhausted_messages_are_buried() {
+        let sink = Arc::new(RecordingSink::default());
+        let (relay, pool) = relay(sink.clone()).await;
+        assert!(append(&pool, "a").await);
+        sink.fail.store(true, Ordering::SeqCst);
+        sqlx::query("UPDATE outbox SET attempts = $1")
+            .bind(MAX_ATTEMPTS - 1)
+            .execute(&pool)
+            .await
+            .unwrap();
+
+        assert_eq!(relay.relay_batch().await.unwrap(), 0);
+        let a = row(&pool, "a").await;
+        assert!(a.failed && !a.dispatched && a.attempts == MAX_ATTEMPTS);
+
+        sink.fail.store(false, Ordering::SeqCst);
+        sqlx::query("UPDATE outbox SET next_attempt_at = now()")
+            .execute(&pool)
+            .await
+            .unwrap();
+        assert_eq!(relay.relay_batch().await.unwrap(), 0);
+        assert!(sink.sent.lock().is_empty());
+    }
+
+    #[tokio::test]
+    #[ignore = "needs a postgres server in DATABASE_URL"]
+    async fn expired_claims_are_delivered_again() {
+        let sink = Arc::new(RecordingSink::default());
+        let (relay, pool) = relay(sink.clone()).await;
+        assert!(append(&pool, "a").await);
+        //a relay that delivered the message, then died before marking it
+        let claimed = relay.driver.claim(10, Duration::ZERO).await.unwrap();
+        assert_eq!(claimed.len(), 1);
+        sink.send(&claimed[0]).await.unwrap();
+
+        assert_eq!(relay.relay_batch().await.unwrap(), 1);
+        //same key both times, so the consumer can drop the second one
+        assert_eq!(*sink.sent.lock(), ["a", "a"]);
+        let a = row(&pool, "a").await;
+        assert!(a.dispatched && a.attempts == 2);
+    }
+}
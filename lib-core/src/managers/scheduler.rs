@@ -0,0 +1,380 @@
+use crate::managers::leader_election::LeaderElection;
+use crate::managers::thread_manager::ThreadManager;
+use chrono::Utc;
+use eyre::eyre;
+use lib_shared::metrics::Metrics;
+use lib_shared::{Res, error, instrument, warn};
+use opentelemetry::KeyValue;
+use std::future::Future;
+use std::pin::Pin;
+use std::str::FromStr;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::{Duration, Instant};
+use tokio::time::sleep;
+use tokio_util::sync::CancellationToken;
+use tokio_util::task::TaskTracker;
+
+pub type JobFuture = Pin<Box<dyn Future<Output = Res> + Send>>;
+type JobFn = Arc<dyn Fn() -> JobFuture + Send + Sync>;
+
+#[derive(Clone)]
+pub enum Schedule {
+    Cron(Box<cron::Schedule>),
+    Interval(Duration),
+}
+
+impl Schedule {
+    ///cron expression with seconds, e.x: `0 */5 * * * *`
+    pub fn cron(expr: &str) -> eyre::Result<Self> {
+        Ok(Self::Cron(Box::new(cron::Schedule::from_str(expr)?)))
+    }
+    pub fn every(interval: Duration) -> Self {
+        Self::Interval(interval)
+    }
+    fn next_delay(&self) -> Option<Duration> {
+        match self {
+            Self::Interval(interval) => Some(*interval),
+            Self::Cron(schedule) => schedule
+                .upcoming(Utc)
+                .next()
+                .map(|at| (at - Utc::now()).to_std().unwrap_or_default()),
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct Job {
+    name: String,
+    schedule: Schedule,
+    jitter: Duration,
+    timeout: Option<Duration>,
+    allow_overlap: bool,
+    singleton: bool,
+    task: JobFn,
+}
+
+impl Job {
+    pub fn new<F, Fut>(name: &str, schedule: Schedule, task: F) -> Self
+    where
+        F: Fn() -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = Res> + Send + 'static,
+    {
+        Self {
+            name: name.to_owned(),
+            schedule,
+            jitter: Duration::ZERO,
+            timeout: None,
+            allow_overlap: false,
+            singleton: false,
+            task: Arc::new(move || Box::pin(task())),
+        }
+    }
+    ///random delay in `[0, jitter]` added to every run, spreads the load across replicas
+    pub fn jitter(mut self, jitter: Duration) -> Self {
+        self.jitter = jitter;
+        self
+    }
+    pub fn timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = Some(timeout);
+        self
+    }
+    ///by default a tick is skipped while the previous run is still active
+    pub fn allow_overlap(mut self) -> Self {
+        self.allow_overlap = true;
+        self
+    }
+    ///only runs on the replica that currently holds the leadership
+    pub fn singleton(mut self) -> Self {
+        self.singleton = true;
+        self
+    }
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn jitter_offset(&self) -> Duration {
+        if self.jitter.is_zero() {
+            return Duration::ZERO;
+        }
+        Duration::from_millis(rand::random_range(0..=self.jitter.as_millis() as u64))
+    }
+}
+
+///runs [`Job`]s periodically, each job loop is tracked by the [`ThreadManager`] and each run by
+///the scheduler itself, see [`Scheduler::shutdown`]
+#[derive(Clone)]
+pub struct Scheduler {
+    thread_manager: ThreadManager,
+    metrics: Metrics,
+    leader: LeaderElection,
+    runs: TaskTracker,
+    stop: CancellationToken,
+}
+
+impl Scheduler {
+    pub fn new(thread_manager: ThreadManager, metrics: Metrics, leader: LeaderElection) -> Self {
+        Self {
+            thread_manager,
+            metrics,
+            leader,
+            runs: TaskTracker::new(),
+            stop: CancellationToken::new(),
+        }
+    }
+
+    #[instrument(skip_all, fields(job = job.name))]
+    pub async fn register(&self, job: Job) {
+        let name = format!("job:{}", job.name);
+        let handle = tokio::spawn(run_loop(job, self.clone()));
+        self.thread_manager.add(&name, handle).await;
+    }
+
+    ///stops starting runs and waits up to `grace` for the active ones, which are aborted with the
+    ///runtime past it
+    #[instrument(skip(self))]
+    pub async fn shutdown(&self, grace: Duration) {
+        self.stop.cancel();
+        self.runs.close();
+        if tokio::time::timeout(grace, self.runs.wait()).await.is_err() {
+            warn!(
+                active = self.runs.len(),
+                "job runs still active after the grace period"
+            );
+        }
+    }
+}
+
+async fn run_loop(job: Job, scheduler: Scheduler) -> Res {
+    let Scheduler {
+        metrics,
+        leader,
+        runs,
+        stop,
+        ..
+    } = scheduler;
+    let running = Arc::new(AtomicBool::new(false));
+    let labels = [KeyValue::new("job", job.name.clone())];
+    loop {
+        let Some(delay) = job.schedule.next_delay() else {
+            warn!(job = job.name, "schedule has no upcoming runs");
+            return Ok(());
+        };
+        tokio::select! {
+            _ = sleep(delay + job.jitter_offset()) => {}
+            _ = stop.cancelled() => return Ok(()),
+        }
+
+        if job.singleton && !leader.is_leader() {
+            continue;
+        }
+
+        if running.swap(true, Ordering::AcqRel) && !job.allow_overlap {
+            warn!(job = job.name, "previous run is still active, skipping");
+            metrics.job_skip_count.add(1, &labels);
+            continue;
+        }
+        runs.spawn(execute(
+            job.clone(),
+            metrics.clone(),
+            job.singleton.then(|| leader.clone()),
+            RunningGuard(running.clone()),
+        ));
+    }
+}
+
+///singleton runs get the `leader` and are cancelled when it loses the leadership
+async fn execute(job: Job, metrics: Metrics, leader: Option<LeaderElection>, _guard: RunningGuard) {
+    let start = Instant::now();
+    let run = async {
+        let run = (job.task)();
+        match job.timeout {
+            Some(timeout) => match tokio::time::timeout(timeout, run).await {
+                Ok(result) => (outcome_of(&result), result),
+                Err(_) => ("timeout", Err(eyre!("timed out after {timeout:?}"))),
+            },
+            None => {
+                let result = run.await;
+                (outcome_of(&result), result)
+            }
+        }
+    };
+    let lost = async {
+        match &leader {
+            Some(leader) => leader.lost().await,
+            None => std::future::pending().await,
+        }
+    };
+    let (outcome, result) = tokio::select! {
+        outcome = run => outcome,
+        _ = lost => ("leadership_lost", Err(eyre!("cancelled, the leadership was lost"))),
+    };
+    if let Err(e) = result {
+        error!(job = job.name, error = e.to_string(), "job failed");
+    }
+    let labels = [
+        KeyValue::new("job", job.name),
+        KeyValue::new("outcome", outcome),
+    ];
+    metrics.job_run_count.add(1, &labels);
+    metrics
+        .job_duration
+        .record(start.elapsed().as_secs_f64(), &labels);
+}
+
+fn outcome_of(result: &Res) -> &'static str {
+    if result.is_ok() { "ok" } else { "error" }
+}
+
+///clears the running flag even if the job panics
+struct RunningGuard(Arc<AtomicBool>);
+
+impl Drop for RunningGuard {
+    fn drop(&mut self) {
+        self.0.store(false, Ordering::Release);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::AtomicUsize;
+
+    const TICK: Duration = Duration::from_millis(10);
+
+    fn scheduler(is_leader: bool) -> Scheduler {
+        Scheduler::new(
+            ThreadManager::new(),
+            Metrics::new(),
+            LeaderElection::fixed(is_leader),
+        )
+    }
+
+    #[derive(Default)]
+    struct Runs {
+        started: AtomicUsize,
+        finished: AtomicUsize,
+        active: AtomicUsize,
+        max_active: AtomicUsize,
+    }
+
+    ///every run takes `duration`
+    fn job(runs: &Arc<Runs>, duration: Duration) -> Job {
+        let runs = runs.clone();
+        Job::new("test", Schedule::every(TICK), move || {
+            let runs = runs.clone();
+            async move {
+                runs.started.fetch_add(1, Ordering::SeqCst);
+                let active = runs.active.fetch_add(1, Ordering::SeqCst) + 1;
+                runs.max_active.fetch_max(active, Ordering::SeqCst);
+                let _active = Active(runs.clone());
+                sleep(duration).await;
+                runs.finished.fetch_add(1, Ordering::SeqCst);
+                Ok(())
+            }
+        })
+    }
+
+    ///also leaves when a timeout drops the run
+    struct Active(Arc<Runs>);
+
+    impl Drop for Active {
+        fn drop(&mut self) {
+            self.0.active.fetch_sub(1, Ordering::SeqCst);
+        }
+    }
+
+    #[tokio::test]
+    async fn skips_ticks_while_the_previous_run_is_active() {
+        let (scheduler, runs) = (scheduler(false), Arc::new(Runs::default()));
+        scheduler.register(job(&runs, TICK * 5)).await;
+        sleep(TICK * 20).await;
+        scheduler.shutdown(Duration::from_secs(1)).await;
+        assert_eq!(runs.max_active.load(Ordering::SeqCst), 1);
+        assert!(runs.started.load(Ordering::SeqCst) >= 2);
+    }
+
+    #[tokio::test]
+    async fn overlaps_when_allowed() {
+        let (scheduler, runs) = (scheduler(false), Arc::new(Runs::default()));
+        scheduler
+            .register(job(&runs, TICK * 5).allow_overlap())
+            .await;
+        sleep(TICK * 20).await;
+        scheduler.shutdown(Duration::from_secs(1)).await;
+        assert!(runs.max_active.load(Ordering::SeqCst) > 1);
+    }
+
+    #[tokio::test]
+    async fn timed_out_runs_are_cancelled() {
+        let (scheduler, runs) = (scheduler(false), Arc::new(Runs::default()));
+        scheduler
+            .register(job(&runs, Duration::from_secs(60)).timeout(TICK * 2))
+            .await;
+        sleep(TICK * 20).await;
+        scheduler.shutdown(Duration::from_secs(1)).await;
+        //each run got dropped at its timeout, which let the next ticks run
+        assert!(runs.started.load(Ordering::SeqCst) >= 2);
+        assert_eq!(runs.finished.load(Ordering::SeqCst), 0);
+        assert_eq!(runs.active.load(Ordering::SeqCst), 0);
+    }
+
+    #[tokio::test]
+    async fn singletons_only_run_on_the_leader() {
+        let runs = Arc::new(Runs::default());
+        let follower = scheduler(false);
+        follower
+            .register(job(&runs, Duration::ZERO).singleton())
+            .await;
+        sleep(TICK * 5).await;
+        follower.shutdown(Duration::from_secs(1)).await;
+        assert_eq!(runs.started.load(Ordering::SeqCst), 0);
+
+        let leader = scheduler(true);
+        leader
+            .register(job(&runs, Duration::ZERO).singleton())
+            .await;
+        sleep(TICK * 5).await;
+        leader.shutdown(Duration::from_secs(1)).await;
+        assert!(runs.started.load(Ordering::SeqCst) > 0);
+    }
+
+    #[tokio::test]
+    async fn singleton_runs_stop_with_the_leadership() {
+        let runs = Arc::new(Runs::default());
+        let (leader, status) = LeaderElection::manual(true);
+        let scheduler = Scheduler::new(ThreadManager::new(), Metrics::new(), leader);
+        scheduler
+            .register(job(&runs, Duration::from_secs(60)).singleton())
+            .await;
+        while runs.started.load(Ordering::SeqCst) == 0 {
+            sleep(TICK).await;
+        }
+
+        status.send_replace(false);
+        sleep(TICK * 5).await;
+        //the active run was cancelled and no other one started
+        assert_eq!(runs.active.load(Ordering::SeqCst), 0);
+        assert_eq!(runs.finished.load(Ordering::SeqCst), 0);
+        assert_eq!(runs.started.load(Ordering::SeqCst), 1);
+
+        status.send_replace(true);
+        sleep(TICK * 5).await;
+        scheduler.shutdown(Duration::from_secs(1)).await;
+        assert!(runs.started.load(Ordering::SeqCst) > 1);
+    }
+
+    #[tokio::test]
+    async fn shutdown_waits_for_active_runs() {
+        let (scheduler, runs) = (scheduler(false), Arc::new(Runs::default()));
+        scheduler.register(job(&runs, TICK * 10)).await;
+        while runs.started.load(Ordering::SeqCst) == 0 {
+            sleep(TICK).await;
+        }
+        scheduler.shutdown(Duration::from_secs(5)).await;
+        assert_eq!(runs.finished.load(Ordering::SeqCst), 1);
+        //no run starts afterwards
+        sleep(TICK * 5).await;
+        assert_eq!(runs.started.load(Ordering::SeqCst), 1);
+    }
+}
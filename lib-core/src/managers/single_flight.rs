@@ -0,0 +1,260 @@
+use hashbrown::HashMap;
+use lib_shared::metrics::Metrics;
+use opentelemetry::KeyValue;
+use parking_lot::Mutex;
+use std::error::Error;
+use std::fmt;
+use std::future::Future;
+use std::hash::Hash;
+use std::sync::Arc;
+use tokio::sync::watch;
+
+type Outcome<V> = Option<Result<V, SharedError>>;
+
+///deduplicates concurrent computations of the same key: the first caller runs it and the ones
+///arriving meanwhile wait for its result, errors included. nothing is kept once it completes,
+///pair it with a cache to keep the values
+pub struct SingleFlight<K, V> {
+    name: &'static str,
+    in_flight: Arc<Mutex<HashMap<K, watch::Receiver<Outcome<V>>>>>,
+    metrics: Metrics,
+}
+
+impl<K, V> Clone for SingleFlight<K, V> {
+    fn clone(&self) -> Self {
+        Self {
+            name: self.name,
+            in_flight: self.in_flight.clone(),
+            metrics: self.metrics.clone(),
+        }
+    }
+}
+
+impl<K, V> SingleFlight<K, V>
+where
+    K: Hash + Eq + Clone,
+    V: Clone,
+{
+    ///`name` labels the `single_flight.coalesced` metric
+    pub fn new(name: &'static str, metrics: &Metrics) -> Self {
+        Self {
+            name,
+            in_flight: Default::default(),
+            metrics: metrics.clone(),
+        }
+    }
+
+    ///runs `f` unless the same key is already being computed. every caller gets the same
+    ///[`SharedError`] when it fails. if the caller running `f` is cancelled, one of the waiting
+    ///callers runs it again
+    pub async fn run<F, Fut>(&self, key: K, f: F) -> eyre::Result<V>
+    where
+        F: FnOnce() -> Fut,
+        Fut: Future<Output = eyre::Result<V>>,
+    {
+        let flight = loop {
+            let mut rx = {
+                let mut in_flight = self.in_flight.lock();
+                match in_flight.get(&key) {
+                    Some(rx) => rx.clone(),
+                    None => {
+                        let (tx, rx) = watch::channel(None);
+                        in_flight.insert(key.clone(), rx);
+                        break Flight {
+                            key: &key,
+                            in_flight: &self.in_flight,
+                            tx: Some(tx),
+                        };
+                    }
+                }
+            };
+            self.metrics
+                .single_flight_coalesced
+                .add(1, &[KeyValue::new("flight", self.name)]);
+            if let Ok(outcome) = rx.wait_for(Option::is_some).await
+                && let Some(result) = outcome.clone()
+            {
+                return result.map_err(eyre::Report::new);
+            }
+            //the sender got dropped without a result
+        };
+        let result = f().await.map_err(SharedError::new);
+        flight.complete(result.clone());
+        result.map_err(eyre::Report::new)
+    }
+
+    ///keys being computed right now
+    pub fn in_flight(&self) -> usize {
+        self.in_flight.lock().len()
+    }
+}
+
+///removes its key once completed or dropped, waiters of a dropped flight retry
+struct Flight<'a, K: Hash + Eq, V> {
+    key: &'a K,
+    in_flight: &'a Mutex<HashMap<K, watch::Receiver<Outcome<V>>>>,
+    tx: Option<watch::Sender<Outcome<V>>>,
+}
+
+impl<K: Hash + Eq, V> Flight<'_, K, V> {
+    ///the key is removed first, so late callers start a new flight instead of reading this one
+    fn complete(mut self, result: Result<V, SharedError>) {
+        self.in_flight.lock().remove(self.key);
+        if let Some(tx) = self.tx.take() {
+            tx.send_replace(Some(result));
+        }
+    }
+}
+
+impl<K: Hash + Eq, V> Drop for Flight<'_, K, V> {
+    fn drop(&mut self) {
+        if self.tx.is_some() {
+            self.in_flight.lock().remove(self.key);
+        }
+    }
+}
+
+///the error of a computation shared by several callers, the original one is its `source`
+#[derive(Clone)]
+pub struct SharedError(Arc<eyre::Report>);
+
+impl SharedError {
+    fn new(report: eyre::Report) -> Self {
+        Self(Arc::new(report))
+    }
+
+    pub fn report(&self) -> &eyre::Report {
+        &self.0
+    }
+}
+
+impl fmt::Display for SharedError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("coalesced computation failed")
+    }
+}
+
+impl fmt::Debug for SharedError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "coalesced computation failed: {:?}", self.0)
+    }
+}
+
+impl Error for SharedError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        let original: &(dyn Error + Send + Sync + 'static) = &**self.0;
+        Some(original)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use eyre::eyre;
+    use futures_util::future::join_all;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::time::Duration;
+    use tokio::time::{sleep, timeout};
+
+    fn flight() -> SingleFlight<&'static str, u64> {
+        SingleFlight::new("test", &Metrics::new())
+    }
+
+    #[tokio::test]
+    async fn concurrent_misses_run_once() {
+        let flight = flight();
+        let calls = AtomicUsize::new(0);
+        let load = || async {
+            calls.fetch_add(1, Ordering::SeqCst);
+            sleep(Duration::from_millis(50)).await;
+            Ok(42)
+        };
+        let results = join_all((0..10).map(|_| flight.run("key", load))).await;
+        assert_eq!(calls.load(Ordering::SeqCst), 1);
+        assert!(results.into_iter().all(|r| r.unwrap() == 42));
+        assert_eq!(flight.in_flight(), 0);
+        //nothing is kept once completed
+        flight.run("key", load).await.unwrap();
+        assert_eq!(calls.load(Ordering::SeqCst), 2);
+    }
+
+    #[tokio::test]
+    async fn distinct_keys_run_separately() {
+        let flight = flight();
+        let calls = AtomicUsize::new(0);
+        let load = |value| {
+            let calls = &calls;
+            move || async move {
+                calls.fetch_add(1, Ordering::SeqCst);
+                sleep(Duration::from_millis(20)).await;
+                Ok(value)
+            }
+        };
+        let (a, b) = tokio::join!(flight.run("a", load(1)), flight.run("b", load(2)));
+        assert_eq!((a.unwrap(), b.unwrap()), (1, 2));
+        assert_eq!(calls.load(Ordering::SeqCst), 2);
+    }
+
+    #[tokio::test]
+    async fn errors_are_shared_by_every_waiter() {
+        let flight = flight();
+        let calls = AtomicUsize::new(0);
+        let load = || async {
+            calls.fetch_add(1, Ordering::SeqCst);
+            sleep(Duration::from_millis(50)).await;
+            Err(eyre!("database is down"))
+        };
+        let results = join_all((0..5).map(|_| flight.run("key", load))).await;
+        assert_eq!(calls.load(Ordering::SeqCst), 1);
+        for result in results {
+            let e = result.unwrap_err();
+            let shared = e.downcast_ref::<SharedError>().unwrap();
+            assert_eq!(shared.report().to_string(), "database is down");
+            assert_eq!(e.source().unwrap().to_string(), "database is down");
+        }
+    }
+
+    #[tokio::test]
+    async fn a_cancelled_leader_hands_over_to_a_waiter() {
+        let flight = flight();
+        let calls = Arc::new(AtomicUsize::new(0));
+        let load = {
+            let calls = calls.clone();
+            move || {
+                let call = calls.fetch_add(1, Ordering::SeqCst);
+                async move {
+                    //the leader never completes
+                    if call == 0 {
+                        std::future::pending::<()>().await;
+                    }
+                    sleep(Duration::from_millis(50)).await;
+                    Ok(7)
+                }
+            }
+        };
+        let leader = tokio::spawn({
+            let (flight, load) = (flight.clone(), load.clone());
+            async move { flight.run("key", load).await }
+        });
+        while calls.load(Ordering::SeqCst) == 0 {
+            tokio::task::yield_now().await;
+        }
+        let waiters = tokio::spawn({
+            let flight = flight.clone();
+            async move {
+                let waiters = join_all((0..3).map(|_| flight.run("key", load.clone())));
+                timeout(Duration::from_secs(5), waiters).await
+            }
+        });
+        sleep(Duration::from_millis(20)).await;
+        leader.abort();
+        let results = waiters
+            .await
+            .unwrap()
+            .expect("waiters hung after the leader was cancelled");
+        assert!(results.into_iter().all(|r| r.unwrap() == 7));
+        //one waiter took over
+        assert_eq!(calls.load(Ordering::SeqCst), 2);
+        assert_eq!(flight.in_flight(), 0);
+    }
+}
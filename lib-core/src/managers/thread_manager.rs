@@ -31,6 +31,21 @@ impl ThreadManager {
         self.inner.write().handles.insert(name.to_owned(), handle);
         info!("started tracking task");
     }
+
+    ///stops tracking the task and waits for it, for tasks that were asked to finish on their own
+    #[instrument(skip(self))]
+    pub async fn join(&self, name: &str) {
+        let handle = self.inner.write().handles.remove(name);
+        let Some(handle) = handle else {
+            warn!(name, "attempting to join an unknown task");
+            return;
+        };
+        match handle.await {
+            Ok(Ok(())) => info!("task finished"),
+            Ok(Err(e)) => warn!(error = e.to_string(), "task failed"),
+            Err(e) => warn!(error = e.to_string(), "task panicked or was aborted"),
+        }
+    }
 }
 
 impl Drop for InnerMut {
@@ -0,0 +1,104 @@
+use lib_db::AuditLogDriver;
+use lib_db::audit_log_driver::{AuditEntry, AuditFilter, ChainStatus, NewAuditEntry};
+use lib_shared::{error, instrument};
+use std::net::IpAddr;
+
+///names recorded in `audit_log.action`
+pub mod actions {
+    pub const LOGIN: &str = "auth.login";
+    pub const TOKEN_ISSUED: &str = "auth.token_issued";
+    pub const ADMIN_ACCESS: &str = "admin.access";
+    pub const AUDIT_QUERY: &str = "admin.audit_query";
+    pub const AUDIT_VERIFY: &str = "admin.audit_verify";
+}
+
+#[derive(Clone, Copy, Debug)]
+pub enum Outcome {
+    Success,
+    Failure,
+    ///the actor wasn't allowed to do it
+    Denied,
+}
+
+impl Outcome {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Outcome::Success => "success",
+            Outcome::Failure => "failure",
+            Outcome::Denied => "denied",
+        }
+    }
+}
+
+///one security relevant action, the actor is usually `TokenClaims.sub`
+pub struct AuditRecord {
+    entry: NewAuditEntry,
+}
+
+impl AuditRecord {
+    pub fn new(action: &str, outcome: Outcome) -> Self {
+        Self {
+            entry: NewAuditEntry {
+                actor: None,
+                ip: None,
+                action: action.to_owned(),
+                target: None,
+                outcome: outcome.as_str().to_owned(),
+                details: serde_json::Value::Null,
+            },
+        }
+    }
+
+    pub fn actor(mut self, actor: impl Into<String>) -> Self {
+        self.entry.actor = Some(actor.into());
+        self
+    }
+
+    pub fn ip(mut self, ip: IpAddr) -> Self {
+        self.entry.ip = Some(ip.to_string());
+        self
+    }
+
+    ///what the action was performed on, e.g. the identity a token was issued for
+    pub fn target(mut self, target: impl Into<String>) -> Self {
+        self.entry.target = Some(target.into());
+        self
+    }
+
+    ///never put secrets in here, the log is readable by every admin
+    pub fn details(mut self, details: serde_json::Value) -> Self {
+        self.entry.details = details;
+        self
+    }
+}
+
+///append-only, hash chained log of security relevant actions
+#[derive(Clone)]
+pub struct AuditService {
+    driver: AuditLogDriver,
+}
+
+impl AuditService {
+    pub fn new(driver: AuditLogDriver) -> Self {
+        Self { driver }
+    }
+
+    ///fails if the entry couldn't be stored, callers should fail the action too so nothing
+    ///happens unaudited
+    #[instrument(skip_all, fields(action = record.entry.action))]
+    pub async fn record(&self, record: AuditRecord) -> eyre::Result<()> {
+        self.driver
+            .append(&record.entry)
+            .await
+            .inspect_err(|e| error!("failed to write audit entry: {e:?}"))?;
+        Ok(())
+    }
+
+    pub async fn query(&self, filter: &AuditFilter) -> eyre::Result<Vec<AuditEntry>> {
+        self.driver.query(filter).await
+    }
+
+    pub async fn verify(&self) -> eyre::Result<ChainStatus> {
+        self.driver.verify().await
+    }
+}
@@ -0,0 +1,18 @@
+use crate::managers::event_bus::Event;
+use serde::{Deserialize, Serialize};
+
+pub use lib_db::user_auth_driver::ProfileUpdated;
+
+#[derive(Serialize, Deserialize, Clone)]
+pub struct UserLoggedIn {
+    pub sub: String,
+}
+
+impl Event for UserLoggedIn {
+    const NAME: &'static str = "auth.user_logged_in";
+}
+
+///committed to the outbox by `update_display_name`
+impl Event for ProfileUpdated {
+    const NAME: &'static str = lib_db::user_auth_driver::PROFILE_UPDATED;
+}
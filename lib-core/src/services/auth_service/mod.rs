@@ -1,18 +1,42 @@
+use crate::managers::event_bus::EventBus;
+use crate::services::auth_service::events::{ProfileUpdated, UserLoggedIn};
 use crate::services::auth_service::token_claims::TokenClaims;
 use jsonwebtoken::{DecodingKey, EncodingKey, Header, Validation, decode, encode};
-use std::ops::Deref;
-use std::sync::LazyLock;
+use lib_shared::env_service::AuthConfig;
+use lib_shared::metrics::Metrics;
+use std::sync::OnceLock;
+use std::time::Duration;
 
+pub mod events;
 pub mod token_claims;
 
-//store it somewhere else
-const SECRET: &str = "RdtcDAYypWNCrbvsDHZa1PaiZ727GAKr8WfVvDGtQj7nPAh9Bi1YjtTQL4IEMQ0E2r9uIGcsePMJM6hp1z8VNZ7k0k2qQHjKfxZQ";
-static D: LazyLock<DecodingKey> = LazyLock::new(|| DecodingKey::from_secret(SECRET.as_bytes()));
-static E: LazyLock<EncodingKey> = LazyLock::new(|| EncodingKey::from_secret(SECRET.as_bytes()));
-static V: LazyLock<Validation> = LazyLock::new(Validation::default);
+static KEYS: OnceLock<Keys> = OnceLock::new();
+
+struct Keys {
+    decoding: DecodingKey,
+    encoding: EncodingKey,
+    validation: Validation,
+    token_ttl: Duration,
+}
+
+///sets the secret and the token lifetime, once at startup before any token is handled
+pub fn configure(config: &AuthConfig) {
+    let keys = Keys {
+        decoding: DecodingKey::from_secret(config.jwt_secret.as_bytes()),
+        encoding: EncodingKey::from_secret(config.jwt_secret.as_bytes()),
+        validation: Validation::default(),
+        token_ttl: config.token_ttl,
+    };
+    _ = KEYS.set(keys);
+}
+
+fn keys() -> &'static Keys {
+    KEYS.get().expect("auth_service::configure wasn't called")
+}
 
 pub fn extract_claims(token: String) -> Option<TokenClaims> {
-    let claims = decode::<TokenClaims>(&token, &D, V.deref()).ok()?;
+    let keys = keys();
+    let claims = decode::<TokenClaims>(&token, &keys.decoding, &keys.validation).ok()?;
 
     if claims.claims.expired() {
         return None;
@@ -20,13 +44,37 @@ pub fn extract_claims(token: String) -> Option<TokenClaims> {
     Some(claims.claims)
 }
 
-pub fn create_jwt_token(sub: String) -> Option<String> {
+pub const ADMIN_ROLE: &str = "admin";
+
+pub fn create_jwt_token(
+    sub: String,
+    roles: Vec<String>,
+    tenant_id: Option<String>,
+) -> Option<String> {
+    let keys = keys();
     let now = chrono::Utc::now();
-    let exp = (now + chrono::Duration::hours(10)).timestamp() as usize;
+    let exp = (now + keys.token_ttl).timestamp() as usize;
     let claims = TokenClaims {
         sub,
         exp,
         iat: now.timestamp() as usize,
+        roles,
+        tenant_id,
     };
-    Some(encode(&Header::default(), &claims, &E).unwrap_or_default())
+    Some(encode(&Header::default(), &claims, &keys.encoding).unwrap_or_default())
+}
+
+pub fn register_handlers(bus: &EventBus, metrics: &Metrics) {
+    let logins = metrics.login_count.clone();
+    bus.subscribe::<UserLoggedIn, _>("login-counter", move |_| {
+        logins.add(1, &[]);
+        Ok(())
+    });
+    //a redelivered update is counted twice, fine for a metric. side effects dedupe on
+    //`event_bus::idempotency_key()`
+    let updates = metrics.profile_update_count.clone();
+    bus.subscribe::<ProfileUpdated, _>("profile-update-counter", move |_| {
+        updates.add(1, &[]);
+        Ok(())
+    });
 }
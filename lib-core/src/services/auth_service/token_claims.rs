@@ -6,6 +6,12 @@ pub struct TokenClaims {
     pub sub: String,
     pub exp: usize,
     pub iat: usize,
+    ///missing in tokens issued before roles existed
+    #[serde(default)]
+    pub roles: Vec<String>,
+    ///tenant the token was issued for, it can't be used for another one
+    #[serde(default)]
+    pub tenant_id: Option<String>,
 }
 
 impl TokenClaims {
@@ -13,4 +19,8 @@ impl TokenClaims {
         let current_time = Utc::now().timestamp();
         self.exp < current_time as _
     }
+
+    pub fn has_role(&self, role: &str) -> bool {
+        self.roles.iter().any(|r| r == role)
+    }
 }
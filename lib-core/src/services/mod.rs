@@ -1 +1,2 @@
+pub mod audit_service;
 pub mod auth_service;
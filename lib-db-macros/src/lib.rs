@@ -0,0 +1,66 @@
+use proc_macro::TokenStream;
+use quote::quote;
+use syn::visit_mut::VisitMut;
+use syn::{ExprMethodCall, Ident, ImplItem, ItemImpl, Type, parse_macro_input, parse_quote};
+
+///labels every `.db()`, `.orm()`, `.db_read()`, `.orm_read()` and `.conn()` call inside the methods of a driver with the driver and
+///method name, so the metric collector doesn't have to guess the caller at runtime.
+///```ignore
+///#[db_driver]
+///impl UserAuthDriver {
+///    pub async fn login(&self) -> eyre::Result<bool> {
+///        //becomes self.connection.db_as(DbCaller::new("UserAuthDriver", "login"))
+///        query("").execute(*self.connection.db()).await?;
+///    }
+///}
+///```
+#[proc_macro_attribute]
+pub fn db_driver(_attr: TokenStream, item: TokenStream) -> TokenStream {
+    let mut item = parse_macro_input!(item as ItemImpl);
+    let driver = match &*item.self_ty {
+        Type::Path(ty) => ty.path.segments.last().map(|s| s.ident.to_string()),
+        _ => None,
+    };
+    let Some(driver) = driver else {
+        return syn::Error::new_spanned(&item.self_ty, "expected a driver struct")
+            .to_compile_error()
+            .into();
+    };
+
+    for impl_item in &mut item.items {
+        if let ImplItem::Fn(f) = impl_item {
+            let mut labeler = Labeler {
+                driver: &driver,
+                method: f.sig.ident.to_string(),
+            };
+            labeler.visit_block_mut(&mut f.block);
+        }
+    }
+    quote!(#item).into()
+}
+
+struct Labeler<'a> {
+    driver: &'a str,
+    method: String,
+}
+
+impl VisitMut for Labeler<'_> {
+    fn visit_expr_method_call_mut(&mut self, call: &mut ExprMethodCall) {
+        syn::visit_mut::visit_expr_method_call_mut(self, call);
+        if !call.args.is_empty() || call.turbofish.is_some() {
+            return;
+        }
+        let labeled = match call.method.to_string().as_str() {
+            "db" => "db_as",
+            "orm" => "orm_as",
+            "db_read" => "db_read_as",
+            "orm_read" => "orm_read_as",
+            "conn" => "conn_as",
+            _ => return,
+        };
+        call.method = Ident::new(labeled, call.method.span());
+        let (driver, method) = (self.driver, &self.method);
+        call.args
+            .push(parse_quote!(crate::psql_connection::DbCaller::new(#driver, #method)));
+    }
+}
@@ -0,0 +1,46 @@
+use criterion::{Criterion, criterion_group, criterion_main};
+use lib_db::psql_connection::{DbCaller, PsqlConnection};
+use lib_shared::metrics::Metrics;
+use regex_macro::regex;
+use sqlx::postgres::PgPoolOptions;
+use std::hint::black_box;
+
+///what every `db()` call used to cost: a backtrace plus the regex parsing done by the collector.
+///`\s`/`\d` are spelled out since regex-macro is built without unicode-perl
+fn legacy_caller() -> Option<(String, String)> {
+    let capture = std::backtrace::Backtrace::force_capture().to_string();
+    let stack = regex!(r"(^|\n)[ \t]+[0-9]+:")
+        .split(&capture)
+        .filter(|frame| !frame.contains("/rustc/") && !frame.is_empty())
+        .collect::<Vec<_>>();
+    let frame = stack.get(2)?;
+    let captures = regex!(
+        r"(?:[a-zA-Z0-9_]+::)(?P<mod>[a-zA-Z0-9_]+)::<.*?>::(?P<func>[a-zA-Z0-9_]+)(?:::(?:\{\{closure\}\})?)?"
+    )
+    .captures(frame)?;
+    Some((captures["mod"].to_string(), captures["func"].to_string()))
+}
+
+fn caller(c: &mut Criterion) {
+    let runtime = tokio::runtime::Runtime::new().unwrap();
+    //the pool never connects, only the guard is measured
+    let conn = runtime.block_on(async {
+        let pool = PgPoolOptions::new()
+            .connect_lazy("postgres://bench@localhost/bench")
+            .unwrap();
+        let conn = PsqlConnection::new(pool, Metrics::new());
+        conn.run_metric_provider();
+        conn
+    });
+
+    let mut group = c.benchmark_group("db caller");
+    group.bench_function("backtrace", |b| b.iter(|| black_box(legacy_caller())));
+    group.bench_function("db_as", |b| {
+        b.iter(|| drop(black_box(conn.db_as(DbCaller::new("BenchDriver", "bench")))))
+    });
+    group.bench_function("track_caller", |b| b.iter(|| drop(black_box(conn.db()))));
+    group.finish();
+}
+
+criterion_group!(benches, caller);
+criterion_main!(benches);
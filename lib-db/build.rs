@@ -0,0 +1,4 @@
+fn main() {
+    //embedded migrations must be rebuilt when a file is added or edited
+    println!("cargo:rerun-if-changed=migrations");
+}
@@ -0,0 +1,18 @@
+CREATE TABLE job_queue
+(
+    id           BIGSERIAL PRIMARY KEY,
+    kind         TEXT        NOT NULL,
+    payload      JSONB       NOT NULL,
+    state        TEXT        NOT NULL DEFAULT 'pending',
+    priority     SMALLINT    NOT NULL DEFAULT 0,
+    attempts     INT         NOT NULL DEFAULT 0,
+    max_attempts INT         NOT NULL DEFAULT 5,
+    run_at       TIMESTAMPTZ NOT NULL DEFAULT now(),
+    locked_at    TIMESTAMPTZ,
+    last_error   TEXT,
+    created_at   TIMESTAMPTZ NOT NULL DEFAULT now(),
+    finished_at  TIMESTAMPTZ
+);
+
+CREATE INDEX job_queue_pending_idx ON job_queue (priority DESC, run_at) WHERE state = 'pending';
+CREATE INDEX job_queue_running_idx ON job_queue (locked_at) WHERE state = 'running';
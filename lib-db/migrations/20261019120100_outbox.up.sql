@@ -0,0 +1,13 @@
+CREATE TABLE outbox
+(
+    id              BIGSERIAL PRIMARY KEY,
+    event           TEXT        NOT NULL,
+    payload         JSONB       NOT NULL,
+    idempotency_key TEXT        NOT NULL UNIQUE,
+    attempts        INT         NOT NULL DEFAULT 0,
+    last_error      TEXT,
+    created_at      TIMESTAMPTZ NOT NULL DEFAULT now(),
+    dispatched_at   TIMESTAMPTZ
+);
+
+CREATE INDEX outbox_pending_idx ON outbox (id) WHERE dispatched_at IS NULL;
@@ -0,0 +1,3 @@
+ALTER TABLE users
+    DROP COLUMN display_name,
+    DROP COLUMN version;
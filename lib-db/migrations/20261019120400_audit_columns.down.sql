@@ -0,0 +1,11 @@
+DROP INDEX sessions_user_id_idx;
+CREATE INDEX sessions_user_id_idx ON sessions (user_id);
+
+ALTER TABLE sessions
+    DROP COLUMN created_by,
+    DROP COLUMN deleted_at,
+    DROP COLUMN updated_at;
+
+ALTER TABLE users
+    DROP COLUMN created_by,
+    DROP COLUMN deleted_at;
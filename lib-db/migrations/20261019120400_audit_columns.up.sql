@@ -0,0 +1,11 @@
+ALTER TABLE users
+    ADD COLUMN deleted_at TIMESTAMPTZ,
+    ADD COLUMN created_by TEXT;
+
+ALTER TABLE sessions
+    ADD COLUMN updated_at TIMESTAMPTZ NOT NULL DEFAULT now(),
+    ADD COLUMN deleted_at TIMESTAMPTZ,
+    ADD COLUMN created_by TEXT;
+
+DROP INDEX sessions_user_id_idx;
+CREATE INDEX sessions_user_id_idx ON sessions (user_id) WHERE deleted_at IS NULL;
@@ -0,0 +1,30 @@
+CREATE TABLE audit_log
+(
+    id          BIGSERIAL PRIMARY KEY,
+    occurred_at TIMESTAMPTZ NOT NULL,
+    actor       TEXT,
+    ip          TEXT,
+    action      TEXT        NOT NULL,
+    target      TEXT,
+    outcome     TEXT        NOT NULL,
+    details     JSONB       NOT NULL DEFAULT '{}',
+    prev_hash   TEXT        NOT NULL,
+    hash        TEXT        NOT NULL UNIQUE
+);
+
+CREATE INDEX audit_log_actor_idx ON audit_log (actor, id);
+CREATE INDEX audit_log_action_idx ON audit_log (action, id);
+
+-- append-only, rows can't be edited or removed through sql
+CREATE FUNCTION audit_log_immutable() RETURNS trigger AS
+$$
+BEGIN
+    RAISE EXCEPTION 'audit_log is append-only';
+END;
+$$ LANGUAGE plpgsql;
+
+CREATE TRIGGER audit_log_immutable
+    BEFORE UPDATE OR DELETE OR TRUNCATE
+    ON audit_log
+    FOR EACH STATEMENT
+EXECUTE FUNCTION audit_log_immutable();
@@ -0,0 +1,14 @@
+DROP POLICY sessions_tenant_isolation ON sessions;
+ALTER TABLE sessions NO FORCE ROW LEVEL SECURITY;
+ALTER TABLE sessions DISABLE ROW LEVEL SECURITY;
+
+DROP POLICY users_tenant_isolation ON users;
+ALTER TABLE users NO FORCE ROW LEVEL SECURITY;
+ALTER TABLE users DISABLE ROW LEVEL SECURITY;
+
+DROP INDEX sessions_tenant_id_idx;
+ALTER TABLE users DROP CONSTRAINT users_tenant_identity_key;
+ALTER TABLE users ADD CONSTRAINT users_identity_key UNIQUE (identity);
+
+ALTER TABLE sessions DROP COLUMN tenant_id;
+ALTER TABLE users DROP COLUMN tenant_id;
@@ -0,0 +1,28 @@
+-- rows that existed before tenants belong to the `default` tenant
+ALTER TABLE users ADD COLUMN tenant_id TEXT;
+UPDATE users SET tenant_id = 'default';
+ALTER TABLE users
+    ALTER COLUMN tenant_id SET NOT NULL,
+    ALTER COLUMN tenant_id SET DEFAULT NULLIF(current_setting('app.tenant_id', true), '');
+
+ALTER TABLE sessions ADD COLUMN tenant_id TEXT;
+UPDATE sessions SET tenant_id = 'default';
+ALTER TABLE sessions
+    ALTER COLUMN tenant_id SET NOT NULL,
+    ALTER COLUMN tenant_id SET DEFAULT NULLIF(current_setting('app.tenant_id', true), '');
+
+-- identities are unique per tenant
+ALTER TABLE users DROP CONSTRAINT users_identity_key;
+ALTER TABLE users ADD CONSTRAINT users_tenant_identity_key UNIQUE (tenant_id, identity);
+CREATE INDEX sessions_tenant_id_idx ON sessions (tenant_id);
+
+-- without `app.tenant_id` nothing is visible, FORCE applies the policies to the table owner too
+ALTER TABLE users ENABLE ROW LEVEL SECURITY;
+ALTER TABLE users FORCE ROW LEVEL SECURITY;
+CREATE POLICY users_tenant_isolation ON users
+    USING (tenant_id = NULLIF(current_setting('app.tenant_id', true), ''));
+
+ALTER TABLE sessions ENABLE ROW LEVEL SECURITY;
+ALTER TABLE sessions FORCE ROW LEVEL SECURITY;
+CREATE POLICY sessions_tenant_isolation ON sessions
+    USING (tenant_id = NULLIF(current_setting('app.tenant_id', true), ''));
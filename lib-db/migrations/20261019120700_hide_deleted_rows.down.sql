@@ -0,0 +1,2 @@
+DROP POLICY sessions_hide_deleted ON sessions;
+DROP POLICY users_hide_deleted ON users;
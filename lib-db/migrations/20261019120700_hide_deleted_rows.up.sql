@@ -0,0 +1,12 @@
+-- soft deleted rows are hidden unless `app.include_deleted` is on. restrictive, so it narrows the tenant policy
+-- down instead of widening it. updates must pass the select policies with their new row, a row deleted by the
+-- statement itself (`deleted_at = statement_timestamp()`) is let through so soft deletes don't need the setting
+CREATE POLICY users_hide_deleted ON users AS RESTRICTIVE FOR SELECT
+    USING (deleted_at IS NULL
+        OR deleted_at >= statement_timestamp()
+        OR current_setting('app.include_deleted', true) = 'on');
+
+CREATE POLICY sessions_hide_deleted ON sessions AS RESTRICTIVE FOR SELECT
+    USING (deleted_at IS NULL
+        OR deleted_at >= statement_timestamp()
+        OR current_setting('app.include_deleted', true) = 'on');
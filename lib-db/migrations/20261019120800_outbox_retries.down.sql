@@ -0,0 +1,7 @@
+DROP INDEX outbox_pending_idx;
+CREATE INDEX outbox_pending_idx ON outbox (id) WHERE dispatched_at IS NULL;
+
+ALTER TABLE outbox
+    DROP COLUMN failed_at,
+    DROP COLUMN claimed_until,
+    DROP COLUMN next_attempt_at;
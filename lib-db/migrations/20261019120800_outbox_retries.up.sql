@@ -0,0 +1,9 @@
+-- messages are claimed for a while instead of being locked during the dispatch, failures are retried with a
+-- backoff and given up (`failed_at`) after too many attempts
+ALTER TABLE outbox
+    ADD COLUMN next_attempt_at TIMESTAMPTZ NOT NULL DEFAULT now(),
+    ADD COLUMN claimed_until   TIMESTAMPTZ,
+    ADD COLUMN failed_at       TIMESTAMPTZ;
+
+DROP INDEX outbox_pending_idx;
+CREATE INDEX outbox_pending_idx ON outbox (id) WHERE dispatched_at IS NULL AND failed_at IS NULL;
@@ -0,0 +1,6 @@
+DROP POLICY audit_log_tenant_isolation ON audit_log;
+ALTER TABLE audit_log NO FORCE ROW LEVEL SECURITY;
+ALTER TABLE audit_log DISABLE ROW LEVEL SECURITY;
+
+DROP INDEX audit_log_tenant_id_idx;
+ALTER TABLE audit_log DROP COLUMN tenant_id;
@@ -0,0 +1,12 @@
+-- entries recorded before tenants belong to the `default` tenant, they formed a single chain so it stays intact.
+-- entries recorded without a tenant (e.g. a login that named none) are only visible without a tenant either
+ALTER TABLE audit_log ADD COLUMN tenant_id TEXT DEFAULT 'default';
+ALTER TABLE audit_log ALTER COLUMN tenant_id SET DEFAULT NULLIF(current_setting('app.tenant_id', true), '');
+
+-- every tenant has its own chain
+CREATE INDEX audit_log_tenant_id_idx ON audit_log (tenant_id, id);
+
+ALTER TABLE audit_log ENABLE ROW LEVEL SECURITY;
+ALTER TABLE audit_log FORCE ROW LEVEL SECURITY;
+CREATE POLICY audit_log_tenant_isolation ON audit_log
+    USING (tenant_id IS NOT DISTINCT FROM NULLIF(current_setting('app.tenant_id', true), ''));
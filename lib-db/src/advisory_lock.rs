@@ -0,0 +1,86 @@
+use crate::psql_connection::PsqlConnection;
+use sqlx::{Connection, PgConnection};
+use std::time::Duration;
+
+///session level advisory lock, held for as long as its dedicated connection lives.
+///if the connection drops, postgres releases the lock for the other instances.
+pub struct AdvisoryLock {
+    conn: PgConnection,
+    name: String,
+}
+
+impl PsqlConnection {
+    ///returns `None` if another session already holds the lock
+    pub async fn try_advisory_lock(&self, name: &str) -> eyre::Result<Option<AdvisoryLock>> {
+        //own connection outside the pool, so the holder and the retries never take a pool slot
+        let mut conn = PgConnection::connect_with(&self.db.connect_options()).await?;
+        let acquired: bool = sqlx::query_scalar("SELECT pg_try_advisory_lock(hashtext($1))")
+            .bind(name)
+            .fetch_one(&mut conn)
+            .await?;
+        if !acquired {
+            conn.close().await?;
+            return Ok(None);
+        }
+        Ok(Some(AdvisoryLock {
+            conn,
+            name: name.to_owned(),
+        }))
+    }
+}
+
+impl AdvisoryLock {
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    ///checks the session that holds the lock is still usable
+    pub async fn is_alive(&mut self, timeout: Duration) -> bool {
+        matches!(
+            tokio::time::timeout(timeout, self.conn.ping()).await,
+            Ok(Ok(_))
+        )
+    }
+
+    pub async fn release(mut self) -> eyre::Result<()> {
+        sqlx::query("SELECT pg_advisory_unlock(hashtext($1))")
+            .bind(&self.name)
+            .execute(&mut self.conn)
+            .await?;
+        self.conn.close().await?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use lib_shared::metrics::Metrics;
+    use sqlx::PgPool;
+
+    #[tokio::test]
+    #[ignore = "needs a postgres server in DATABASE_URL"]
+    async fn only_one_session_holds_the_lock() {
+        let url = std::env::var("DATABASE_URL").unwrap();
+        let connection = PsqlConnection::new(PgPool::connect(&url).await.unwrap(), Metrics::new());
+        let name = format!("lock-test-{}", rand::random::<u32>());
+
+        let mut lock = connection.try_advisory_lock(&name).await.unwrap().unwrap();
+        assert!(lock.is_alive(Duration::from_secs(1)).await);
+        assert!(connection.try_advisory_lock(&name).await.unwrap().is_none());
+
+        lock.release().await.unwrap();
+        let lock = connection.try_advisory_lock(&name).await.unwrap().unwrap();
+        //dropping the connection releases it too
+        drop(lock);
+        let mut acquired = None;
+        for _ in 0..50 {
+            acquired = connection.try_advisory_lock(&name).await.unwrap();
+            if acquired.is_some() {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        }
+        assert!(acquired.is_some());
+    }
+}
@@ -0,0 +1,130 @@
+use crate::entities::{sessions, users};
+use sea_orm::sea_query::Expr;
+use sea_orm::{ActiveModelTrait, ColumnTrait, EntityTrait, QueryFilter, UpdateMany, Value};
+use sqlx::{Executor, Postgres};
+use std::future::Future;
+
+///postgres setting read by the row level security policies hiding the soft deleted rows
+pub const INCLUDE_DELETED_SETTING: &str = "app.include_deleted";
+
+tokio::task_local! {
+    static PRINCIPAL: Option<String>;
+    static INCLUDE_DELETED: bool;
+}
+
+///runs `f` on behalf of `principal`, it ends up in `created_by` of the rows inserted inside.
+///tasks spawned inside don't inherit it
+pub async fn with_principal<F: Future>(principal: Option<String>, f: F) -> F::Output {
+    PRINCIPAL.scope(principal, f).await
+}
+
+///`None` outside of [`with_principal`], e.g. in background jobs
+pub fn principal() -> Option<String> {
+    PRINCIPAL.try_with(Clone::clone).ok().flatten()
+}
+
+///runs `f` with the soft deleted rows of the audited tables visible to every connection acquired
+///inside, queries outside of it never return them. tasks spawned inside don't inherit it
+pub async fn with_deleted<F: Future>(f: F) -> F::Output {
+    INCLUDE_DELETED.scope(true, f).await
+}
+
+///`true` inside [`with_deleted`]
+pub fn include_deleted() -> bool {
+    INCLUDE_DELETED
+        .try_with(|include| *include)
+        .unwrap_or(false)
+}
+
+///entities following the `created_at`, `updated_at`, `deleted_at`, `created_by` convention,
+///implemented with [`impl_audited!`](crate::impl_audited). their soft deleted rows are hidden from
+///`find()` and plain sql alike, see [`with_deleted`]
+pub trait Audited: EntityTrait {
+    fn created_at() -> Self::Column;
+    fn updated_at() -> Self::Column;
+    fn deleted_at() -> Self::Column;
+    fn created_by() -> Self::Column;
+
+    ///narrow it down with `.filter(...)`, rows already deleted are left untouched. stamped with the
+    ///time of the statement, which the policies let through, see the `hide_deleted_rows` migration
+    fn soft_delete() -> UpdateMany<Self> {
+        Self::update_many()
+            .col_expr(Self::deleted_at(), Expr::cust("statement_timestamp()"))
+            .col_expr(Self::updated_at(), Expr::current_timestamp().into())
+            .filter(Self::deleted_at().is_null())
+    }
+
+    ///only finds the deleted rows inside [`with_deleted`]
+    fn restore() -> UpdateMany<Self> {
+        Self::update_many()
+            .col_expr(
+                Self::deleted_at(),
+                Expr::value(Value::ChronoDateTimeWithTimeZone(None)),
+            )
+            .col_expr(Self::updated_at(), Expr::current_timestamp().into())
+            .filter(Self::deleted_at().is_not_null())
+    }
+}
+
+///fills the audit columns of a new row, call it right before `insert`
+pub fn stamp_insert<A>(mut model: A) -> A
+where
+    A: ActiveModelTrait,
+    A::Entity: Audited,
+{
+    let now = chrono::Utc::now().fixed_offset();
+    model.set(<A::Entity as Audited>::created_at(), now.into());
+    model.set(<A::Entity as Audited>::updated_at(), now.into());
+    model.set(<A::Entity as Audited>::created_by(), principal().into());
+    model
+}
+
+///bumps `updated_at`, call it right before `update`
+pub fn stamp_update<A>(mut model: A) -> A
+where
+    A: ActiveModelTrait,
+    A::Entity: Audited,
+{
+    let now = chrono::Utc::now().fixed_offset();
+    model.set(<A::Entity as Audited>::updated_at(), now.into());
+    model
+}
+
+///sqlx counterpart of [`Audited::soft_delete`], returns whether a row got deleted
+pub async fn soft_delete<'e, E>(executor: E, table: &'static str, id: i64) -> eyre::Result<bool>
+where
+    E: Executor<'e, Database = Postgres>,
+{
+    let result = sqlx::query(&format!(
+        "UPDATE {table} SET deleted_at = statement_timestamp(), updated_at = now() \
+         WHERE id = $1 AND deleted_at IS NULL"
+    ))
+    .bind(id)
+    .execute(executor)
+    .await?;
+    Ok(result.rows_affected() > 0)
+}
+
+#[macro_export]
+macro_rules! impl_audited {
+    ($( $entity:ident ),+) => {
+        $(
+            impl $crate::audit::Audited for $entity::Entity {
+                fn created_at() -> Self::Column {
+                    $entity::Column::CreatedAt
+                }
+                fn updated_at() -> Self::Column {
+                    $entity::Column::UpdatedAt
+                }
+                fn deleted_at() -> Self::Column {
+                    $entity::Column::DeletedAt
+                }
+                fn created_by() -> Self::Column {
+                    $entity::Column::CreatedBy
+                }
+            }
+        )+
+    };
+}
+
+impl_audited!(users, sessions);
@@ -0,0 +1,359 @@
+use crate::AuditLogDriver;
+use crate::tenant::current_tenant;
+use chrono::{DateTime, Utc};
+use lib_db_macros::db_driver;
+use lib_shared::instrument;
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+use sqlx::{FromRow, PgConnection, Postgres, QueryBuilder};
+use std::fmt::Write;
+extern crate tracing;
+
+///`prev_hash` of the first entry
+const GENESIS: &str = "0000000000000000000000000000000000000000000000000000000000000000";
+const VERIFY_BATCH: i64 = 1000;
+
+#[derive(Clone, Debug)]
+pub struct NewAuditEntry {
+    pub actor: Option<String>,
+    pub ip: Option<String>,
+    pub action: String,
+    pub target: Option<String>,
+    pub outcome: String,
+    pub details: serde_json::Value,
+}
+
+#[derive(FromRow, Debug, Serialize)]
+pub struct AuditEntry {
+    pub id: i64,
+    pub occurred_at: DateTime<Utc>,
+    pub actor: Option<String>,
+    pub ip: Option<String>,
+    pub action: String,
+    pub target: Option<String>,
+    pub outcome: String,
+    pub details: serde_json::Value,
+    pub prev_hash: String,
+    pub hash: String,
+}
+
+#[derive(Debug, Default)]
+pub struct AuditFilter {
+    pub actor: Option<String>,
+    pub action: Option<String>,
+    pub target: Option<String>,
+    pub outcome: Option<String>,
+    pub from: Option<DateTime<Utc>>,
+    pub to: Option<DateTime<Utc>>,
+    ///pagination, only entries older than this id
+    pub before_id: Option<i64>,
+    pub limit: i64,
+}
+
+#[derive(Debug, Serialize)]
+pub struct ChainStatus {
+    pub checked: u64,
+    ///first entry whose hash doesn't match its content or its predecessor
+    pub broken_at: Option<i64>,
+}
+
+#[db_driver]
+impl AuditLogDriver {
+    ///entries of a tenant are serialized on an advisory lock so every hash covers the previous
+    ///one. `tenant_id` defaults to the current tenant and row level security only shows the
+    ///entries of that tenant, so every tenant gets its own chain
+    #[instrument(skip(self))]
+    pub async fn append(&self, entry: &NewAuditEntry) -> eyre::Result<AuditEntry> {
+        self.connection
+            .transaction(|tx| async move { Self::append_in(&mut *tx.conn().await?, entry).await })
+            .await
+    }
+
+    async fn append_in(conn: &mut PgConnection, entry: &NewAuditEntry) -> eyre::Result<AuditEntry> {
+        sqlx::query("SELECT pg_advisory_xact_lock(hashtext('audit_log'), hashtext($1))")
+            .bind(current_tenant().unwrap_or_default())
+            .execute(&mut *conn)
+            .await?;
+        let prev_hash: String =
+            sqlx::query_scalar("SELECT hash FROM audit_log ORDER BY id DESC LIMIT 1")
+                .fetch_optional(&mut *conn)
+                .await?
+                .unwrap_or_else(|| GENESIS.to_owned());
+
+        //postgres keeps microseconds, the hash must cover what is stored
+        let occurred_at =
+            DateTime::from_timestamp_micros(Utc::now().timestamp_micros()).unwrap_or_default();
+        let hash = entry_hash(
+            &prev_hash,
+            occurred_at,
+            &entry.actor,
+            &entry.ip,
+            &entry.action,
+            &entry.target,
+            &entry.outcome,
+            &entry.details,
+        );
+        let entry = sqlx::query_as::<_, AuditEntry>(
+            "INSERT INTO audit_log
+             (occurred_at, actor, ip, action, target, outcome, details, prev_hash, hash)
+             VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9) RETURNING *",
+        )
+        .bind(occurred_at)
+        .bind(&entry.actor)
+        .bind(&entry.ip)
+        .bind(&entry.action)
+        .bind(&entry.target)
+        .bind(&entry.outcome)
+        .bind(&entry.details)
+        .bind(&prev_hash)
+        .bind(&hash)
+        .fetch_one(&mut *conn)
+        .await?;
+        Ok(entry)
+    }
+
+    ///newest first, only the entries of the current tenant
+    #[instrument(skip(self))]
+    pub async fn query(&self, filter: &AuditFilter) -> eyre::Result<Vec<AuditEntry>> {
+        let mut query = QueryBuilder::<Postgres>::new("SELECT * FROM audit_log WHERE true");
+        let columns = [
+            ("actor", &filter.actor),
+            ("action", &filter.action),
+            ("target", &filter.target),
+            ("outcome", &filter.outcome),
+        ];
+        for (column, value) in columns {
+            if let Some(value) = value {
+                query
+                    .push(format_args!(" AND {column} = "))
+                    .push_bind(value);
+            }
+        }
+        if let Some(from) = filter.from {
+            query.push(" AND occurred_at >= ").push_bind(from);
+        }
+        if let Some(to) = filter.to {
+            query.push(" AND occurred_at < ").push_bind(to);
+        }
+        if let Some(before_id) = filter.before_id {
+            query.push(" AND id < ").push_bind(before_id);
+        }
+        query
+            .push(" ORDER BY id DESC LIMIT ")
+            .push_bind(filter.limit);
+        Ok(query
+            .build_query_as()
+            .fetch_all(*self.connection.db_read())
+            .await?)
+    }
+
+    ///recomputes the chain of the current tenant, oldest first
+    #[instrument(skip(self))]
+    pub async fn verify(&self) -> eyre::Result<ChainStatus> {
+        let mut chain = ChainCheck::new();
+        let mut last_id = 0;
+        loop {
+            let batch = sqlx::query_as::<_, AuditEntry>(
+                "SELECT * FROM audit_log WHERE id > $1 ORDER BY id LIMIT $2",
+            )
+            .bind(last_id)
+            .bind(VERIFY_BATCH)
+            .fetch_all(*self.connection.db_read())
+            .await?;
+            let Some(last) = batch.last() else {
+                return Ok(chain.status(None));
+            };
+            last_id = last.id;
+
+            for entry in batch {
+                let id = entry.id;
+                if !chain.push(entry) {
+                    return Ok(chain.status(Some(id)));
+                }
+            }
+        }
+    }
+}
+
+///walks a chain oldest first
+struct ChainCheck {
+    checked: u64,
+    prev_hash: String,
+}
+
+impl ChainCheck {
+    fn new() -> Self {
+        Self {
+            checked: 0,
+            prev_hash: GENESIS.to_owned(),
+        }
+    }
+
+    ///`false` if `entry` doesn't match its content or doesn't follow the previous entry
+    fn push(&mut self, entry: AuditEntry) -> bool {
+        let hash = entry_hash(
+            &self.prev_hash,
+            entry.occurred_at,
+            &entry.actor,
+            &entry.ip,
+            &entry.action,
+            &entry.target,
+            &entry.outcome,
+            &entry.details,
+        );
+        if entry.prev_hash != self.prev_hash || entry.hash != hash {
+            return false;
+        }
+        self.checked += 1;
+        self.prev_hash = entry.hash;
+        true
+    }
+
+    fn status(self, broken_at: Option<i64>) -> ChainStatus {
+        ChainStatus {
+            checked: self.checked,
+            broken_at,
+        }
+    }
+}
+
+#[allow(clippy::too_many_arguments)]
+fn entry_hash(
+    prev_hash: &str,
+    occurred_at: DateTime<Utc>,
+    actor: &Option<String>,
+    ip: &Option<String>,
+    action: &str,
+    target: &Option<String>,
+    outcome: &str,
+    details: &serde_json::Value,
+) -> String {
+    //the tenant isn't hashed, chaining per tenant already binds an entry to its tenant and keeps
+    //the entries older than tenants valid.
+    //json array so field boundaries are unambiguous, object keys are sorted by serde_json
+    let content = serde_json::json!([
+        prev_hash,
+        occurred_at.timestamp_micros(),
+        actor,
+        ip,
+        action,
+        target,
+        outcome,
+        details,
+    ]);
+    let digest = Sha256::digest(content.to_string().as_bytes());
+    digest.iter().fold(String::with_capacity(64), |mut hex, b| {
+        _ = write!(hex, "{b:02x}");
+        hex
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    ///entries chained the way [`AuditLogDriver::append`] does
+    fn chain(len: i64) -> Vec<AuditEntry> {
+        let mut prev_hash = GENESIS.to_owned();
+        (1..=len)
+            .map(|id| {
+                let occurred_at =
+                    DateTime::from_timestamp_micros(1_700_000_000_000_000 + id).unwrap_or_default();
+                let actor = Some(format!("user-{id}"));
+                let details = serde_json::json!({ "n": id });
+                let hash = entry_hash(
+                    &prev_hash,
+                    occurred_at,
+                    &actor,
+                    &None,
+                    "auth.login",
+                    &None,
+                    "success",
+                    &details,
+                );
+                AuditEntry {
+                    id,
+                    occurred_at,
+                    actor,
+                    ip: None,
+                    action: "auth.login".to_owned(),
+                    target: None,
+                    outcome: "success".to_owned(),
+                    details,
+                    prev_hash: std::mem::replace(&mut prev_hash, hash.clone()),
+                    hash,
+                }
+            })
+            .collect()
+    }
+
+    fn check(entries: Vec<AuditEntry>) -> (u64, Option<i64>) {
+        let mut chain = ChainCheck::new();
+        for entry in entries {
+            let id = entry.id;
+            if !chain.push(entry) {
+                let status = chain.status(Some(id));
+                return (status.checked, status.broken_at);
+            }
+        }
+        (chain.status(None).checked, None)
+    }
+
+    #[test]
+    fn an_intact_chain_verifies() {
+        assert_eq!(check(chain(5)), (5, None));
+        assert_eq!(check(vec![]), (0, None));
+    }
+
+    #[test]
+    fn hashes_cover_every_field() {
+        let edits: [fn(&mut AuditEntry); 7] = [
+            |e| e.occurred_at += chrono::Duration::microseconds(1),
+            |e| e.actor = Some("mallory".to_owned()),
+            |e| e.ip = Some("10.0.0.1".to_owned()),
+            |e| e.action = "auth.token_issued".to_owned(),
+            |e| e.target = Some("alice".to_owned()),
+            |e| e.outcome = "failure".to_owned(),
+            |e| e.details = serde_json::json!({ "n": 0 }),
+        ];
+        for edit in edits {
+            let mut entries = chain(5);
+            edit(&mut entries[2]);
+            assert_eq!(check(entries), (2, Some(3)));
+        }
+    }
+
+    #[test]
+    fn a_rehashed_row_breaks_its_successor() {
+        let mut entries = chain(5);
+        let edited = &mut entries[2];
+        edited.actor = Some("mallory".to_owned());
+        edited.hash = entry_hash(
+            &edited.prev_hash,
+            edited.occurred_at,
+            &edited.actor,
+            &edited.ip,
+            &edited.action,
+            &edited.target,
+            &edited.outcome,
+            &edited.details,
+        );
+        assert_eq!(check(entries), (3, Some(4)));
+    }
+
+    #[test]
+    fn reordered_or_missing_entries_break_the_chain() {
+        let mut entries = chain(5);
+        entries.swap(1, 2);
+        assert_eq!(check(entries), (1, Some(3)));
+
+        let mut entries = chain(5);
+        entries.remove(3);
+        assert_eq!(check(entries), (3, Some(5)));
+
+        //the first entry must follow the genesis hash
+        let mut entries = chain(5);
+        entries.remove(0);
+        assert_eq!(check(entries), (0, Some(2)));
+    }
+}
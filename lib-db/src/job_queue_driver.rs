@@ -0,0 +1,285 @@
+use crate::JobQueueDriver;
+use chrono::{DateTime, Utc};
+use lib_db_macros::db_driver;
+use lib_shared::instrument;
+use sqlx::FromRow;
+use std::time::Duration;
+extern crate tracing;
+
+#[derive(FromRow, Debug)]
+pub struct QueuedJob {
+    pub id: i64,
+    pub kind: String,
+    pub payload: serde_json::Value,
+    pub attempts: i32,
+    pub max_attempts: i32,
+}
+
+#[derive(Debug, Default, PartialEq)]
+pub struct StaleJobs {
+    ///back to pending
+    pub released: u64,
+    ///out of attempts, moved to the dead-letter state
+    pub buried: u64,
+}
+
+#[db_driver]
+impl JobQueueDriver {
+    #[instrument(skip(self, payload))]
+    pub async fn enqueue(
+        &self,
+        kind: &str,
+        payload: serde_json::Value,
+        priority: i16,
+        run_at: DateTime<Utc>,
+        max_attempts: i32,
+    ) -> eyre::Result<i64> {
+        let id = sqlx::query_scalar(
+            "INSERT INTO job_queue (kind, payload, priority, run_at, max_attempts)
+             VALUES ($1, $2, $3, $4, $5) RETURNING id",
+        )
+        .bind(kind)
+        .bind(payload)
+        .bind(priority)
+        .bind(run_at)
+        .bind(max_attempts)
+        .fetch_one(*self.connection.db())
+        .await?;
+        Ok(id)
+    }
+
+    ///locks up to `limit` due jobs of the given kinds, concurrent workers skip each other's rows
+    #[instrument(skip(self))]
+    pub async fn claim(&self, kinds: &[String], limit: i64) -> eyre::Result<Vec<QueuedJob>> {
+        let jobs = sqlx::query_as(
+            "UPDATE job_queue
+             SET state = 'running', locked_at = now(), attempts = attempts + 1
+             WHERE id IN (SELECT id
+                          FROM job_queue
+                          WHERE state = 'pending' AND run_at <= now() AND kind = ANY($1)
+                          ORDER BY priority DESC, run_at
+                          LIMIT $2 FOR UPDATE SKIP LOCKED)
+             RETURNING id, kind, payload, attempts, max_attempts",
+        )
+        .bind(kinds)
+        .bind(limit)
+        .fetch_all(*self.connection.db())
+        .await?;
+        Ok(jobs)
+    }
+
+    ///the `complete`, `retry` and `bury` updates only apply to the claim `attempt` of a job that
+    ///still runs. `false` means the job was released as stale and possibly claimed again, the
+    ///result of this run is dropped
+    #[instrument(skip(self))]
+    pub async fn complete(&self, id: i64, attempt: i32) -> eyre::Result<bool> {
+        let result = sqlx::query(
+            "UPDATE job_queue SET state = 'done', locked_at = NULL, finished_at = now()
+             WHERE id = $1 AND attempts = $2 AND state = 'running'",
+        )
+        .bind(id)
+        .bind(attempt)
+        .execute(*self.connection.db())
+        .await?;
+        Ok(result.rows_affected() > 0)
+    }
+
+    #[instrument(skip(self))]
+    pub async fn retry(
+        &self,
+        id: i64,
+        attempt: i32,
+        error: &str,
+        run_at: DateTime<Utc>,
+    ) -> eyre::Result<bool> {
+        let result = sqlx::query(
+            "UPDATE job_queue SET state = 'pending', locked_at = NULL, last_error = $3, run_at = $4
+             WHERE id = $1 AND attempts = $2 AND state = 'running'",
+        )
+        .bind(id)
+        .bind(attempt)
+        .bind(error)
+        .bind(run_at)
+        .execute(*self.connection.db())
+        .await?;
+        Ok(result.rows_affected() > 0)
+    }
+
+    ///moves the job to the dead-letter state, it won't be picked up again
+    #[instrument(skip(self))]
+    pub async fn bury(&self, id: i64, attempt: i32, error: &str) -> eyre::Result<bool> {
+        let result = sqlx::query(
+            "UPDATE job_queue SET state = 'dead', locked_at = NULL, last_error = $3, finished_at = now()
+             WHERE id = $1 AND attempts = $2 AND state = 'running'",
+        )
+        .bind(id)
+        .bind(attempt)
+        .bind(error)
+        .execute(*self.connection.db())
+        .await?;
+        Ok(result.rows_affected() > 0)
+    }
+
+    ///puts jobs of crashed workers back into the queue, or buries them once they used up their
+    ///attempts so a job that kills its worker isn't requeued forever. a job is stale after the
+    ///window of its kind in `windows`, or `default_window` for the other kinds
+    #[instrument(skip(self))]
+    pub async fn release_stale(
+        &self,
+        windows: &[(String, Duration)],
+        default_window: Duration,
+    ) -> eyre::Result<StaleJobs> {
+        let (kinds, secs): (Vec<_>, Vec<_>) = windows
+            .iter()
+            .map(|(kind, window)| (kind.as_str(), window.as_secs_f64()))
+            .unzip();
+        let states: Vec<String> = sqlx::query_scalar(
+            "UPDATE job_queue j
+             SET state       = CASE WHEN attempts >= max_attempts THEN 'dead' ELSE 'pending' END,
+                 last_error  = CASE WHEN attempts >= max_attempts
+                                    THEN 'the worker was lost on the last attempt'
+                                    ELSE last_error END,
+                 finished_at = CASE WHEN attempts >= max_attempts THEN now() END,
+                 locked_at   = NULL
+             WHERE state = 'running'
+               AND locked_at < now() - COALESCE((SELECT make_interval(secs => w.secs)
+                                                 FROM unnest($1::text[], $2::float8[]) AS w (kind, secs)
+                                                 WHERE w.kind = j.kind), $3)
+             RETURNING state",
+        )
+        .bind(kinds)
+        .bind(secs)
+        .bind(default_window)
+        .fetch_all(*self.connection.db())
+        .await?;
+        let buried = states.iter().filter(|state| *state == "dead").count() as u64;
+        Ok(StaleJobs {
+            released: states.len() as u64 - buried,
+            buried,
+        })
+    }
+
+    #[instrument(skip(self))]
+    pub async fn purge_finished(&self, older_than: Duration) -> eyre::Result<u64> {
+        let result =
+            sqlx::query("DELETE FROM job_queue WHERE state = 'done' AND finished_at < now() - $1")
+                .bind(older_than)
+                .execute(*self.connection.db())
+                .await?;
+        Ok(result.rows_affected())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::psql_connection::PsqlConnection;
+    use lib_shared::metrics::Metrics;
+    use sqlx::PgPool;
+    use sqlx::postgres::PgPoolOptions;
+
+    ///a driver over a temporary `job_queue` table. the pool has a single connection, so the table
+    ///shadows the real one for every query
+    async fn driver() -> (JobQueueDriver, PgPool) {
+        let url = std::env::var("DATABASE_URL").unwrap();
+        let pool = PgPoolOptions::new()
+            .max_connections(1)
+            .connect(&url)
+            .await
+            .unwrap();
+        sqlx::raw_sql("CREATE TEMP TABLE job_queue (LIKE job_queue INCLUDING ALL)")
+            .execute(&pool)
+            .await
+            .unwrap();
+        let driver = JobQueueDriver::new(PsqlConnection::new(pool.clone(), Metrics::new()));
+        (driver, pool)
+    }
+
+    async fn enqueue(driver: &JobQueueDriver, kind: &str, max_attempts: i32) -> i64 {
+        let payload = serde_json::json!({});
+        driver
+            .enqueue(kind, payload, 0, Utc::now(), max_attempts)
+            .await
+            .unwrap()
+    }
+
+    async fn lock_for(pool: &PgPool, id: i64, locked_for: Duration) {
+        sqlx::query("UPDATE job_queue SET locked_at = now() - $2 WHERE id = $1")
+            .bind(id)
+            .bind(locked_for)
+            .execute(pool)
+            .await
+            .unwrap();
+    }
+
+    async fn state(pool: &PgPool, id: i64) -> String {
+        sqlx::query_scalar("SELECT state FROM job_queue WHERE id = $1")
+            .bind(id)
+            .fetch_one(pool)
+            .await
+            .unwrap()
+    }
+
+    #[tokio::test]
+    #[ignore = "needs a postgres server in DATABASE_URL"]
+    async fn stale_jobs_are_released_or_buried() {
+        let (driver, pool) = driver().await;
+        let kinds = ["fast".to_owned(), "slow".to_owned()];
+        let retried = enqueue(&driver, "fast", 3).await;
+        let exhausted = enqueue(&driver, "fast", 1).await;
+        let slow = enqueue(&driver, "slow", 1).await;
+        assert_eq!(driver.claim(&kinds, 10).await.unwrap().len(), 3);
+        for id in [retried, exhausted, slow] {
+            lock_for(&pool, id, Duration::from_secs(20 * 60)).await;
+        }
+
+        let windows = [("slow".to_owned(), Duration::from_secs(60 * 60))];
+        let stale = driver
+            .release_stale(&windows, Duration::from_secs(10 * 60))
+            .await
+            .unwrap();
+        assert_eq!(
+            stale,
+            StaleJobs {
+                released: 1,
+                buried: 1
+            }
+        );
+        assert_eq!(state(&pool, retried).await, "pending");
+        assert_eq!(state(&pool, exhausted).await, "dead");
+        //within the window of its kind
+        assert_eq!(state(&pool, slow).await, "running");
+    }
+
+    #[tokio::test]
+    #[ignore = "needs a postgres server in DATABASE_URL"]
+    async fn outcomes_only_apply_to_the_current_claim() {
+        let (driver, pool) = driver().await;
+        let kinds = ["fast".to_owned()];
+        let id = enqueue(&driver, "fast", 3).await;
+        let first = driver.claim(&kinds, 1).await.unwrap().remove(0);
+        lock_for(&pool, id, Duration::from_secs(20 * 60)).await;
+        driver
+            .release_stale(&[], Duration::from_secs(10 * 60))
+            .await
+            .unwrap();
+        let second = driver.claim(&kinds, 1).await.unwrap().remove(0);
+        assert_eq!((first.attempts, second.attempts), (1, 2));
+
+        //the first worker finishes late, its results don't touch the second claim
+        assert!(!driver.complete(id, first.attempts).await.unwrap());
+        assert!(!driver.bury(id, first.attempts, "late").await.unwrap());
+        assert!(
+            !driver
+                .retry(id, first.attempts, "late", Utc::now())
+                .await
+                .unwrap()
+        );
+        assert_eq!(state(&pool, id).await, "running");
+
+        assert!(driver.complete(id, second.attempts).await.unwrap());
+        assert_eq!(state(&pool, id).await, "done");
+        //and it can't be completed twice
+        assert!(!driver.complete(id, second.attempts).await.unwrap());
+    }
+}
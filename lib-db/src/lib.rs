@@ -1,8 +1,26 @@
 use crate::psql_connection::PsqlConnection;
+use crate::replicas::Replicas;
+use lib_shared::env_service::PsqlPoolEnv;
 use lib_shared::metrics::Metrics;
 
+pub mod advisory_lock;
+pub mod audit;
+pub mod audit_log_driver;
+pub mod entities;
+pub mod job_queue_driver;
+pub mod migrations;
+pub mod notify;
+pub mod outbox_driver;
+pub mod password;
+pub mod pool;
 pub mod psql_connection;
+pub mod query_trace;
+pub mod replicas;
+pub mod repositories;
+pub mod tenant;
+pub mod transaction;
 pub mod user_auth_driver;
+pub mod versioning;
 
 #[macro_export]
 macro_rules! impl_psql_driver {
@@ -16,13 +34,43 @@ macro_rules! impl_psql_driver {
         }
         paste::paste!{
             impl PsqlDriver {
-                pub async fn new(url: &str,metrics: Metrics) -> Self {
-                    let db = sqlx::PgPool::connect(url).await.unwrap();
-                    let connection = PsqlConnection::new(db, metrics);
-                    Self {
+                pub async fn new(
+                    url: &str,
+                    replica_urls: &[String],
+                    pool: &PsqlPoolEnv,
+                    metrics: Metrics,
+                ) -> eyre::Result<Self> {
+                    let db = $crate::pool::connect_with_retry(url, pool, &metrics).await?;
+                    Self::with_pool(db, replica_urls, pool, metrics)
+                }
+
+                ///doesn't connect until the first query, so tests can build the drivers without a
+                ///database
+                pub fn connect_lazy(
+                    url: &str,
+                    replica_urls: &[String],
+                    pool: &PsqlPoolEnv,
+                    metrics: Metrics,
+                ) -> eyre::Result<Self> {
+                    let options = $crate::pool::connect_options(url, pool)?;
+                    let db = $crate::pool::pool_options(pool).connect_lazy_with(options);
+                    Self::with_pool(db, replica_urls, pool, metrics)
+                }
+
+                fn with_pool(
+                    db: sqlx::PgPool,
+                    replica_urls: &[String],
+                    pool: &PsqlPoolEnv,
+                    metrics: Metrics,
+                ) -> eyre::Result<Self> {
+                    let replicas = Replicas::connect_lazy(replica_urls, pool, &metrics)?;
+                    let connection = PsqlConnection::new(db, metrics)
+                        .with_slow_query_threshold(pool.slow_query_threshold)
+                        .with_replicas(replicas);
+                    Ok(Self {
                         connection:connection.clone(),
                         $([<$struct_name:snake:lower>]:$struct_name::new(connection.clone()),)+
-                    }
+                    })
                 }
             }
         }
@@ -45,23 +93,20 @@ macro_rules! impl_psql_driver {
 
 #[macro_export]
 macro_rules! impl_guard {
-    ($i:ident,$i2:path) => {
+    ($i:ident,$i2:ty) => {
         pub struct $i<'a> {
-            db: &'a $i2,
+            db: $i2,
             exec_time: std::time::Instant,
-            caller: String,
-            sender: Sender<(String, Duration)>,
+            caller: $crate::psql_connection::DbCaller,
+            sender: $crate::psql_connection::MetricSender,
         }
         impl Drop for $i<'_> {
             fn drop(&mut self) {
-                _ = self
-                    .sender
-                    .send((self.caller.clone(), self.exec_time.elapsed()))
-                    .inspect_err(|e| error!("failed to send metrics: {e}"));
+                self.sender.send(self.caller, self.exec_time.elapsed());
             }
         }
         impl<'a> Deref for $i<'a> {
-            type Target = &'a $i2;
+            type Target = $i2;
             fn deref(&self) -> &Self::Target {
                 &self.db
             }
@@ -69,4 +114,4 @@ macro_rules! impl_guard {
     };
 }
 
-impl_psql_driver!(UserAuthDriver);
+impl_psql_driver!(UserAuthDriver, JobQueueDriver, OutboxDriver, AuditLogDriver);
@@ -0,0 +1,110 @@
+use crate::psql_connection::PsqlConnection;
+use lib_shared::{instrument, warn};
+use sqlx::migrate::{Migrate, Migrator};
+use std::path::PathBuf;
+extern crate tracing;
+
+///every file in `lib-db/migrations`, embedded at compile time
+pub static MIGRATOR: Migrator = sqlx::migrate!();
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum MigrationState {
+    Applied,
+    Pending,
+    ///the embedded file was edited after it got applied
+    ChecksumMismatch,
+    ///applied in the database but no longer embedded in this build
+    Missing,
+}
+
+#[derive(Debug)]
+pub struct MigrationStatus {
+    pub version: i64,
+    pub description: String,
+    pub state: MigrationState,
+}
+
+impl PsqlConnection {
+    ///applies pending migrations, concurrent runners wait on the migrator's advisory lock.
+    ///fails without touching the schema if an applied migration was edited
+    #[instrument(skip(self))]
+    pub async fn migrate_up(&self) -> eyre::Result<()> {
+        MIGRATOR.run(&self.db).await?;
+        Ok(())
+    }
+
+    ///reverts the last `steps` applied migrations
+    #[instrument(skip(self))]
+    pub async fn migrate_down(&self, steps: usize) -> eyre::Result<()> {
+        let mut applied = self
+            .migration_status()
+            .await?
+            .into_iter()
+            .filter(|m| m.state != MigrationState::Pending)
+            .map(|m| m.version)
+            .collect::<Vec<_>>();
+        applied.sort_unstable();
+
+        let target = match applied.len().checked_sub(steps + 1) {
+            Some(i) => applied[i],
+            None => 0,
+        };
+        MIGRATOR.undo(&self.db, target).await?;
+        Ok(())
+    }
+
+    #[instrument(skip(self))]
+    pub async fn migration_status(&self) -> eyre::Result<Vec<MigrationStatus>> {
+        let mut conn = self.db.acquire().await?;
+        conn.ensure_migrations_table().await?;
+        let applied = conn.list_applied_migrations().await?;
+
+        let mut status = MIGRATOR
+            .iter()
+            .filter(|m| m.migration_type.is_up_migration())
+            .map(|m| {
+                let state = match applied.iter().find(|a| a.version == m.version) {
+                    Some(a) if a.checksum == m.checksum => MigrationState::Applied,
+                    Some(_) => MigrationState::ChecksumMismatch,
+                    None => MigrationState::Pending,
+                };
+                MigrationStatus {
+                    version: m.version,
+                    description: m.description.to_string(),
+                    state,
+                }
+            })
+            .collect::<Vec<_>>();
+
+        for a in applied
+            .iter()
+            .filter(|a| !MIGRATOR.version_exists(a.version))
+        {
+            warn!(
+                version = a.version,
+                "applied migration is missing from this build"
+            );
+            status.push(MigrationStatus {
+                version: a.version,
+                description: String::new(),
+                state: MigrationState::Missing,
+            });
+        }
+        status.sort_by_key(|m| m.version);
+        Ok(status)
+    }
+}
+
+///creates an empty `up`/`down` pair in `lib-db/migrations`, returns their paths
+pub fn create_migration(name: &str) -> eyre::Result<(PathBuf, PathBuf)> {
+    let dir = PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("migrations");
+    std::fs::create_dir_all(&dir)?;
+
+    let version = chrono::Utc::now().format("%Y%m%d%H%M%S");
+    let name = name.trim().replace([' ', '-'], "_").to_lowercase();
+    let up = dir.join(format!("{version}_{name}.up.sql"));
+    let down = dir.join(format!("{version}_{name}.down.sql"));
+    std::fs::write(&up, "-- Add up migration script here\n")?;
+    std::fs::write(&down, "-- Add down migration script here\n")?;
+    Ok((up, down))
+}
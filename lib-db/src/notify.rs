@@ -0,0 +1,23 @@
+use crate::psql_connection::{DbCaller, PsqlConnection};
+use sqlx::postgres::PgListener;
+
+impl PsqlConnection {
+    ///dedicated session receiving the `NOTIFY`s sent on `channel`, it reconnects by itself
+    ///but the notifications sent in between are lost, see [`PgListener::try_recv`]
+    pub async fn listen(&self, channel: &str) -> eyre::Result<PgListener> {
+        let mut listener = PgListener::connect_with(&self.db).await?;
+        listener.listen(channel).await?;
+        Ok(listener)
+    }
+
+    ///delivered to the listeners of every instance, including this one, once the current
+    ///transaction commits (right away outside of one)
+    pub async fn notify(&self, channel: &str, payload: &str) -> eyre::Result<()> {
+        sqlx::query("SELECT pg_notify($1, $2)")
+            .bind(channel)
+            .bind(payload)
+            .execute(*self.db_as(DbCaller::new("PsqlConnection", "notify")))
+            .await?;
+        Ok(())
+    }
+}
@@ -0,0 +1,155 @@
+use crate::OutboxDriver;
+use chrono::{DateTime, Utc};
+use lib_db_macros::db_driver;
+use lib_shared::instrument;
+use serde::Serialize;
+use sqlx::{FromRow, PgConnection};
+use std::time::Duration;
+extern crate tracing;
+
+///an event to be stored alongside business data, see [`OutboxDriver::append`]
+pub struct NewOutboxMessage {
+    pub event: &'static str,
+    pub payload: serde_json::Value,
+    ///consumers use it to drop duplicate deliveries
+    pub idempotency_key: String,
+}
+
+impl NewOutboxMessage {
+    pub fn new<T: Serialize>(
+        event: &'static str,
+        payload: &T,
+        idempotency_key: String,
+    ) -> eyre::Result<Self> {
+        Ok(Self {
+            event,
+            payload: serde_json::to_value(payload)?,
+            idempotency_key,
+        })
+    }
+}
+
+#[derive(FromRow, Debug)]
+pub struct OutboxMessage {
+    pub id: i64,
+    pub event: String,
+    pub payload: serde_json::Value,
+    pub idempotency_key: String,
+    pub attempts: i32,
+}
+
+#[db_driver]
+impl OutboxDriver {
+    ///must be called with the same transaction that writes the business data, e.x:
+    ///```ignore
+    ///self.connection.transaction(|tx| async move {
+    ///    sqlx::query("UPDATE users ...").execute(&mut *tx.conn().await?).await?;
+    ///    OutboxDriver::append(&mut *tx.conn().await?, &message).await?;
+    ///    Ok(())
+    ///}).await?;
+    ///```
+    ///returns false if a message with the same idempotency key already exists
+    #[instrument(skip_all, fields(event = message.event, key = message.idempotency_key))]
+    pub async fn append(conn: &mut PgConnection, message: &NewOutboxMessage) -> eyre::Result<bool> {
+        let result = sqlx::query(
+            "INSERT INTO outbox (event, payload, idempotency_key) VALUES ($1, $2, $3)
+             ON CONFLICT (idempotency_key) DO NOTHING",
+        )
+        .bind(message.event)
+        .bind(&message.payload)
+        .bind(&message.idempotency_key)
+        .execute(conn)
+        .await?;
+        Ok(result.rows_affected() > 0)
+    }
+
+    ///claims up to `limit` due messages for `lease`, in order. the claim is committed right away,
+    ///no lock is held while they're dispatched: concurrent relays skip claimed messages until the
+    ///lease runs out, then a crashed relay's messages are delivered again (at-least-once).
+    ///`attempts` counts this claim
+    #[instrument(skip(self))]
+    pub async fn claim(&self, limit: i64, lease: Duration) -> eyre::Result<Vec<OutboxMessage>> {
+        let mut messages: Vec<OutboxMessage> = sqlx::query_as(
+            "UPDATE outbox
+             SET claimed_until = now() + $2, attempts = attempts + 1
+             WHERE id IN (SELECT id
+                          FROM outbox
+                          WHERE dispatched_at IS NULL AND failed_at IS NULL
+                            AND next_attempt_at <= now()
+                            AND (claimed_until IS NULL OR claimed_until < now())
+                          ORDER BY id
+                          LIMIT $1 FOR UPDATE SKIP LOCKED)
+             RETURNING id, event, payload, idempotency_key, attempts",
+        )
+        .bind(limit)
+        .bind(lease)
+        .fetch_all(*self.connection.db())
+        .await?;
+        messages.sort_by_key(|m| m.id);
+        Ok(messages)
+    }
+
+    #[instrument(skip(self))]
+    pub async fn mark_dispatched(&self, ids: &[i64]) -> eyre::Result<()> {
+        sqlx::query(
+            "UPDATE outbox SET dispatched_at = now(), claimed_until = NULL WHERE id = ANY($1)",
+        )
+        .bind(ids)
+        .execute(*self.connection.db())
+        .await?;
+        Ok(())
+    }
+
+    #[instrument(skip(self))]
+    pub async fn retry(
+        &self,
+        id: i64,
+        error: &str,
+        next_attempt_at: DateTime<Utc>,
+    ) -> eyre::Result<()> {
+        sqlx::query(
+            "UPDATE outbox SET claimed_until = NULL, last_error = $2, next_attempt_at = $3 WHERE id = $1",
+        )
+        .bind(id)
+        .bind(error)
+        .bind(next_attempt_at)
+        .execute(*self.connection.db())
+        .await?;
+        Ok(())
+    }
+
+    ///gives up on the message, it won't be claimed again
+    #[instrument(skip(self))]
+    pub async fn bury(&self, id: i64, error: &str) -> eyre::Result<()> {
+        sqlx::query(
+            "UPDATE outbox SET claimed_until = NULL, last_error = $2, failed_at = now() WHERE id = $1",
+        )
+        .bind(id)
+        .bind(error)
+        .execute(*self.connection.db())
+        .await?;
+        Ok(())
+    }
+
+    ///hands claimed messages back without counting the attempt, for the ones a relay had no time
+    ///left to dispatch
+    #[instrument(skip(self))]
+    pub async fn release(&self, ids: &[i64]) -> eyre::Result<()> {
+        sqlx::query(
+            "UPDATE outbox SET claimed_until = NULL, attempts = attempts - 1 WHERE id = ANY($1)",
+        )
+        .bind(ids)
+        .execute(*self.connection.db())
+        .await?;
+        Ok(())
+    }
+
+    #[instrument(skip(self))]
+    pub async fn purge_dispatched(&self, older_than: Duration) -> eyre::Result<u64> {
+        let result = sqlx::query("DELETE FROM outbox WHERE dispatched_at < now() - $1")
+            .bind(older_than)
+            .execute(*self.connection.db())
+            .await?;
+        Ok(result.rows_affected())
+    }
+}
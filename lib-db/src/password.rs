@@ -0,0 +1,71 @@
+use argon2::Argon2;
+use argon2::password_hash::{PasswordHash, PasswordHasher, PasswordVerifier, SaltString};
+use std::sync::LazyLock;
+
+const SALT_LEN: usize = 16;
+
+///verified when the identity doesn't exist, so unknown identities take as long as wrong passwords
+static DUMMY: LazyLock<String> =
+    LazyLock::new(|| hash_password("").expect("the default argon2 parameters are valid"));
+
+///the format of `users.password_hash`: an argon2id PHC string
+///(`$argon2id$v=19$m=..,t=..,p=..$<salt>$<hash>`) with the default parameters of the crate
+pub fn hash_password(pwd: &str) -> eyre::Result<String> {
+    let salt = SaltString::encode_b64(&rand::random::<[u8; SALT_LEN]>())?;
+    Ok(Argon2::default()
+        .hash_password(pwd.as_bytes(), &salt)?
+        .to_string())
+}
+
+///uses the algorithm and parameters of `stored`, so older hashes keep verifying once the
+///defaults change. a malformed `stored` hash never matches
+pub fn verify_password(pwd: &str, stored: &str) -> bool {
+    PasswordHash::new(stored).is_ok_and(|hash| {
+        Argon2::default()
+            .verify_password(pwd.as_bytes(), &hash)
+            .is_ok()
+    })
+}
+
+///burns the time of a verification, for the identities that don't exist
+pub fn verify_dummy(pwd: &str) {
+    verify_password(pwd, &DUMMY);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn verifies_its_own_hashes() {
+        let stored = hash_password("hunter2").unwrap();
+        assert!(stored.starts_with("$argon2id$"));
+        assert!(verify_password("hunter2", &stored));
+        assert!(!verify_password("hunter3", &stored));
+        assert!(!verify_password("", &stored));
+    }
+
+    #[test]
+    fn salts_are_random() {
+        let (a, b) = (
+            hash_password("hunter2").unwrap(),
+            hash_password("hunter2").unwrap(),
+        );
+        assert_ne!(a, b);
+        assert!(verify_password("hunter2", &a) && verify_password("hunter2", &b));
+    }
+
+    #[test]
+    fn rejects_malformed_hashes() {
+        let stored = hash_password("hunter2").unwrap();
+        for malformed in [
+            "",
+            "hunter2",
+            &stored.replace("argon2id", "md5"),
+            &format!("{stored}$"),
+            &stored[..stored.len() - 2],
+        ] {
+            assert!(!verify_password("hunter2", malformed), "{malformed}");
+        }
+    }
+}
@@ -0,0 +1,333 @@
+use crate::audit::{INCLUDE_DELETED_SETTING, include_deleted};
+use crate::psql_connection::PsqlConnection;
+use crate::tenant::{TENANT_SETTING, current_tenant};
+use lib_shared::env_service::PsqlPoolEnv;
+use lib_shared::metrics::Metrics;
+use lib_shared::{Res, info, instrument, warn};
+use opentelemetry::metrics::{Counter, Histogram};
+use opentelemetry::{KeyValue, Value};
+use sea_orm::{ConnAcquireErr, DbErr};
+use sqlx::postgres::{PgConnectOptions, PgPoolOptions};
+use sqlx::{PgConnection, PgPool};
+use std::str::FromStr;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::task::JoinHandle;
+use tokio::time::MissedTickBehavior;
+extern crate tracing;
+
+const MAX_BACKOFF: Duration = Duration::from_secs(30);
+pub(crate) const PRIMARY: &str = "primary";
+
+tokio::task_local! {
+    ///when the current query started waiting for a connection, and the pool it waits on
+    static ACQUIRE: (Instant, Arc<AcquireTimer>);
+}
+
+///samples how long the guards of one pool wait for a connection. the hooks of [`pool_options`]
+///record the wait, so only real acquisitions are measured
+#[derive(Debug)]
+pub(crate) struct AcquireTimer {
+    label: [KeyValue; 1],
+    duration: Arc<Histogram<f64>>,
+    timeouts: Arc<Counter<u64>>,
+}
+
+impl AcquireTimer {
+    pub(crate) fn new(metrics: &Metrics, pool: impl Into<Value>) -> Arc<Self> {
+        Arc::new(Self {
+            label: [KeyValue::new("pool", pool)],
+            duration: metrics.db_pool_acquire_duration.clone(),
+            timeouts: metrics.db_pool_acquire_timeouts.clone(),
+        })
+    }
+
+    ///runs `f`, the connection it acquires from the pool records the wait
+    pub(crate) async fn time<T, E: AcquireError>(
+        self: &Arc<Self>,
+        f: impl Future<Output = Result<T, E>>,
+    ) -> Result<T, E> {
+        let result = ACQUIRE.scope((Instant::now(), self.clone()), f).await;
+        if let Err(e) = &result {
+            self.record_error(e);
+        }
+        result
+    }
+
+    ///[`AcquireTimer::time`] for one poll of a stream that started waiting at `start`
+    pub(crate) fn poll<R>(self: &Arc<Self>, start: Instant, poll: impl FnOnce() -> R) -> R {
+        ACQUIRE.sync_scope((start, self.clone()), poll)
+    }
+
+    pub(crate) fn record_error(&self, e: &impl AcquireError) {
+        if e.timed_out() {
+            self.timeouts.add(1, &self.label);
+        }
+    }
+}
+
+pub(crate) trait AcquireError {
+    fn timed_out(&self) -> bool;
+}
+
+impl AcquireError for sqlx::Error {
+    fn timed_out(&self) -> bool {
+        matches!(self, sqlx::Error::PoolTimedOut)
+    }
+}
+
+impl AcquireError for DbErr {
+    fn timed_out(&self) -> bool {
+        matches!(self, DbErr::ConnectionAcquire(ConnAcquireErr::Timeout))
+    }
+}
+
+///called by the hooks once a connection is handed out, outside of an [`AcquireTimer`] (e.g. the
+///connections the pool opens on its own) there is no wait to record
+fn record_acquire() {
+    _ = ACQUIRE.try_with(|(start, timer)| {
+        timer
+            .duration
+            .record(start.elapsed().as_secs_f64(), &timer.label)
+    });
+}
+
+///pool options for `config`, every new connection gets the session settings before it is handed out
+///and every connection gets the scope of the task acquiring it, see [`with_tenant`](crate::tenant::with_tenant)
+///and [`with_deleted`](crate::audit::with_deleted)
+pub fn pool_options(config: &PsqlPoolEnv) -> PgPoolOptions {
+    let settings = session_settings(config);
+    PgPoolOptions::new()
+        .max_connections(config.max_connections)
+        .min_connections(config.min_connections)
+        .acquire_timeout(config.acquire_timeout)
+        .idle_timeout(config.idle_timeout)
+        .max_lifetime(config.max_lifetime)
+        .after_connect(move |conn, _meta| {
+            let settings = settings.clone();
+            Box::pin(async move {
+                for (name, value) in settings {
+                    sqlx::query("SELECT set_config($1, $2, false)")
+                        .bind(name)
+                        .bind(value)
+                        .execute(&mut *conn)
+                        .await?;
+                }
+                //connections opened by an `acquire` skip `before_acquire`
+                apply_scope(conn).await?;
+                record_acquire();
+                Ok(())
+            })
+        })
+        .before_acquire(|conn, _meta| {
+            Box::pin(async move {
+                apply_scope(conn).await?;
+                record_acquire();
+                Ok(true)
+            })
+        })
+}
+
+///called whenever a connection is handed out, so a connection never keeps the tenant or the
+///visibility of the deleted rows of its previous user. `SET LOCAL` wouldn't cover the queries
+///running outside a transaction
+async fn apply_scope(conn: &mut PgConnection) -> Result<(), sqlx::Error> {
+    let include_deleted = match include_deleted() {
+        true => "on",
+        false => "off",
+    };
+    sqlx::query("SELECT set_config($1, $2, false), set_config($3, $4, false)")
+        .bind(TENANT_SETTING)
+        .bind(current_tenant().unwrap_or_default())
+        .bind(INCLUDE_DELETED_SETTING)
+        .bind(include_deleted)
+        .execute(conn)
+        .await?;
+    Ok(())
+}
+
+pub fn connect_options(url: &str, config: &PsqlPoolEnv) -> eyre::Result<PgConnectOptions> {
+    Ok(PgConnectOptions::from_str(url)?.statement_cache_capacity(config.statement_cache_capacity))
+}
+
+///connects to the primary, retrying with an exponential backoff so the app survives the db
+///starting after it
+#[instrument(skip_all)]
+pub async fn connect_with_retry(
+    url: &str,
+    config: &PsqlPoolEnv,
+    metrics: &Metrics,
+) -> eyre::Result<PgPool> {
+    let options = connect_options(url, config)?;
+    let mut backoff = config.connect_backoff;
+    let mut attempt = 0;
+    loop {
+        attempt += 1;
+        match pool_options(config).connect_with(options.clone()).await {
+            Ok(pool) => {
+                info!(attempt, "connected to postgres");
+                return Ok(pool);
+            }
+            Err(e) if attempt < config.connect_retries => {
+                metrics
+                    .db_pool_connection_errors
+                    .add(1, &[KeyValue::new("pool", PRIMARY)]);
+                warn!(
+                    attempt,
+                    error = e.to_string(),
+                    "failed to connect to postgres, retrying in {backoff:?}"
+                );
+                tokio::time::sleep(backoff).await;
+                backoff = (backoff * 2).min(MAX_BACKOFF);
+            }
+            Err(e) => return Err(e.into()),
+        }
+    }
+}
+
+impl PsqlConnection {
+    ///records the size of every pool, the wait for a connection is sampled by the acquisitions
+    ///themselves (see [`pool_options`])
+    pub fn run_pool_metric_provider(&self, interval: Duration) -> JoinHandle<Res> {
+        let slf = self.clone();
+        tokio::spawn(async move {
+            let mut ticker = tokio::time::interval(interval);
+            ticker.set_missed_tick_behavior(MissedTickBehavior::Skip);
+            loop {
+                ticker.tick().await;
+                let pools = std::iter::once((PRIMARY.to_owned(), &slf.db))
+                    .chain(slf.replicas.pools().map(|(url, db)| (url.to_owned(), db)));
+                for (name, pool) in pools {
+                    let label = [KeyValue::new("pool", name)];
+                    slf.metrics.db_pool_size.record(pool.size() as u64, &label);
+                    slf.metrics
+                        .db_pool_idle
+                        .record(pool.num_idle() as u64, &label);
+                }
+            }
+        })
+    }
+}
+
+fn session_settings(config: &PsqlPoolEnv) -> Vec<(&'static str, String)> {
+    let mut settings = vec![("application_name", config.application_name.clone())];
+    if let Some(search_path) = &config.search_path {
+        settings.push(("search_path", search_path.clone()));
+    }
+    if let Some(timeout) = config.statement_timeout {
+        settings.push(("statement_timeout", timeout.as_millis().to_string()));
+    }
+    settings
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use lib_shared::metrics::testing::MetricsReader;
+    use sea_orm::ConnectionTrait;
+    use sqlx::Row;
+
+    fn config(acquire_timeout: Duration) -> PsqlPoolEnv {
+        PsqlPoolEnv {
+            max_connections: 1,
+            min_connections: 0,
+            acquire_timeout,
+            idle_timeout: None,
+            max_lifetime: None,
+            statement_cache_capacity: 100,
+            application_name: "pool-test".to_owned(),
+            search_path: None,
+            statement_timeout: None,
+            slow_query_threshold: None,
+            connect_retries: 1,
+            connect_backoff: Duration::from_millis(10),
+        }
+    }
+
+    fn acquisitions(reader: &MetricsReader) -> u64 {
+        reader.count(
+            "db.pool.acquire.duration",
+            &[KeyValue::new("pool", PRIMARY)],
+        )
+    }
+
+    #[tokio::test]
+    async fn the_metric_provider_never_acquires() {
+        let (metrics, reader) = MetricsReader::new();
+        let options = connect_options(
+            "postgres://localhost:1/none",
+            &config(Duration::from_secs(1)),
+        );
+        let pool =
+            pool_options(&config(Duration::from_secs(1))).connect_lazy_with(options.unwrap());
+        let connection = PsqlConnection::new(pool.clone(), metrics);
+
+        let provider = connection.run_pool_metric_provider(Duration::from_millis(5));
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        provider.abort();
+        assert_eq!(pool.size(), 0);
+        assert_eq!(acquisitions(&reader), 0);
+        assert_eq!(
+            reader.sum(
+                "db.pool.connection.errors",
+                &[KeyValue::new("pool", PRIMARY)]
+            ),
+            0
+        );
+    }
+
+    #[tokio::test]
+    #[ignore = "needs a postgres server in DATABASE_URL"]
+    async fn the_acquisitions_of_the_guards_are_sampled() {
+        let url = std::env::var("DATABASE_URL").unwrap();
+        let config = config(Duration::from_millis(200));
+        let (metrics, reader) = MetricsReader::new();
+        let pool = pool_options(&config).connect_lazy_with(connect_options(&url, &config).unwrap());
+        let connection = PsqlConnection::new(pool.clone(), metrics);
+
+        //opens the connection (`after_connect`), then reuses it (`before_acquire`)
+        for _ in 0..2 {
+            sqlx::query("SELECT 1")
+                .execute(*connection.db())
+                .await
+                .unwrap();
+        }
+        let rows = sqlx::query("SELECT generate_series(1, 3) AS n")
+            .fetch_all(*connection.db())
+            .await
+            .unwrap();
+        assert_eq!(rows.last().unwrap().get::<i32, _>("n"), 3);
+        connection
+            .orm()
+            .execute_unprepared("SELECT 1")
+            .await
+            .unwrap();
+        connection
+            .transaction(|tx| async move {
+                sqlx::query("SELECT 1")
+                    .execute(&mut *tx.conn().await?)
+                    .await?;
+                Ok(())
+            })
+            .await
+            .unwrap();
+        assert_eq!(acquisitions(&reader), 5);
+
+        //nothing to sample outside of a guard
+        drop(pool.acquire().await.unwrap());
+        assert_eq!(acquisitions(&reader), 5);
+
+        let held = pool.acquire().await.unwrap();
+        let timed_out = sqlx::query("SELECT 1").execute(*connection.db()).await;
+        assert!(matches!(timed_out, Err(sqlx::Error::PoolTimedOut)));
+        let timed_out = connection.orm().execute_unprepared("SELECT 1").await;
+        assert!(timed_out.is_err());
+        drop(held);
+        let timeouts = reader.sum(
+            "db.pool.acquire.timeouts",
+            &[KeyValue::new("pool", PRIMARY)],
+        );
+        assert_eq!(timeouts, 2);
+        assert_eq!(acquisitions(&reader), 5);
+    }
+}
@@ -1,103 +1,300 @@
 use crate::impl_guard;
-use crossbeam_channel::{Receiver, Sender};
+use crate::pool::{AcquireTimer, PRIMARY};
+use crate::query_trace::{TracedOrm, TracedPool};
+use crate::replicas::{Replicas, stick_to_primary};
+use hashbrown::HashMap;
 use lib_shared::metrics::Metrics;
-use lib_shared::{Res, error, warn};
+use lib_shared::{Res, info, warn};
 use opentelemetry::KeyValue;
-use regex_macro::regex;
+use opentelemetry::metrics::Counter;
 use sea_orm::DatabaseConnection;
 use std::ops::Deref;
+use std::panic::Location;
+use std::sync::{Arc, Mutex};
 use std::time::{Duration, Instant};
+use tokio::sync::mpsc;
+use tokio::sync::mpsc::error::TrySendError;
 use tokio::task::JoinHandle;
+use tokio_util::sync::CancellationToken;
+
+///guards dropped while the channel is full are counted as dropped instead of blocking
+const METRIC_CHANNEL_CAPACITY: usize = 8192;
+const METRIC_BATCH_SIZE: usize = 512;
+
+type MetricReceiver = mpsc::Receiver<(DbCaller, Duration)>;
 
 ///wrapper around PgPool to collect metrics
 #[derive(Clone)]
 pub struct PsqlConnection {
-    db: sqlx::PgPool,
+    pub(crate) db: sqlx::PgPool,
     orm: DatabaseConnection,
+    pub(crate) acquire: Arc<AcquireTimer>,
+    pub(crate) slow_query: Option<Duration>,
+    pub(crate) replicas: Arc<Replicas>,
     pub metrics: Metrics,
-    pub recv: Receiver<(String, Duration)>,
-    pub sender: Sender<(String, Duration)>,
+    recv: Arc<Mutex<Option<MetricReceiver>>>,
+    pub(crate) sender: MetricSender,
+    shutdown: CancellationToken,
+}
+
+///sending side of the db metric pipeline, never blocks
+#[derive(Clone)]
+pub struct MetricSender {
+    tx: mpsc::Sender<(DbCaller, Duration)>,
+    dropped: Arc<Counter<u64>>,
+}
+
+impl MetricSender {
+    pub fn send(&self, caller: DbCaller, elapsed: Duration) {
+        if let Err(TrySendError::Full(_)) = self.tx.try_send((caller, elapsed)) {
+            self.dropped.add(1, &[]);
+        }
+    }
+}
+
+///who acquired a guard, used as the labels of the db metrics
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum DbCaller {
+    ///set by `#[db_driver]` on the driver methods
+    Named {
+        driver: &'static str,
+        method: &'static str,
+    },
+    ///fallback for calls outside of a `#[db_driver]` impl
+    Location(&'static Location<'static>),
+}
+
+impl DbCaller {
+    pub const fn new(driver: &'static str, method: &'static str) -> Self {
+        Self::Named { driver, method }
+    }
+
+    fn labels(&self) -> [KeyValue; 2] {
+        match self {
+            Self::Named { driver, method } => [
+                KeyValue::new("function", *method),
+                KeyValue::new("module", *driver),
+            ],
+            Self::Location(location) => [
+                KeyValue::new("function", location.line().to_string()),
+                KeyValue::new("module", location.file()),
+            ],
+        }
+    }
 }
 
-impl_guard!(PgPoolGuard, sqlx::PgPool);
-impl_guard!(OrmGuard, DatabaseConnection);
+impl_guard!(PgPoolGuard, TracedPool<'a>);
+impl_guard!(OrmGuard, TracedOrm<'a, DatabaseConnection>);
 
 impl PsqlConnection {
     #[inline]
     pub fn new(db: sqlx::PgPool, metrics: Metrics) -> Self {
-        let (s, r) = crossbeam_channel::unbounded::<(String, Duration)>();
+        let (tx, rx) = mpsc::channel(METRIC_CHANNEL_CAPACITY);
+        let sender = MetricSender {
+            tx,
+            dropped: metrics.db_metric_dropped.clone(),
+        };
         let orm = DatabaseConnection::from(db.clone());
         Self {
             db,
             orm,
+            acquire: AcquireTimer::new(&metrics, PRIMARY),
+            slow_query: None,
+            replicas: Arc::default(),
             metrics,
-            sender: s,
-            recv: r,
+            sender,
+            recv: Arc::new(Mutex::new(Some(rx))),
+            shutdown: CancellationToken::new(),
         }
     }
 
+    pub fn with_replicas(self, replicas: Replicas) -> Self {
+        Self {
+            replicas: Arc::new(replicas),
+            ..self
+        }
+    }
+
+    ///queries slower than `threshold` are logged at warn level
+    pub fn with_slow_query_threshold(self, threshold: Option<Duration>) -> Self {
+        Self {
+            slow_query: threshold,
+            ..self
+        }
+    }
+
+    ///exports the guard metrics in batches until [`PsqlConnection::shutdown_metrics`], only one
+    ///provider can run per connection
     pub fn run_metric_provider(&self) -> JoinHandle<Res> {
-        let rx = self.recv.clone();
+        let rx = self
+            .recv
+            .lock()
+            .expect("metric receiver lock poisoned")
+            .take();
         let metrics = self.metrics.clone();
+        let shutdown = self.shutdown.clone();
         tokio::spawn(async move {
-            let regex = regex!(
-                r"(?:[a-zA-Z0-9_]+::)(?P<mod>[a-zA-Z0-9_]+)::<.*?>::(?P<func>[a-zA-Z0-9_]+)(?:::(?:\{\{closure\}\})?)?"
-            );
-            while let Ok((stack, duration)) = rx.recv() {
-                let Some(captures) = regex.captures(&stack) else {
-                    warn!(stack, "unknown caller");
-                    continue;
-                };
-                let md = captures.name("mod").map(|a| a.as_str()).unwrap_or_default();
-                let func = captures
-                    .name("func")
-                    .map(|a| a.as_str())
-                    .unwrap_or_default();
-                if md.is_empty() || func.is_empty() {
-                    warn!(stack, func, md, "unknown caller");
-                    continue;
+            let Some(mut rx) = rx else {
+                warn!("psql metric collector is already running");
+                return Ok(());
+            };
+            let mut batch = Vec::with_capacity(METRIC_BATCH_SIZE);
+            loop {
+                tokio::select! {
+                    received = rx.recv_many(&mut batch, METRIC_BATCH_SIZE) => {
+                        if received == 0 {
+                            break;
+                        }
+                        export(&metrics, &mut batch);
+                    }
+                    _ = shutdown.cancelled() => {
+                        //flush what was sent before the shutdown
+                        rx.close();
+                        while rx.recv_many(&mut batch, METRIC_BATCH_SIZE).await > 0 {
+                            export(&metrics, &mut batch);
+                        }
+                        break;
+                    }
                 }
-                let labels = [
-                    KeyValue::new("function", func.to_string()),
-                    KeyValue::new("module", md.to_string()),
-                ];
-                metrics.db_call_count.add(1, &labels);
-                metrics.db_exec_time.record(duration.as_secs_f64(), &labels);
             }
-            warn!("closing psql metric collector");
+            info!("closing psql metric collector");
             eyre::Result::<()>::Ok(())
         })
     }
 
-    pub fn db(&self) -> PgPoolGuard {
-        let capture = std::backtrace::Backtrace::capture().to_string();
-        let stack = trim_backtrace(&capture);
-        let outer_function = stack.get(1).unwrap_or(&"unknown caller");
+    ///stops the metric provider once the pending metrics are exported
+    pub fn shutdown_metrics(&self) {
+        self.shutdown.cancel();
+    }
+
+    #[track_caller]
+    pub fn db(&self) -> PgPoolGuard<'_> {
+        self.db_as(DbCaller::Location(Location::caller()))
+    }
+
+    pub fn db_as(&self, caller: DbCaller) -> PgPoolGuard<'_> {
+        stick_to_primary();
+        self.guard(&self.db, &self.acquire, caller)
+    }
+
+    #[track_caller]
+    pub fn orm(&self) -> OrmGuard<'_> {
+        self.orm_as(DbCaller::Location(Location::caller()))
+    }
+
+    pub fn orm_as(&self, caller: DbCaller) -> OrmGuard<'_> {
+        stick_to_primary();
+        self.orm_guard(&self.orm, &self.acquire, caller)
+    }
+
+    pub(crate) fn guard<'a>(
+        &self,
+        pool: &'a sqlx::PgPool,
+        acquire: &'a Arc<AcquireTimer>,
+        caller: DbCaller,
+    ) -> PgPoolGuard<'a> {
         PgPoolGuard {
-            db: &self.db,
+            db: TracedPool {
+                pool,
+                acquire,
+                caller,
+                slow_query: self.slow_query,
+            },
             sender: self.sender.clone(),
-            caller: ToString::to_string(&outer_function),
+            caller,
             exec_time: Instant::now(),
         }
     }
 
-    pub fn orm(&self) -> OrmGuard {
-        let capture = std::backtrace::Backtrace::capture().to_string();
-        let stack = trim_backtrace(&capture);
-        let outer_function = stack.get(1).unwrap_or(&"unknown caller");
+    pub(crate) fn orm_guard<'a>(
+        &self,
+        db: &'a DatabaseConnection,
+        acquire: &'a Arc<AcquireTimer>,
+        caller: DbCaller,
+    ) -> OrmGuard<'a> {
         OrmGuard {
-            db: &self.orm,
+            db: TracedOrm {
+                db,
+                acquire: Some(acquire),
+                caller,
+                slow_query: self.slow_query,
+            },
             sender: self.sender.clone(),
-            caller: ToString::to_string(&outer_function),
+            caller,
             exec_time: Instant::now(),
         }
     }
 }
 
-fn trim_backtrace(s: &str) -> Vec<&str> {
-    let splitter = regex!(r"(^|\n)\s+\d+:");
-    splitter
-        .split(s)
-        .filter(|frame| !frame.contains("/rustc/") && !frame.is_empty())
-        .collect()
+///one `add` per caller and batch, the histogram still gets every duration
+fn export(metrics: &Metrics, batch: &mut Vec<(DbCaller, Duration)>) {
+    let mut calls = HashMap::<DbCaller, Vec<Duration>>::new();
+    for (caller, duration) in batch.drain(..) {
+        calls.entry(caller).or_default().push(duration);
+    }
+    for (caller, durations) in calls {
+        let labels = caller.labels();
+        metrics.db_call_count.add(durations.len() as u64, &labels);
+        for duration in durations {
+            metrics.db_exec_time.record(duration.as_secs_f64(), &labels);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use lib_shared::metrics::testing::MetricsReader;
+    use sqlx::postgres::PgPoolOptions;
+
+    const CALLER: DbCaller = DbCaller::new("TestDriver", "query");
+
+    fn connection() -> (PsqlConnection, MetricsReader) {
+        let (metrics, reader) = MetricsReader::new();
+        let db = PgPoolOptions::new()
+            .connect_lazy("postgres://localhost:1/none")
+            .unwrap();
+        (PsqlConnection::new(db, metrics), reader)
+    }
+
+    fn calls(reader: &MetricsReader, caller: DbCaller) -> u64 {
+        reader.sum("db.calls.count", &caller.labels())
+    }
+
+    #[tokio::test]
+    async fn a_full_channel_counts_the_dropped_samples() {
+        let (connection, reader) = connection();
+        //nothing reads the channel yet
+        for _ in 0..METRIC_CHANNEL_CAPACITY + 5 {
+            connection.sender.send(CALLER, Duration::from_millis(1));
+        }
+        assert_eq!(reader.sum("db.metrics.dropped", &[]), 5);
+
+        let handle = connection.run_metric_provider();
+        connection.shutdown_metrics();
+        handle.await.unwrap().unwrap();
+        assert_eq!(calls(&reader, CALLER), METRIC_CHANNEL_CAPACITY as u64);
+        assert_eq!(
+            reader.count("db.call.duration", &CALLER.labels()),
+            METRIC_CHANNEL_CAPACITY as u64
+        );
+    }
+
+    #[tokio::test]
+    async fn shutdown_flushes_the_pending_samples() {
+        let (connection, reader) = connection();
+        let other = DbCaller::new("TestDriver", "other");
+        drop(connection.db_as(CALLER));
+        drop(connection.orm_as(CALLER));
+        drop(connection.db_as(other));
+        //sent after the shutdown was requested, before the provider drained the channel
+        connection.shutdown_metrics();
+        drop(connection.db_as(other));
+
+        connection.run_metric_provider().await.unwrap().unwrap();
+        assert_eq!(calls(&reader, CALLER), 2);
+        assert_eq!(calls(&reader, other), 2);
+        //a second provider has nothing to read
+        connection.run_metric_provider().await.unwrap().unwrap();
+    }
 }
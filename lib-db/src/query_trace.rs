@@ -0,0 +1,556 @@
+use crate::pool::AcquireTimer;
+use crate::psql_connection::DbCaller;
+use futures_util::StreamExt;
+use futures_util::future::BoxFuture;
+use futures_util::stream::{BoxStream, poll_fn};
+use lib_shared::{Instrument, Span, field, info_span, warn};
+use sea_orm::{ConnectionTrait, DbBackend, DbErr, ExecResult, QueryResult, Statement};
+use sqlx::postgres::{PgQueryResult, PgRow, PgStatement, PgTypeInfo};
+use sqlx::{Describe, Either, Execute, Executor, PgPool, Postgres};
+use std::fmt::{Debug, Display, Formatter};
+use std::ops::Deref;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+extern crate tracing;
+
+const MAX_STATEMENT_LEN: usize = 2048;
+
+///what `*connection.db()` hands out, runs every query inside a `db.query` span
+#[derive(Clone, Copy, Debug)]
+pub struct TracedPool<'a> {
+    pub(crate) pool: &'a PgPool,
+    pub(crate) acquire: &'a Arc<AcquireTimer>,
+    pub(crate) caller: DbCaller,
+    pub(crate) slow_query: Option<Duration>,
+}
+
+impl Deref for TracedPool<'_> {
+    type Target = PgPool;
+    fn deref(&self) -> &Self::Target {
+        self.pool
+    }
+}
+
+impl<'a> Executor<'a> for TracedPool<'a> {
+    type Database = Postgres;
+
+    fn fetch_many<'e, 'q: 'e, E>(
+        self,
+        query: E,
+    ) -> BoxStream<'e, Result<Either<PgQueryResult, PgRow>, sqlx::Error>>
+    where
+        'a: 'e,
+        E: 'q + Execute<'q, Postgres>,
+    {
+        let mut trace = QueryTrace::start(query.sql(), self.caller.to_string(), self.slow_query);
+        let span = trace.span.clone();
+        let (acquire, start) = (self.acquire.clone(), Instant::now());
+        let mut steps = self.pool.fetch_many(query);
+        //polled inside the span, like an instrumented future
+        poll_fn(move |cx| {
+            let _entered = span.enter();
+            let step = acquire.poll(start, || steps.poll_next_unpin(cx));
+            if let std::task::Poll::Ready(Some(step)) = &step {
+                if let Err(e) = step {
+                    acquire.record_error(e);
+                }
+                trace.record_step(step);
+            }
+            step
+        })
+        .boxed()
+    }
+
+    fn fetch_optional<'e, 'q: 'e, E>(
+        self,
+        query: E,
+    ) -> BoxFuture<'e, Result<Option<PgRow>, sqlx::Error>>
+    where
+        'a: 'e,
+        E: 'q + Execute<'q, Postgres>,
+    {
+        let mut trace = QueryTrace::start(query.sql(), self.caller.to_string(), self.slow_query);
+        let fetch = self
+            .acquire
+            .time(self.pool.fetch_optional(query))
+            .instrument(trace.span.clone());
+        Box::pin(async move {
+            let row = fetch.await;
+            trace.record_row(&row);
+            row
+        })
+    }
+
+    fn prepare_with<'e, 'q: 'e>(
+        self,
+        sql: &'q str,
+        parameters: &'e [PgTypeInfo],
+    ) -> BoxFuture<'e, Result<PgStatement<'q>, sqlx::Error>>
+    where
+        'a: 'e,
+    {
+        Box::pin(self.acquire.time(self.pool.prepare_with(sql, parameters)))
+    }
+
+    fn describe<'e, 'q: 'e>(
+        self,
+        sql: &'q str,
+    ) -> BoxFuture<'e, Result<Describe<Postgres>, sqlx::Error>>
+    where
+        'a: 'e,
+    {
+        Box::pin(self.acquire.time(self.pool.describe(sql)))
+    }
+}
+
+///what `*connection.orm()` and `*tx.orm()` hand out, runs every seaorm query inside a `db.query`
+///span like [`TracedPool`]
+#[derive(Clone, Copy, Debug)]
+pub struct TracedOrm<'a, C> {
+    pub(crate) db: &'a C,
+    ///`None` on a transaction, which already holds its connection
+    pub(crate) acquire: Option<&'a Arc<AcquireTimer>>,
+    pub(crate) caller: DbCaller,
+    pub(crate) slow_query: Option<Duration>,
+}
+
+impl<C> TracedOrm<'_, C> {
+    fn start(&self, stmt: &Statement) -> QueryTrace {
+        let mut trace = QueryTrace::start(&stmt.sql, self.caller.to_string(), self.slow_query);
+        trace.parameters = stmt.values.as_ref().map_or(0, |v| v.0.len());
+        trace
+    }
+
+    async fn timed<T>(&self, f: impl Future<Output = Result<T, DbErr>>) -> Result<T, DbErr> {
+        match self.acquire {
+            Some(acquire) => acquire.time(f).await,
+            None => f.await,
+        }
+    }
+}
+
+#[async_trait::async_trait]
+impl<C: ConnectionTrait + Sync> ConnectionTrait for TracedOrm<'_, C> {
+    fn get_database_backend(&self) -> DbBackend {
+        self.db.get_database_backend()
+    }
+
+    async fn execute(&self, stmt: Statement) -> Result<ExecResult, DbErr> {
+        let mut trace = self.start(&stmt);
+        let result = self
+            .timed(self.db.execute(stmt))
+            .instrument(trace.span.clone())
+            .await;
+        trace.record_result(&result, ExecResult::rows_affected);
+        result
+    }
+
+    async fn execute_unprepared(&self, sql: &str) -> Result<ExecResult, DbErr> {
+        let mut trace = QueryTrace::start(sql, self.caller.to_string(), self.slow_query);
+        let result = self
+            .timed(self.db.execute_unprepared(sql))
+            .instrument(trace.span.clone())
+            .await;
+        trace.record_result(&result, ExecResult::rows_affected);
+        result
+    }
+
+    async fn query_one(&self, stmt: Statement) -> Result<Option<QueryResult>, DbErr> {
+        let mut trace = self.start(&stmt);
+        let result = self
+            .timed(self.db.query_one(stmt))
+            .instrument(trace.span.clone())
+            .await;
+        trace.record_result(&result, |row| row.is_some() as u64);
+        result
+    }
+
+    async fn query_all(&self, stmt: Statement) -> Result<Vec<QueryResult>, DbErr> {
+        let mut trace = self.start(&stmt);
+        let result = self
+            .timed(self.db.query_all(stmt))
+            .instrument(trace.span.clone())
+            .await;
+        trace.record_result(&result, |rows| rows.len() as u64);
+        result
+    }
+
+    fn support_returning(&self) -> bool {
+        self.db.support_returning()
+    }
+
+    fn is_mock_connection(&self) -> bool {
+        self.db.is_mock_connection()
+    }
+}
+
+struct QueryTrace {
+    span: Span,
+    statement: String,
+    caller: String,
+    parameters: usize,
+    rows: u64,
+    failed: bool,
+    start: Instant,
+    slow_query: Option<Duration>,
+}
+
+impl QueryTrace {
+    fn start(sql: &str, caller: String, slow_query: Option<Duration>) -> Self {
+        let statement = sanitize(sql);
+        let operation = statement
+            .split_whitespace()
+            .next()
+            .unwrap_or_default()
+            .to_uppercase();
+        let span = info_span!(
+            "db.query",
+            otel.name = operation,
+            otel.kind = "client",
+            otel.status_code = field::Empty,
+            db.system = "postgresql",
+            db.operation = operation,
+            db.statement = statement,
+            db.rows_affected = field::Empty,
+            code.function = caller,
+        );
+        Self {
+            span,
+            parameters: parameters(&statement),
+            statement,
+            caller,
+            rows: 0,
+            failed: false,
+            start: Instant::now(),
+            slow_query,
+        }
+    }
+
+    fn record_step(&mut self, step: &Result<Either<PgQueryResult, PgRow>, sqlx::Error>) {
+        match step {
+            Ok(Either::Left(result)) => self.rows += result.rows_affected(),
+            Ok(Either::Right(_)) => self.rows += 1,
+            Err(_) => self.failed = true,
+        }
+    }
+
+    fn record_row(&mut self, row: &Result<Option<PgRow>, sqlx::Error>) {
+        self.record_result(row, |row| row.is_some() as u64);
+    }
+
+    fn record_result<T, E>(&mut self, result: &Result<T, E>, rows: impl FnOnce(&T) -> u64) {
+        match result {
+            Ok(value) => self.rows = rows(value),
+            Err(_) => self.failed = true,
+        }
+    }
+}
+
+impl Drop for QueryTrace {
+    fn drop(&mut self) {
+        let elapsed = self.start.elapsed();
+        self.span.record("db.rows_affected", self.rows);
+        if self.failed {
+            self.span.record("otel.status_code", "ERROR");
+        }
+        if self
+            .slow_query
+            .is_some_and(|threshold| elapsed >= threshold)
+        {
+            warn!(
+                parent: &self.span,
+                statement = self.statement,
+                caller = self.caller,
+                parameters = self.parameters,
+                elapsed_ms = elapsed.as_millis() as u64,
+                "slow query"
+            );
+        }
+    }
+}
+
+impl Display for DbCaller {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Named { driver, method } => write!(f, "{driver}::{method}"),
+            Self::Location(location) => write!(f, "{}:{}", location.file(), location.line()),
+        }
+    }
+}
+
+///the highest `$n` placeholder, postgres numbers every bound parameter
+fn parameters(statement: &str) -> usize {
+    statement
+        .split('$')
+        .skip(1)
+        .filter_map(|s| {
+            let end = s.find(|c: char| !c.is_ascii_digit()).unwrap_or(s.len());
+            s[..end].parse().ok()
+        })
+        .max()
+        .unwrap_or(0)
+}
+
+///replaces literals with `?` and drops comments, so values inlined in raw sql never end up in the
+///traces. quoted identifiers and `$n` placeholders are kept
+fn sanitize(sql: &str) -> String {
+    let mut out = String::with_capacity(sql.len().min(MAX_STATEMENT_LEN));
+    let mut rest = sql;
+    while let Some(c) = rest.chars().next() {
+        if out.len() >= MAX_STATEMENT_LEN {
+            out.push_str("...");
+            break;
+        }
+        let prev = out.chars().last().unwrap_or(' ');
+        let in_word = prev.is_alphanumeric() || prev == '_';
+        let len = if rest.starts_with("--") {
+            space(&mut out);
+            rest.find('\n').unwrap_or(rest.len())
+        } else if rest.starts_with("/*") {
+            space(&mut out);
+            block_comment_len(rest)
+        } else if c == '\'' {
+            out.push('?');
+            quoted_len(rest, '\'', false)
+        } else if matches!(c, 'E' | 'e') && !in_word && rest[1..].starts_with('\'') {
+            //backslashes escape in E'...' strings
+            out.push('?');
+            1 + quoted_len(&rest[1..], '\'', true)
+        } else if c == '"' {
+            let len = quoted_len(rest, '"', false);
+            out.push_str(&rest[..len]);
+            len
+        } else if let Some(len) = dollar_quoted_len(rest) {
+            out.push('?');
+            len
+        } else if c.is_ascii_digit() && !(in_word || prev == '$') {
+            out.push('?');
+            rest.find(|c: char| !(c.is_ascii_digit() || c == '.'))
+                .unwrap_or(rest.len())
+        } else if c.is_whitespace() {
+            space(&mut out);
+            c.len_utf8()
+        } else {
+            out.push(c);
+            c.len_utf8()
+        };
+        rest = &rest[len..];
+    }
+    out.trim().to_owned()
+}
+
+fn space(out: &mut String) {
+    if !out.ends_with(' ') {
+        out.push(' ');
+    }
+}
+
+///length of the quoted token at the start of `s`, doubled quotes are escaped ones
+fn quoted_len(s: &str, quote: char, backslash_escapes: bool) -> usize {
+    let mut chars = s.char_indices().skip(1).peekable();
+    while let Some((i, c)) = chars.next() {
+        if backslash_escapes && c == '\\' {
+            chars.next();
+        } else if c == quote && chars.next_if(|(_, c)| *c == quote).is_none() {
+            return i + 1;
+        }
+    }
+    s.len()
+}
+
+///block comments nest in postgres
+fn block_comment_len(s: &str) -> usize {
+    let bytes = s.as_bytes();
+    let (mut depth, mut i) = (0, 0);
+    while i < bytes.len() {
+        if bytes[i..].starts_with(b"/*") {
+            depth += 1;
+            i += 2;
+        } else if bytes[i..].starts_with(b"*/") {
+            depth -= 1;
+            i += 2;
+            if depth == 0 {
+                return i;
+            }
+        } else {
+            i += 1;
+        }
+    }
+    s.len()
+}
+
+///`$$...$$` or `$tag$...$tag$` at the start of `s`, `None` for anything else like `$1`
+fn dollar_quoted_len(s: &str) -> Option<usize> {
+    if !s.starts_with('$') {
+        return None;
+    }
+    let tag_len = s[1..]
+        .find(|c: char| !(c.is_alphanumeric() || c == '_'))
+        .unwrap_or(s.len() - 1);
+    let tag = &s[1..1 + tag_len];
+    if tag.starts_with(|c: char| c.is_ascii_digit()) || !s[1 + tag_len..].starts_with('$') {
+        return None;
+    }
+    let delimiter = &s[..tag_len + 2];
+    let body = &s[delimiter.len()..];
+    Some(
+        body.find(delimiter)
+            .map_or(s.len(), |end| delimiter.len() + end + delimiter.len()),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use parking_lot::Mutex;
+    use std::sync::Arc;
+    use tracing::field::{Field, Visit};
+    use tracing::span::Attributes;
+    use tracing::{Id, Subscriber};
+    use tracing_subscriber::Layer;
+    use tracing_subscriber::layer::{Context, SubscriberExt};
+
+    #[test]
+    fn replaces_literals() {
+        assert_eq!(
+            sanitize("SELECT * FROM users WHERE id = 42 AND score > 1.5 AND name = 'bob'"),
+            "SELECT * FROM users WHERE id = ? AND score > ? AND name = ?"
+        );
+        assert_eq!(
+            sanitize("INSERT INTO t2 (a_1, b) VALUES (-7, 'x')"),
+            "INSERT INTO t2 (a_1, b) VALUES (-?, ?)"
+        );
+    }
+
+    #[test]
+    fn replaces_quoted_strings_with_escapes() {
+        assert_eq!(sanitize("SELECT 'it''s', ''"), "SELECT ?, ?");
+        assert_eq!(sanitize(r"SELECT E'it\'s \\', e'\n'"), "SELECT ?, ?");
+        assert_eq!(
+            sanitize("SELECT $$it's$$, $fn$ a $$ b $fn$ FROM t"),
+            "SELECT ?, ? FROM t"
+        );
+        //unterminated, nothing after the quote is kept
+        assert_eq!(sanitize("SELECT 'secret"), "SELECT ?");
+    }
+
+    #[test]
+    fn keeps_identifiers_and_placeholders() {
+        assert_eq!(
+            sanitize(r#"SELECT "col1", "it's ""x""" FROM users WHERE id = $1 AND v = $12"#),
+            r#"SELECT "col1", "it's ""x""" FROM users WHERE id = $1 AND v = $12"#
+        );
+        assert_eq!(
+            sanitize("SELECT pg_notify($1, $2)"),
+            "SELECT pg_notify($1, $2)"
+        );
+    }
+
+    #[test]
+    fn drops_comments() {
+        assert_eq!(
+            sanitize("SELECT 1 -- it's a 'secret' 42\nFROM t"),
+            "SELECT ? FROM t"
+        );
+        assert_eq!(
+            sanitize("SELECT /* outer /* it's */ 'x' */ a FROM t /* unterminated"),
+            "SELECT a FROM t"
+        );
+    }
+
+    #[test]
+    fn collapses_whitespace_and_truncates() {
+        assert_eq!(sanitize("  SELECT\n\t a \r\n FROM  t  "), "SELECT a FROM t");
+        assert_eq!(sanitize("SELECT 'é', ünï FROM t"), "SELECT ?, ünï FROM t");
+        let long = format!("SELECT {}", "a, ".repeat(MAX_STATEMENT_LEN));
+        let sanitized = sanitize(&long);
+        assert!(sanitized.ends_with("..."));
+        assert!(sanitized.len() <= MAX_STATEMENT_LEN + 3);
+    }
+
+    #[test]
+    fn counts_placeholders() {
+        assert_eq!(parameters("SELECT 1"), 0);
+        assert_eq!(parameters("SELECT $2, $1"), 2);
+        assert_eq!(parameters("UPDATE t SET a = $10 WHERE id = $9"), 10);
+        //once sanitized, dollars inside literals and comments are gone
+        let statement = sanitize("SELECT '$5', $1 -- $9\n/* $8 */");
+        assert_eq!(parameters(&statement), 1);
+    }
+
+    ///the `code.function` of every new span
+    #[derive(Clone, Default)]
+    struct Callers(Arc<Mutex<Vec<String>>>);
+
+    impl Visit for Callers {
+        fn record_str(&mut self, field: &Field, value: &str) {
+            if field.name() == "code.function" {
+                self.0.lock().push(value.to_owned());
+            }
+        }
+        fn record_debug(&mut self, _: &Field, _: &dyn Debug) {}
+    }
+
+    impl<S: Subscriber> Layer<S> for Callers {
+        fn on_new_span(&self, attrs: &Attributes<'_>, _: &Id, _: Context<'_, S>) {
+            attrs.record(&mut self.clone());
+        }
+    }
+
+    ///records the span each query runs in
+    #[derive(Default)]
+    struct SpanProbe(Mutex<Vec<Option<&'static str>>>);
+
+    impl SpanProbe {
+        fn probe(&self) {
+            self.0
+                .lock()
+                .push(Span::current().metadata().map(|m| m.name()));
+        }
+    }
+
+    #[async_trait::async_trait]
+    impl ConnectionTrait for SpanProbe {
+        fn get_database_backend(&self) -> DbBackend {
+            DbBackend::Postgres
+        }
+        async fn execute(&self, _: Statement) -> Result<ExecResult, DbErr> {
+            self.probe();
+            Err(DbErr::Custom("unsupported".into()))
+        }
+        async fn execute_unprepared(&self, _: &str) -> Result<ExecResult, DbErr> {
+            self.probe();
+            Err(DbErr::Custom("unsupported".into()))
+        }
+        async fn query_one(&self, _: Statement) -> Result<Option<QueryResult>, DbErr> {
+            self.probe();
+            Ok(None)
+        }
+        async fn query_all(&self, _: Statement) -> Result<Vec<QueryResult>, DbErr> {
+            self.probe();
+            Ok(vec![])
+        }
+    }
+
+    #[tokio::test]
+    async fn orm_queries_run_inside_a_span_with_their_caller() {
+        let callers = Callers::default();
+        let _default =
+            tracing::subscriber::set_default(tracing_subscriber::registry().with(callers.clone()));
+        let probe = SpanProbe::default();
+        let orm = TracedOrm {
+            db: &probe,
+            acquire: None,
+            caller: DbCaller::new("UserAuthDriver", "find_by_identity"),
+            slow_query: None,
+        };
+        let stmt = || Statement::from_string(DbBackend::Postgres, "SELECT 1");
+        orm.query_one(stmt()).await.unwrap();
+        orm.query_all(stmt()).await.unwrap();
+        orm.execute(stmt()).await.unwrap_err();
+        orm.execute_unprepared("SELECT 1").await.unwrap_err();
+
+        assert_eq!(*probe.0.lock(), [Some("db.query"); 4]);
+        assert_eq!(*callers.0.lock(), ["UserAuthDriver::find_by_identity"; 4]);
+    }
+}
@@ -0,0 +1,264 @@
+use crate::pool::{AcquireTimer, connect_options, pool_options};
+use crate::psql_connection::{DbCaller, OrmGuard, PgPoolGuard, PsqlConnection};
+use lib_shared::env_service::PsqlPoolEnv;
+use lib_shared::metrics::Metrics;
+use lib_shared::{Res, info, warn};
+use opentelemetry::KeyValue;
+use sea_orm::DatabaseConnection;
+use std::future::Future;
+use std::panic::Location;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::time::Duration;
+use tokio::task::JoinHandle;
+extern crate tracing;
+
+const HEALTH_CHECK_TIMEOUT: Duration = Duration::from_secs(2);
+
+tokio::task_local! {
+    static STICKY: AtomicBool;
+}
+
+pub struct Replica {
+    url: String,
+    db: sqlx::PgPool,
+    orm: DatabaseConnection,
+    acquire: Arc<AcquireTimer>,
+    healthy: AtomicBool,
+}
+
+///read only pools, picked round-robin among the ones that passed the last health check
+#[derive(Default)]
+pub struct Replicas {
+    replicas: Vec<Replica>,
+    next: AtomicUsize,
+}
+
+impl Replicas {
+    ///pools connect lazily and start unhealthy, a replica being down never blocks the startup.
+    ///reads go to the primary until the first round of [`PsqlConnection::run_replica_health_check`]
+    pub fn connect_lazy(
+        urls: &[String],
+        config: &PsqlPoolEnv,
+        metrics: &Metrics,
+    ) -> eyre::Result<Self> {
+        let replicas = urls
+            .iter()
+            .map(|url| {
+                let db = pool_options(config).connect_lazy_with(connect_options(url, config)?);
+                let orm = DatabaseConnection::from(db.clone());
+                let url = redact(url);
+                Ok(Replica {
+                    acquire: AcquireTimer::new(metrics, url.clone()),
+                    url,
+                    orm,
+                    db,
+                    healthy: AtomicBool::new(false),
+                })
+            })
+            .collect::<eyre::Result<Vec<_>>>()?;
+        Ok(Self {
+            replicas,
+            next: AtomicUsize::new(0),
+        })
+    }
+
+    pub(crate) fn pools(&self) -> impl Iterator<Item = (&str, &sqlx::PgPool)> {
+        self.replicas.iter().map(|r| (r.url.as_str(), &r.db))
+    }
+
+    fn pick(&self) -> Option<&Replica> {
+        let len = self.replicas.len();
+        let start = self.next.fetch_add(1, Ordering::Relaxed);
+        (0..len)
+            .map(|i| &self.replicas[(start + i) % len])
+            .find(|r| r.healthy.load(Ordering::Relaxed))
+    }
+}
+
+impl PsqlConnection {
+    ///guard on a healthy replica, falls back to the primary if there is none (as at startup,
+    ///before the first health check) or the current request already went through the primary
+    ///(see [`read_your_writes`])
+    #[track_caller]
+    pub fn db_read(&self) -> PgPoolGuard<'_> {
+        self.db_read_as(DbCaller::Location(Location::caller()))
+    }
+
+    pub fn db_read_as(&self, caller: DbCaller) -> PgPoolGuard<'_> {
+        match self.read_replica() {
+            Some(replica) => self.guard(&replica.db, &replica.acquire, caller),
+            None => self.db_as(caller),
+        }
+    }
+
+    #[track_caller]
+    pub fn orm_read(&self) -> OrmGuard<'_> {
+        self.orm_read_as(DbCaller::Location(Location::caller()))
+    }
+
+    pub fn orm_read_as(&self, caller: DbCaller) -> OrmGuard<'_> {
+        match self.read_replica() {
+            Some(replica) => self.orm_guard(&replica.orm, &replica.acquire, caller),
+            None => self.orm_as(caller),
+        }
+    }
+
+    fn read_replica(&self) -> Option<&Replica> {
+        if STICKY
+            .try_with(|sticky| sticky.load(Ordering::Relaxed))
+            .unwrap_or(false)
+        {
+            return None;
+        }
+        self.replicas.pick()
+    }
+
+    ///pings every replica, unhealthy ones are skipped by `db_read()` until they answer again
+    pub fn run_replica_health_check(&self, interval: Duration) -> JoinHandle<Res> {
+        let slf = self.clone();
+        tokio::spawn(async move {
+            let mut ticker = tokio::time::interval(interval);
+            loop {
+                ticker.tick().await;
+                for replica in &slf.replicas.replicas {
+                    let healthy = matches!(
+                        tokio::time::timeout(
+                            HEALTH_CHECK_TIMEOUT,
+                            sqlx::query("SELECT 1").execute(&replica.db)
+                        )
+                        .await,
+                        Ok(Ok(_))
+                    );
+                    if !healthy {
+                        slf.metrics
+                            .db_pool_connection_errors
+                            .add(1, &[KeyValue::new("pool", replica.url.clone())]);
+                    }
+                    let was_healthy = replica.healthy.swap(healthy, Ordering::Relaxed);
+                    match (was_healthy, healthy) {
+                        (false, true) => info!(replica = replica.url, "replica is up"),
+                        (true, false) => warn!(replica = replica.url, "replica is down"),
+                        _ => {}
+                    }
+                    slf.metrics.db_replica_status.record(
+                        healthy as u64,
+                        &[KeyValue::new("replica", replica.url.clone())],
+                    );
+                }
+            }
+        })
+    }
+}
+
+///scope in which reads stick to the primary once anything went through it, so a request
+///reads its own writes regardless of the replication lag. tasks spawned inside don't inherit it
+pub async fn read_your_writes<F: Future>(f: F) -> F::Output {
+    STICKY.scope(AtomicBool::new(false), f).await
+}
+
+///routes every following read of the current [`read_your_writes`] scope to the primary
+pub fn stick_to_primary() {
+    _ = STICKY.try_with(|sticky| sticky.store(true, Ordering::Relaxed));
+}
+
+///keeps the credentials out of the logs and metric labels
+fn redact(url: &str) -> String {
+    match url.split_once("://").zip(url.rsplit_once('@')) {
+        Some(((scheme, _), (_, host))) => format!("{scheme}://{host}"),
+        None => url.to_owned(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use sqlx::postgres::PgPoolOptions;
+
+    fn lazy_pool(url: &str) -> sqlx::PgPool {
+        PgPoolOptions::new().connect_lazy(url).unwrap()
+    }
+
+    fn replicas(names: &[&str]) -> Replicas {
+        Replicas {
+            replicas: names
+                .iter()
+                .map(|name| {
+                    let db = lazy_pool(&format!("postgres://{name}/app"));
+                    Replica {
+                        url: name.to_string(),
+                        orm: DatabaseConnection::from(db.clone()),
+                        acquire: AcquireTimer::new(&Metrics::new(), name.to_string()),
+                        db,
+                        healthy: AtomicBool::new(false),
+                    }
+                })
+                .collect(),
+            next: AtomicUsize::new(0),
+        }
+    }
+
+    fn set_healthy(replicas: &Replicas, name: &str, healthy: bool) {
+        let replica = replicas.replicas.iter().find(|r| r.url == name).unwrap();
+        replica.healthy.store(healthy, Ordering::Relaxed);
+    }
+
+    fn picks(replicas: &Replicas, n: usize) -> Vec<Option<String>> {
+        (0..n)
+            .map(|_| replicas.pick().map(|r| r.url.clone()))
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn round_robin_over_the_healthy_replicas() {
+        let replicas = replicas(&["a", "b", "c"]);
+        //nothing passed a health check yet
+        assert_eq!(picks(&replicas, 2), [None, None]);
+
+        for name in ["a", "b", "c"] {
+            set_healthy(&replicas, name, true);
+        }
+        let all = picks(&replicas, 6);
+        assert_eq!(all[..3], all[3..]);
+        let mut cycle: Vec<_> = all[..3].iter().flatten().cloned().collect();
+        cycle.sort();
+        assert_eq!(cycle, ["a", "b", "c"]);
+
+        set_healthy(&replicas, "b", false);
+        let some = picks(&replicas, 4);
+        assert!(some.iter().all(|r| matches!(r.as_deref(), Some("a" | "c"))));
+        assert!(some.contains(&Some("a".into())) && some.contains(&Some("c".into())));
+        assert!(Replicas::default().pick().is_none());
+    }
+
+    #[tokio::test]
+    async fn reads_stick_to_the_primary_after_a_write() {
+        let replicas = replicas(&["a"]);
+        set_healthy(&replicas, "a", true);
+        let connection = PsqlConnection::new(lazy_pool("postgres://primary/app"), Metrics::new())
+            .with_replicas(replicas);
+        let replica = |c: &PsqlConnection| c.read_replica().map(|r| r.url.clone());
+
+        read_your_writes(async {
+            assert_eq!(replica(&connection).as_deref(), Some("a"));
+            _ = connection.db();
+            assert_eq!(replica(&connection), None);
+            //tasks spawned inside the scope don't inherit it
+            let spawned = connection.clone();
+            let spawned = tokio::spawn(async move { replica(&spawned) }).await;
+            assert_eq!(spawned.unwrap().as_deref(), Some("a"));
+        })
+        .await;
+
+        read_your_writes(async {
+            stick_to_primary();
+            assert_eq!(replica(&connection), None);
+        })
+        .await;
+
+        //every scope starts on the replicas and going through the primary outside of one changes nothing
+        read_your_writes(async { assert!(replica(&connection).is_some()) }).await;
+        _ = connection.orm();
+        assert_eq!(replica(&connection).as_deref(), Some("a"));
+    }
+}
@@ -0,0 +1,4 @@
+//storage agnostic traits for the driver operations, lib-core depends on these instead of the
+//postgres drivers so services can run against the in-memory implementations
+
+pub mod user_auth;
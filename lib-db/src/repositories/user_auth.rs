@@ -0,0 +1,336 @@
+use crate::UserAuthDriver;
+use crate::audit::include_deleted;
+use crate::entities::users;
+use crate::password::{hash_password, verify_dummy, verify_password};
+use crate::tenant::current_tenant;
+use crate::user_auth_driver::UserProfile;
+use crate::versioning::Conflict;
+use async_trait::async_trait;
+use eyre::OptionExt;
+use hashbrown::HashMap;
+use parking_lot::RwLock;
+use std::sync::atomic::{AtomicI64, Ordering};
+
+#[async_trait]
+pub trait UserAuthRepository: Send + Sync + 'static {
+    async fn login(&self, identity: &str, pwd: &str) -> eyre::Result<bool>;
+    ///the user belongs to [`current_tenant`], fails if the identity is taken there
+    async fn create_user(&self, identity: &str, pwd: &str) -> eyre::Result<UserProfile>;
+    ///returns `false` if the identity doesn't exist
+    async fn set_password(&self, identity: &str, pwd: &str) -> eyre::Result<bool>;
+    async fn find_by_identity(&self, identity: &str) -> eyre::Result<Option<UserProfile>>;
+    ///returns the new version, fails with [`Conflict`] if `version` is outdated
+    async fn update_display_name(
+        &self,
+        id: i64,
+        version: i64,
+        display_name: Option<String>,
+    ) -> eyre::Result<i64>;
+}
+
+#[async_trait]
+impl UserAuthRepository for UserAuthDriver {
+    async fn login(&self, identity: &str, pwd: &str) -> eyre::Result<bool> {
+        UserAuthDriver::login(self, identity, pwd).await
+    }
+
+    async fn create_user(&self, identity: &str, pwd: &str) -> eyre::Result<UserProfile> {
+        UserAuthDriver::create_user(self, identity, pwd).await
+    }
+
+    async fn set_password(&self, identity: &str, pwd: &str) -> eyre::Result<bool> {
+        UserAuthDriver::set_password(self, identity, pwd).await
+    }
+
+    async fn find_by_identity(&self, identity: &str) -> eyre::Result<Option<UserProfile>> {
+        UserAuthDriver::find_by_identity(self, identity).await
+    }
+
+    async fn update_display_name(
+        &self,
+        id: i64,
+        version: i64,
+        display_name: Option<String>,
+    ) -> eyre::Result<i64> {
+        UserAuthDriver::update_display_name(self, id, version, display_name).await
+    }
+}
+
+///keeps the users in a map, for tests and local runs without a database.
+///passwords are hashed like the driver does, lookups only see the users of
+///[`current_tenant`] and skip the deleted ones outside of [`with_deleted`](crate::audit::with_deleted),
+///like the row level security of the real tables
+#[derive(Default)]
+pub struct MemoryUserAuthRepository {
+    ///by `(tenant_id, identity)`
+    users: RwLock<HashMap<(String, String), users::Model>>,
+    last_id: AtomicI64,
+}
+
+impl MemoryUserAuthRepository {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn with_user(
+        self,
+        tenant: impl Into<String>,
+        identity: impl Into<String>,
+        pwd: impl AsRef<str>,
+    ) -> Self {
+        self.insert(tenant, identity, pwd);
+        self
+    }
+
+    ///replaces the user if the identity is taken
+    pub fn insert(
+        &self,
+        tenant: impl Into<String>,
+        identity: impl Into<String>,
+        pwd: impl AsRef<str>,
+    ) {
+        let hash = hash_password(pwd.as_ref()).expect("the default argon2 parameters are valid");
+        let mut users = self.users.write();
+        self.put(&mut users, tenant.into(), identity.into(), hash);
+    }
+
+    fn put(
+        &self,
+        users: &mut HashMap<(String, String), users::Model>,
+        tenant: String,
+        identity: String,
+        password_hash: String,
+    ) -> users::Model {
+        let now = chrono::Utc::now().fixed_offset();
+        let user = users::Model {
+            id: self.last_id.fetch_add(1, Ordering::Relaxed) + 1,
+            identity: identity.clone(),
+            password_hash,
+            created_at: now,
+            updated_at: now,
+            version: 1,
+            display_name: None,
+            deleted_at: None,
+            created_by: crate::audit::principal(),
+            tenant_id: tenant.clone(),
+        };
+        users.insert((tenant, identity), user.clone());
+        user
+    }
+
+    fn get(&self, identity: &str) -> Option<users::Model> {
+        let tenant = current_tenant()?;
+        self.users
+            .read()
+            .get(&(tenant, identity.to_owned()))
+            .filter(|u| u.deleted_at.is_none() || include_deleted())
+            .cloned()
+    }
+}
+
+#[async_trait]
+impl UserAuthRepository for MemoryUserAuthRepository {
+    async fn login(&self, identity: &str, pwd: &str) -> eyre::Result<bool> {
+        Ok(match self.get(identity) {
+            Some(user) => verify_password(pwd, &user.password_hash),
+            None => {
+                verify_dummy(pwd);
+                false
+            }
+        })
+    }
+
+    async fn create_user(&self, identity: &str, pwd: &str) -> eyre::Result<UserProfile> {
+        let tenant = current_tenant().ok_or_eyre("users can't be created without a tenant")?;
+        let hash = hash_password(pwd)?;
+        let mut users = self.users.write();
+        let key = (tenant, identity.to_owned());
+        if users.contains_key(&key) {
+            eyre::bail!("identity {identity} is taken");
+        }
+        Ok(self.put(&mut users, key.0, key.1, hash).into())
+    }
+
+    async fn set_password(&self, identity: &str, pwd: &str) -> eyre::Result<bool> {
+        let Some(tenant) = current_tenant() else {
+            return Ok(false);
+        };
+        let hash = hash_password(pwd)?;
+        let mut users = self.users.write();
+        let user = users
+            .get_mut(&(tenant, identity.to_owned()))
+            .filter(|u| u.deleted_at.is_none() || include_deleted());
+        Ok(user
+            .map(|u| {
+                u.password_hash = hash;
+                u.updated_at = chrono::Utc::now().fixed_offset();
+            })
+            .is_some())
+    }
+
+    async fn find_by_identity(&self, identity: &str) -> eyre::Result<Option<UserProfile>> {
+        Ok(self.get(identity).map(UserProfile::from))
+    }
+
+    async fn update_display_name(
+        &self,
+        id: i64,
+        version: i64,
+        display_name: Option<String>,
+    ) -> eyre::Result<i64> {
+        let tenant = current_tenant();
+        let mut users = self.users.write();
+        let user = users
+            .values_mut()
+            .find(|u| {
+                u.id == id
+                    && (u.deleted_at.is_none() || include_deleted())
+                    && Some(&u.tenant_id) == tenant.as_ref()
+            })
+            .ok_or(sqlx::Error::RowNotFound)?;
+        if user.version != version {
+            return Err(Conflict {
+                table: "users",
+                id,
+                expected: version,
+                current: user.version,
+            }
+            .into());
+        }
+        user.display_name = display_name;
+        user.updated_at = chrono::Utc::now().fixed_offset();
+        user.version += 1;
+        Ok(user.version)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tenant::with_tenant;
+    use std::future::Future;
+
+    fn repository() -> MemoryUserAuthRepository {
+        MemoryUserAuthRepository::new()
+            .with_user("acme", "alice", "acme-pwd")
+            .with_user("globex", "alice", "globex-pwd")
+            .with_user("globex", "bob", "bob-pwd")
+    }
+
+    async fn as_tenant<F: Future>(tenant: &str, f: F) -> F::Output {
+        with_tenant(Some(tenant.to_owned()), f).await
+    }
+
+    #[tokio::test]
+    async fn tenants_only_see_their_users() {
+        let repository = repository();
+        let alice = as_tenant("acme", repository.find_by_identity("alice"))
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(alice.tenant_id, "acme");
+        let alice = as_tenant("globex", repository.find_by_identity("alice"))
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(alice.tenant_id, "globex");
+        let bob = as_tenant("acme", repository.find_by_identity("bob")).await;
+        assert!(bob.unwrap().is_none());
+    }
+
+    #[tokio::test]
+    async fn passwords_are_checked_within_the_tenant() {
+        let repository = repository();
+        assert!(
+            as_tenant("acme", repository.login("alice", "acme-pwd"))
+                .await
+                .unwrap()
+        );
+        assert!(
+            !as_tenant("acme", repository.login("alice", "globex-pwd"))
+                .await
+                .unwrap()
+        );
+        assert!(
+            !as_tenant("acme", repository.login("bob", "bob-pwd"))
+                .await
+                .unwrap()
+        );
+    }
+
+    #[tokio::test]
+    async fn nothing_is_visible_without_a_tenant() {
+        let repository = repository();
+        assert!(
+            repository
+                .find_by_identity("alice")
+                .await
+                .unwrap()
+                .is_none()
+        );
+        assert!(!repository.login("alice", "acme-pwd").await.unwrap());
+        assert!(
+            with_tenant(None, repository.find_by_identity("alice"))
+                .await
+                .unwrap()
+                .is_none()
+        );
+    }
+
+    #[tokio::test]
+    async fn passwords_are_hashed() {
+        let repository = repository();
+        let alice = repository.users.read()[&("acme".to_owned(), "alice".to_owned())].clone();
+        assert!(alice.password_hash.starts_with("$argon2id$"));
+        as_tenant("acme", repository.set_password("alice", "new-pwd"))
+            .await
+            .unwrap();
+        assert!(
+            as_tenant("acme", repository.login("alice", "new-pwd"))
+                .await
+                .unwrap()
+        );
+        assert!(
+            !as_tenant("acme", repository.login("alice", "acme-pwd"))
+                .await
+                .unwrap()
+        );
+    }
+
+    #[tokio::test]
+    async fn ids_stay_unique() {
+        let repository = repository();
+        //replaces the first alice
+        repository.insert("acme", "alice", "other-pwd");
+        let created = as_tenant("acme", repository.create_user("carol", "carol-pwd"))
+            .await
+            .unwrap();
+        let mut ids: Vec<i64> = repository.users.read().values().map(|u| u.id).collect();
+        ids.sort();
+        ids.dedup();
+        assert_eq!(ids.len(), 4);
+        assert!(ids.contains(&created.id));
+        let taken = as_tenant("acme", repository.create_user("carol", "carol-pwd")).await;
+        assert!(taken.is_err());
+    }
+
+    #[tokio::test]
+    async fn updates_stay_within_the_tenant() {
+        let repository = repository();
+        let bob = as_tenant("globex", repository.find_by_identity("bob"))
+            .await
+            .unwrap()
+            .unwrap();
+        let update = repository.update_display_name(bob.id, bob.version, Some("Bob".to_owned()));
+        let e = as_tenant("acme", update).await.unwrap_err();
+        assert!(matches!(
+            e.downcast_ref::<sqlx::Error>(),
+            Some(sqlx::Error::RowNotFound)
+        ));
+        let bob = as_tenant("globex", repository.find_by_identity("bob"))
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(bob.display_name, None);
+    }
+}
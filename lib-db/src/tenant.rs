@@ -0,0 +1,58 @@
+use crate::psql_connection::PsqlConnection;
+use eyre::bail;
+use std::future::Future;
+
+///postgres setting read by the row level security policies of the tenant scoped tables
+pub const TENANT_SETTING: &str = "app.tenant_id";
+
+tokio::task_local! {
+    static TENANT: Option<String>;
+}
+
+///runs `f` for `tenant`, every connection acquired inside only sees the rows of that tenant.
+///tasks spawned inside don't inherit it
+pub async fn with_tenant<F: Future>(tenant: Option<String>, f: F) -> F::Output {
+    TENANT.scope(tenant, f).await
+}
+
+///`None` outside of [`with_tenant`], tenant scoped tables look empty then
+pub fn current_tenant() -> Option<String> {
+    TENANT.try_with(Clone::clone).ok().flatten()
+}
+
+impl PsqlConnection {
+    ///fails if the role of the connection skips the row level security policies, as a superuser
+    ///or a role with `BYPASSRLS` does. they would see the rows of every tenant and the soft
+    ///deleted ones, see [`with_deleted`](crate::audit::with_deleted)
+    pub async fn check_row_security(&self) -> eyre::Result<()> {
+        let (role, bypasses): (String, bool) = sqlx::query_as(
+            "SELECT rolname::text, rolsuper OR rolbypassrls FROM pg_catalog.pg_roles \
+             WHERE rolname = current_user",
+        )
+        .fetch_one(*self.db())
+        .await?;
+        if bypasses {
+            bail!(
+                "the database role {role} is a superuser or has BYPASSRLS, it would skip the row \
+                 level security of the tenants and the soft deleted rows"
+            );
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use lib_shared::metrics::Metrics;
+    use sqlx::postgres::PgPoolOptions;
+
+    #[tokio::test]
+    #[ignore = "needs a postgres server in DATABASE_URL"]
+    async fn the_app_role_is_subject_to_row_security() {
+        let url = std::env::var("DATABASE_URL").unwrap();
+        let pool = PgPoolOptions::new().connect(&url).await.unwrap();
+        let connection = PsqlConnection::new(pool, Metrics::new());
+        connection.check_row_security().await.unwrap();
+    }
+}
@@ -0,0 +1,496 @@
+use crate::impl_guard;
+use crate::psql_connection::{DbCaller, MetricSender, PsqlConnection};
+use crate::query_trace::TracedOrm;
+use crate::replicas::stick_to_primary;
+use eyre::eyre;
+use lib_shared::{error, instrument, warn};
+use sea_orm::sea_query::Values;
+use sea_orm::{
+    AccessMode, ConnectionTrait, DbBackend, DbErr, ExecResult, IsolationLevel, QueryResult,
+    RuntimeErr, Statement,
+};
+use sea_query_binder::SqlxValues;
+use sqlx::{PgConnection, Postgres, Transaction};
+use std::future::Future;
+use std::ops::{Deref, DerefMut};
+use std::panic::Location;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::time::{Duration, Instant};
+use tokio::sync::{Mutex, MutexGuard};
+extern crate tracing;
+
+type PgTransaction = Transaction<'static, Postgres>;
+
+#[derive(Clone, Copy)]
+pub struct TxOptions {
+    pub isolation: Option<IsolationLevel>,
+    pub access_mode: Option<AccessMode>,
+    ///how many times the whole transaction is replayed after a serialization failure or deadlock
+    pub max_retries: u32,
+}
+
+impl Default for TxOptions {
+    fn default() -> Self {
+        Self {
+            isolation: None,
+            access_mode: None,
+            max_retries: 3,
+        }
+    }
+}
+
+///handle to an open transaction, clone it to share the transaction between drivers.
+///use `.conn()` for sqlx and `.orm()` for seaorm, both record metrics like the pool guards
+#[derive(Clone)]
+pub struct Tx {
+    inner: Arc<TxInner>,
+}
+
+struct TxInner {
+    tx: Mutex<Option<PgTransaction>>,
+    savepoints: AtomicU32,
+    slow_query: Option<Duration>,
+    sender: MetricSender,
+}
+
+pub struct TxConnGuard<'a> {
+    conn: MutexGuard<'a, Option<PgTransaction>>,
+    exec_time: Instant,
+    caller: DbCaller,
+    sender: MetricSender,
+}
+
+impl_guard!(TxOrmGuard, TracedOrm<'a, Tx>);
+
+impl PsqlConnection {
+    ///runs `f` inside a transaction, commits if it returns `Ok` and rolls back otherwise.
+    ///`f` may run more than once, see [`TxOptions::max_retries`]
+    pub async fn transaction<T, F, Fut>(&self, f: F) -> eyre::Result<T>
+    where
+        F: Fn(Tx) -> Fut,
+        Fut: Future<Output = eyre::Result<T>>,
+    {
+        self.transaction_with(TxOptions::default(), f).await
+    }
+
+    #[instrument(skip_all)]
+    pub async fn transaction_with<T, F, Fut>(&self, options: TxOptions, f: F) -> eyre::Result<T>
+    where
+        F: Fn(Tx) -> Fut,
+        Fut: Future<Output = eyre::Result<T>>,
+    {
+        retry(options.max_retries, || async {
+            let tx = self.begin(&options).await?;
+            match f(tx.clone()).await {
+                Ok(value) => tx.commit().await.map(|_| value),
+                Err(e) => {
+                    _ = tx
+                        .rollback()
+                        .await
+                        .inspect_err(|e| error!("failed to rollback: {e}"));
+                    Err(e)
+                }
+            }
+        })
+        .await
+    }
+
+    async fn begin(&self, options: &TxOptions) -> eyre::Result<Tx> {
+        stick_to_primary();
+        let mut tx = self.acquire.time(self.db.begin()).await?;
+        let mut modes = vec![];
+        if let Some(isolation) = options.isolation {
+            modes.push(format!("ISOLATION LEVEL {isolation}"));
+        }
+        if let Some(access_mode) = options.access_mode {
+            modes.push(access_mode.to_string());
+        }
+        if !modes.is_empty() {
+            //`raw_sql(..).execute(..)` makes every future awaiting `begin` not `Send`
+            let conn: &mut PgConnection = &mut tx;
+            sqlx::Executor::execute(conn, &*format!("SET TRANSACTION {}", modes.join(", ")))
+                .await?;
+        }
+        Ok(Tx {
+            inner: Arc::new(TxInner {
+                tx: Mutex::new(Some(tx)),
+                savepoints: AtomicU32::new(0),
+                slow_query: self.slow_query,
+                sender: self.sender.clone(),
+            }),
+        })
+    }
+}
+
+impl Tx {
+    #[track_caller]
+    pub fn conn(&self) -> impl Future<Output = eyre::Result<TxConnGuard<'_>>> {
+        self.conn_as(DbCaller::Location(Location::caller()))
+    }
+
+    pub async fn conn_as(&self, caller: DbCaller) -> eyre::Result<TxConnGuard<'_>> {
+        let conn = self.inner.tx.lock().await;
+        if conn.is_none() {
+            return Err(eyre!("transaction is already finished"));
+        }
+        Ok(TxConnGuard {
+            conn,
+            exec_time: Instant::now(),
+            caller,
+            sender: self.inner.sender.clone(),
+        })
+    }
+
+    #[track_caller]
+    pub fn orm(&self) -> TxOrmGuard<'_> {
+        self.orm_as(DbCaller::Location(Location::caller()))
+    }
+
+    pub fn orm_as(&self, caller: DbCaller) -> TxOrmGuard<'_> {
+        TxOrmGuard {
+            db: TracedOrm {
+                db: self,
+                acquire: None,
+                caller,
+                slow_query: self.inner.slow_query,
+            },
+            exec_time: Instant::now(),
+            caller,
+            sender: self.inner.sender.clone(),
+        }
+    }
+
+    ///runs `f` inside a savepoint, only the work done by `f` is rolled back if it fails
+    pub async fn savepoint<T, F, Fut>(&self, f: F) -> eyre::Result<T>
+    where
+        F: FnOnce(Tx) -> Fut,
+        Fut: Future<Output = eyre::Result<T>>,
+    {
+        let name = format!(
+            "sp_{}",
+            self.inner.savepoints.fetch_add(1, Ordering::Relaxed)
+        );
+        self.execute_raw(&format!("SAVEPOINT {name}")).await?;
+        match f(self.clone()).await {
+            Ok(value) => {
+                self.execute_raw(&format!("RELEASE SAVEPOINT {name}"))
+                    .await?;
+                Ok(value)
+            }
+            Err(e) => {
+                self.execute_raw(&format!("ROLLBACK TO SAVEPOINT {name}"))
+                    .await?;
+                Err(e)
+            }
+        }
+    }
+
+    async fn execute_raw(&self, sql: &str) -> eyre::Result<()> {
+        let mut conn = self.inner.tx.lock().await;
+        let tx = conn
+            .as_mut()
+            .ok_or_else(|| eyre!("transaction is already finished"))?;
+        let conn: &mut PgConnection = tx;
+        sqlx::Executor::execute(conn, sql).await?;
+        Ok(())
+    }
+
+    async fn commit(&self) -> eyre::Result<()> {
+        let tx = self.take().await?;
+        tx.commit().await?;
+        Ok(())
+    }
+
+    async fn rollback(&self) -> eyre::Result<()> {
+        let tx = self.take().await?;
+        tx.rollback().await?;
+        Ok(())
+    }
+
+    async fn take(&self) -> eyre::Result<PgTransaction> {
+        self.inner
+            .tx
+            .lock()
+            .await
+            .take()
+            .ok_or_else(|| eyre!("transaction is already finished"))
+    }
+}
+
+impl Deref for TxConnGuard<'_> {
+    type Target = PgConnection;
+    fn deref(&self) -> &Self::Target {
+        //checked when the guard is created, the transaction can't finish while the lock is held
+        self.conn
+            .as_deref()
+            .expect("transaction is already finished")
+    }
+}
+
+impl DerefMut for TxConnGuard<'_> {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        self.conn
+            .as_deref_mut()
+            .expect("transaction is already finished")
+    }
+}
+
+impl Drop for TxConnGuard<'_> {
+    fn drop(&mut self) {
+        self.sender.send(self.caller, self.exec_time.elapsed());
+    }
+}
+
+#[async_trait::async_trait]
+impl ConnectionTrait for Tx {
+    fn get_database_backend(&self) -> DbBackend {
+        DbBackend::Postgres
+    }
+
+    async fn execute(&self, stmt: Statement) -> Result<ExecResult, DbErr> {
+        let mut conn = self.inner.tx.lock().await;
+        let tx = conn.as_mut().ok_or_else(finished)?;
+        sqlx::query_with(&stmt.sql, values(stmt.values))
+            .execute(&mut **tx)
+            .await
+            .map(Into::into)
+            .map_err(|e| DbErr::Exec(RuntimeErr::SqlxError(e)))
+    }
+
+    async fn execute_unprepared(&self, sql: &str) -> Result<ExecResult, DbErr> {
+        let mut conn = self.inner.tx.lock().await;
+        let tx = conn.as_mut().ok_or_else(finished)?;
+        let conn: &mut PgConnection = tx;
+        sqlx::Executor::execute(conn, sql)
+            .await
+            .map(Into::into)
+            .map_err(|e| DbErr::Exec(RuntimeErr::SqlxError(e)))
+    }
+
+    async fn query_one(&self, stmt: Statement) -> Result<Option<QueryResult>, DbErr> {
+        let mut conn = self.inner.tx.lock().await;
+        let tx = conn.as_mut().ok_or_else(finished)?;
+        sqlx::query_with(&stmt.sql, values(stmt.values))
+            .fetch_optional(&mut **tx)
+            .await
+            .map(|row| row.map(Into::into))
+            .map_err(|e| DbErr::Query(RuntimeErr::SqlxError(e)))
+    }
+
+    async fn query_all(&self, stmt: Statement) -> Result<Vec<QueryResult>, DbErr> {
+        let mut conn = self.inner.tx.lock().await;
+        let tx = conn.as_mut().ok_or_else(finished)?;
+        sqlx::query_with(&stmt.sql, values(stmt.values))
+            .fetch_all(&mut **tx)
+            .await
+            .map(|rows| rows.into_iter().map(Into::into).collect())
+            .map_err(|e| DbErr::Query(RuntimeErr::SqlxError(e)))
+    }
+}
+
+fn values(values: Option<Values>) -> SqlxValues {
+    SqlxValues(values.unwrap_or(Values(vec![])))
+}
+
+fn finished() -> DbErr {
+    DbErr::Custom("transaction is already finished".into())
+}
+
+///runs `attempt` up to `max_retries` more times while it fails with a retryable error
+async fn retry<T, F, Fut>(max_retries: u32, attempt: F) -> eyre::Result<T>
+where
+    F: Fn() -> Fut,
+    Fut: Future<Output = eyre::Result<T>>,
+{
+    let mut retries = 0;
+    loop {
+        match attempt().await {
+            Err(e) if retries < max_retries && is_retryable(&e) => {
+                retries += 1;
+                warn!(
+                    attempt = retries,
+                    error = e.to_string(),
+                    "retrying transaction"
+                );
+                tokio::time::sleep(Duration::from_millis(10 << retries)).await;
+            }
+            result => return result,
+        }
+    }
+}
+
+///serialization failures and deadlocks succeed if the transaction is replayed
+fn is_retryable(e: &eyre::Report) -> bool {
+    e.chain().any(|cause| {
+        let sqlx_err =
+            cause
+                .downcast_ref::<sqlx::Error>()
+                .or_else(|| match cause.downcast_ref::<DbErr>() {
+                    Some(
+                        DbErr::Exec(RuntimeErr::SqlxError(e))
+                        | DbErr::Query(RuntimeErr::SqlxError(e))
+                        | DbErr::Conn(RuntimeErr::SqlxError(e)),
+                    ) => Some(e),
+                    _ => None,
+                });
+        matches!(
+            sqlx_err,
+            Some(sqlx::Error::Database(e)) if matches!(e.code().as_deref(), Some("40001" | "40P01"))
+        )
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::psql_connection::PsqlConnection;
+    use eyre::WrapErr;
+    use lib_shared::metrics::Metrics;
+    use sqlx::error::{DatabaseError, ErrorKind};
+    use sqlx::postgres::PgPoolOptions;
+    use std::borrow::Cow;
+    use std::error::Error;
+    use std::fmt::{Display, Formatter};
+
+    #[derive(Debug)]
+    struct PgError(&'static str);
+
+    impl Display for PgError {
+        fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+            write!(f, "error {}", self.0)
+        }
+    }
+
+    impl Error for PgError {}
+
+    impl DatabaseError for PgError {
+        fn message(&self) -> &str {
+            "test error"
+        }
+        fn code(&self) -> Option<Cow<'_, str>> {
+            Some(self.0.into())
+        }
+        fn as_error(&self) -> &(dyn Error + Send + Sync + 'static) {
+            self
+        }
+        fn as_error_mut(&mut self) -> &mut (dyn Error + Send + Sync + 'static) {
+            self
+        }
+        fn into_error(self: Box<Self>) -> Box<dyn Error + Send + Sync + 'static> {
+            self
+        }
+        fn kind(&self) -> ErrorKind {
+            ErrorKind::Other
+        }
+    }
+
+    fn pg_error(code: &'static str) -> sqlx::Error {
+        sqlx::Error::Database(Box::new(PgError(code)))
+    }
+
+    #[test]
+    fn only_serialization_failures_and_deadlocks_are_retryable() {
+        for code in ["40001", "40P01"] {
+            assert!(is_retryable(&pg_error(code).into()), "{code}");
+            let orm = DbErr::Query(RuntimeErr::SqlxError(pg_error(code)));
+            assert!(is_retryable(&orm.into()), "{code}");
+            let wrapped = Err::<(), _>(pg_error(code)).wrap_err("in a driver");
+            assert!(is_retryable(&wrapped.unwrap_err()), "{code}");
+        }
+        //unique violation, lock timeout, query canceled
+        for code in ["23505", "55P03", "57014"] {
+            assert!(!is_retryable(&pg_error(code).into()), "{code}");
+        }
+        assert!(!is_retryable(&sqlx::Error::RowNotFound.into()));
+        assert!(!is_retryable(&eyre!("40001")));
+    }
+
+    #[tokio::test]
+    async fn retries_are_bounded() {
+        let attempts = AtomicU32::new(0);
+        let result: eyre::Result<()> = retry(3, || async {
+            attempts.fetch_add(1, Ordering::SeqCst);
+            Err(pg_error("40001").into())
+        })
+        .await;
+        assert!(is_retryable(&result.unwrap_err()));
+        assert_eq!(attempts.load(Ordering::SeqCst), 4);
+
+        //other errors aren't retried
+        attempts.store(0, Ordering::SeqCst);
+        let result: eyre::Result<()> = retry(3, || async {
+            attempts.fetch_add(1, Ordering::SeqCst);
+            Err(pg_error("23505").into())
+        })
+        .await;
+        assert!(result.is_err());
+        assert_eq!(attempts.load(Ordering::SeqCst), 1);
+
+        //stops at the first success
+        attempts.store(0, Ordering::SeqCst);
+        let result = retry(3, || async {
+            match attempts.fetch_add(1, Ordering::SeqCst) {
+                0 => Err(pg_error("40P01").into()),
+                n => Ok(n),
+            }
+        })
+        .await;
+        assert_eq!(result.unwrap(), 1);
+        assert_eq!(attempts.load(Ordering::SeqCst), 2);
+    }
+
+    #[tokio::test]
+    #[ignore = "needs a postgres server in DATABASE_URL"]
+    async fn a_failed_savepoint_only_rolls_back_its_own_work() {
+        let url = std::env::var("DATABASE_URL").unwrap();
+        //a single connection, so the temporary table is visible to the transaction
+        let pool = PgPoolOptions::new()
+            .max_connections(1)
+            .connect(&url)
+            .await
+            .unwrap();
+        sqlx::raw_sql("CREATE TEMP TABLE tx_test (id INT PRIMARY KEY)")
+            .execute(&pool)
+            .await
+            .unwrap();
+        let connection = PsqlConnection::new(pool.clone(), Metrics::new());
+        let insert = |tx: Tx, id: i32| async move {
+            sqlx::query("INSERT INTO tx_test (id) VALUES ($1)")
+                .bind(id)
+                .execute(&mut *tx.conn().await?)
+                .await?;
+            eyre::Result::<()>::Ok(())
+        };
+
+        connection
+            .transaction(|tx| async move {
+                insert(tx.clone(), 1).await?;
+                let failed = tx
+                    .savepoint(|tx| async move {
+                        insert(tx.clone(), 2).await?;
+                        //a duplicate key fails the savepoint, not the transaction
+                        insert(tx, 1).await
+                    })
+                    .await;
+                assert!(failed.is_err());
+                tx.savepoint(|tx| insert(tx, 3)).await
+            })
+            .await
+            .unwrap();
+        //a failed transaction leaves nothing
+        let failed = connection
+            .transaction(|tx| async move {
+                insert(tx, 4).await?;
+                Err::<(), _>(eyre!("rolled back"))
+            })
+            .await;
+        assert!(failed.is_err());
+
+        let ids: Vec<i32> = sqlx::query_scalar("SELECT id FROM tx_test ORDER BY id")
+            .fetch_all(&pool)
+            .await
+            .unwrap();
+        assert_eq!(ids, [1, 3]);
+    }
+}
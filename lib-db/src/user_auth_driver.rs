@@ -1,14 +1,148 @@
-use crate::UserAuthDriver;
+use crate::audit::stamp_insert;
+use crate::entities::users;
+use crate::outbox_driver::NewOutboxMessage;
+use crate::password::{hash_password, verify_dummy, verify_password};
+use crate::tenant::current_tenant;
+use crate::versioning::VersionedUpdate;
+use crate::{OutboxDriver, UserAuthDriver};
+use lib_db_macros::db_driver;
 use lib_shared::instrument;
-use std::fmt::Debug;
+use sea_orm::prelude::DateTimeWithTimeZone;
+use sea_orm::{ActiveModelTrait, ColumnTrait, DerivePartialModel, EntityTrait, QueryFilter, Set};
+use serde::{Deserialize, Serialize};
 extern crate tracing;
 
+///a user without its `password_hash`, safe to cache and to hand to the api
+#[derive(DerivePartialModel, Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[sea_orm(entity = "users::Entity", from_query_result)]
+pub struct UserProfile {
+    pub id: i64,
+    pub tenant_id: String,
+    pub identity: String,
+    pub display_name: Option<String>,
+    pub version: i64,
+    pub created_at: DateTimeWithTimeZone,
+    pub updated_at: DateTimeWithTimeZone,
+}
+
+///the `event` of the outbox message written with every display name change
+pub const PROFILE_UPDATED: &str = "users.profile_updated";
+
+///payload of [`PROFILE_UPDATED`]
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct ProfileUpdated {
+    pub tenant_id: Option<String>,
+    pub user_id: i64,
+    pub version: i64,
+}
+
+impl From<users::Model> for UserProfile {
+    fn from(user: users::Model) -> Self {
+        Self {
+            id: user.id,
+            tenant_id: user.tenant_id,
+            identity: user.identity,
+            display_name: user.display_name,
+            version: user.version,
+            created_at: user.created_at,
+            updated_at: user.updated_at,
+        }
+    }
+}
+
+#[db_driver]
 impl UserAuthDriver {
+    ///checks `pwd` against the `password_hash` of the identity, in the tenant of the request
+    #[instrument(skip(self, pwd))]
+    pub async fn login(&self, identity: &str, pwd: &str) -> eyre::Result<bool> {
+        let stored: Option<String> =
+            sqlx::query_scalar("SELECT password_hash FROM users WHERE identity = $1")
+                .bind(identity)
+                .fetch_optional(*self.connection.db())
+                .await?;
+        let pwd = pwd.to_owned();
+        //hashing takes a while, keep it off the runtime threads
+        Ok(tokio::task::spawn_blocking(move || match stored {
+            Some(stored) => verify_password(&pwd, &stored),
+            None => {
+                verify_dummy(&pwd);
+                false
+            }
+        })
+        .await?)
+    }
+
+    ///the user belongs to the tenant of the request, fails if the identity is taken there
+    #[instrument(skip(self, pwd))]
+    pub async fn create_user(&self, identity: &str, pwd: &str) -> eyre::Result<UserProfile> {
+        let user = stamp_insert(users::ActiveModel {
+            identity: Set(identity.to_owned()),
+            password_hash: Set(hash_off_runtime(pwd).await?),
+            ..Default::default()
+        });
+        Ok(user.insert(&*self.connection.orm()).await?.into())
+    }
+
+    ///returns `false` if the identity doesn't exist
+    #[instrument(skip(self, pwd))]
+    pub async fn set_password(&self, identity: &str, pwd: &str) -> eyre::Result<bool> {
+        let hash = hash_off_runtime(pwd).await?;
+        let result = sqlx::query(
+            "UPDATE users SET password_hash = $1, updated_at = now() WHERE identity = $2",
+        )
+        .bind(hash)
+        .bind(identity)
+        .execute(*self.connection.db())
+        .await?;
+        Ok(result.rows_affected() > 0)
+    }
+
+    ///never selects the `password_hash`
     #[instrument(skip(self))]
-    pub async fn login(&self, _id: impl Into<String> + Debug, _pwd: &str) -> eyre::Result<bool> {
-        // use sqlx to write plain query
-        // query!("").execute(*self.connection.db()).await?;
-        // or seaorm through *self.connection.orm()
-        Ok(true)
+    pub async fn find_by_identity(&self, identity: &str) -> eyre::Result<Option<UserProfile>> {
+        Ok(users::Entity::find()
+            .filter(users::Column::Identity.eq(identity))
+            .into_partial_model()
+            .one(&*self.connection.orm_read())
+            .await?)
     }
+
+    ///returns the new version, fails with `Conflict` if `version` is outdated. a
+    ///[`PROFILE_UPDATED`] message is committed to the outbox with the change
+    #[instrument(skip(self))]
+    pub async fn update_display_name(
+        &self,
+        id: i64,
+        version: i64,
+        display_name: Option<String>,
+    ) -> eyre::Result<i64> {
+        self.connection
+            .transaction(|tx| {
+                let display_name = display_name.clone();
+                async move {
+                    let version = VersionedUpdate::new("users", id, version)
+                        .set("display_name", display_name)
+                        .set_now("updated_at")
+                        .execute(&mut *tx.conn().await?)
+                        .await?;
+                    let event = ProfileUpdated {
+                        tenant_id: current_tenant(),
+                        user_id: id,
+                        version,
+                    };
+                    //a version is only reached once, so is the key
+                    let key = format!("{PROFILE_UPDATED}:{id}:{version}");
+                    let message = NewOutboxMessage::new(PROFILE_UPDATED, &event, key)?;
+                    OutboxDriver::append(&mut *tx.conn().await?, &message).await?;
+                    Ok(version)
+                }
+            })
+            .await
+    }
+}
+
+///hashing takes a while, keep it off the runtime threads
+async fn hash_off_runtime(pwd: &str) -> eyre::Result<String> {
+    let pwd = pwd.to_owned();
+    tokio::task::spawn_blocking(move || hash_password(&pwd)).await?
 }
@@ -0,0 +1,197 @@
+use sqlx::{Encode, Executor, Postgres, QueryBuilder, Type};
+use std::fmt::{Display, Formatter};
+
+///returned (inside `eyre::Report`) when a row changed since the caller read it, find it with
+///`report.downcast_ref::<Conflict>()`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Conflict {
+    pub table: &'static str,
+    pub id: i64,
+    pub expected: i64,
+    pub current: i64,
+}
+
+impl Display for Conflict {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{} {} is at version {}, expected {}",
+            self.table, self.id, self.current, self.expected
+        )
+    }
+}
+
+impl std::error::Error for Conflict {}
+
+///`UPDATE` that only applies if the row is still at `version`, for tables following the
+///`id BIGINT` + `version BIGINT NOT NULL DEFAULT 1` convention.
+///```ignore
+///let version = VersionedUpdate::new("users", id, version)
+///    .set("display_name", name)
+///    .set_now("updated_at")
+///    .execute(*self.connection.db())
+///    .await?;
+///```
+pub struct VersionedUpdate<'a> {
+    table: &'static str,
+    id: i64,
+    version: i64,
+    filters: Vec<&'static str>,
+    query: QueryBuilder<'a, Postgres>,
+}
+
+impl<'a> VersionedUpdate<'a> {
+    pub fn new(table: &'static str, id: i64, version: i64) -> Self {
+        let query = QueryBuilder::new(format!(
+            "WITH updated AS (UPDATE {table} SET version = version + 1"
+        ));
+        Self {
+            table,
+            id,
+            version,
+            filters: vec![],
+            query,
+        }
+    }
+
+    pub fn set<T>(mut self, column: &'static str, value: T) -> Self
+    where
+        T: 'a + Encode<'a, Postgres> + Type<Postgres>,
+    {
+        self.query
+            .push(format_args!(", {column} = "))
+            .push_bind(value);
+        self
+    }
+
+    pub fn set_now(mut self, column: &'static str) -> Self {
+        self.query.push(format_args!(", {column} = now()"));
+        self
+    }
+
+    ///extra sql condition, a row not matching it is treated as missing
+    pub fn filter(mut self, condition: &'static str) -> Self {
+        self.filters.push(condition);
+        self
+    }
+
+    ///returns the new version, [`Conflict`] if someone else updated the row first and
+    ///`RowNotFound` if it doesn't exist
+    pub async fn execute<'e, E>(mut self, executor: E) -> eyre::Result<i64>
+    where
+        E: Executor<'e, Database = Postgres>,
+    {
+        //the select reads the snapshot from before the update, so the current version is known
+        //without a second round trip
+        let filters: String = self.filters.iter().map(|f| format!(" AND {f}")).collect();
+        self.query
+            .push(" WHERE id = ")
+            .push_bind(self.id)
+            .push(" AND version = ")
+            .push_bind(self.version)
+            .push(&filters)
+            .push(" RETURNING version) SELECT (SELECT version FROM updated), (SELECT version FROM ")
+            .push(self.table)
+            .push(" WHERE id = ")
+            .push_bind(self.id)
+            .push(&filters)
+            .push(")");
+        let (updated, current): (Option<i64>, Option<i64>) =
+            self.query.build_query_as().fetch_one(executor).await?;
+        self.outcome(updated, current)
+    }
+
+    ///`updated` is the version set by the update, `current` the one of the row before it
+    fn outcome(&self, updated: Option<i64>, current: Option<i64>) -> eyre::Result<i64> {
+        match (updated, current) {
+            (Some(version), _) => Ok(version),
+            (None, Some(current)) => Err(Conflict {
+                table: self.table,
+                id: self.id,
+                expected: self.version,
+                current,
+            }
+            .into()),
+            (None, None) => Err(sqlx::Error::RowNotFound.into()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use sqlx::{Connection, PgConnection};
+
+    #[test]
+    fn stale_versions_are_conflicts() {
+        let update = VersionedUpdate::new("users", 7, 2);
+        assert_eq!(update.outcome(Some(3), Some(2)).unwrap(), 3);
+        let e = update.outcome(None, Some(5)).unwrap_err();
+        assert_eq!(
+            e.downcast_ref::<Conflict>(),
+            Some(&Conflict {
+                table: "users",
+                id: 7,
+                expected: 2,
+                current: 5,
+            })
+        );
+        assert_eq!(e.to_string(), "users 7 is at version 5, expected 2");
+        let e = update.outcome(None, None).unwrap_err();
+        assert!(matches!(
+            e.downcast_ref::<sqlx::Error>(),
+            Some(sqlx::Error::RowNotFound)
+        ));
+    }
+
+    #[tokio::test]
+    #[ignore = "needs a postgres server in DATABASE_URL"]
+    async fn updates_only_apply_to_the_read_version() {
+        let url = std::env::var("DATABASE_URL").unwrap();
+        let mut conn = PgConnection::connect(&url).await.unwrap();
+        sqlx::raw_sql(
+            "CREATE TEMP TABLE versioned (id BIGINT PRIMARY KEY, version BIGINT NOT NULL DEFAULT 1, \
+             name TEXT, hidden BOOLEAN NOT NULL DEFAULT false);
+             INSERT INTO versioned (id) VALUES (1), (2);
+             UPDATE versioned SET hidden = true WHERE id = 2",
+        )
+        .execute(&mut conn)
+        .await
+        .unwrap();
+        let rename = |version, name: &'static str| {
+            VersionedUpdate::new("versioned", 1, version)
+                .filter("NOT hidden")
+                .set("name", name)
+        };
+
+        assert_eq!(rename(1, "a").execute(&mut conn).await.unwrap(), 2);
+        //a second writer that read version 1 too
+        let e = rename(1, "b").execute(&mut conn).await.unwrap_err();
+        assert_eq!(
+            e.downcast_ref::<Conflict>(),
+            Some(&Conflict {
+                table: "versioned",
+                id: 1,
+                expected: 1,
+                current: 2,
+            })
+        );
+        let name: String = sqlx::query_scalar("SELECT name FROM versioned WHERE id = 1")
+            .fetch_one(&mut conn)
+            .await
+            .unwrap();
+        assert_eq!(name, "a");
+
+        //rows excluded by the filters are missing, not conflicting
+        let e = VersionedUpdate::new("versioned", 2, 1)
+            .filter("NOT hidden")
+            .set("name", "c")
+            .execute(&mut conn)
+            .await
+            .unwrap_err();
+        assert!(matches!(
+            e.downcast_ref::<sqlx::Error>(),
+            Some(sqlx::Error::RowNotFound)
+        ));
+    }
+}
@@ -0,0 +1,541 @@
+use dotenvy::var;
+use std::collections::HashSet;
+use std::fmt;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use toml_edit::{Array, DocumentMut, Item, Table, Value};
+use yaml_rust2::{Yaml, YamlLoader};
+
+///a layer is read from the first of these that exists
+const EXTENSIONS: [&str; 3] = ["toml", "yaml", "yml"];
+
+///selected with `APP_PROFILE`, `dev` by default
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Profile {
+    Dev,
+    Test,
+    Prod,
+}
+
+impl Profile {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Profile::Dev => "dev",
+            Profile::Test => "test",
+            Profile::Prod => "prod",
+        }
+    }
+}
+
+impl FromStr for Profile {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "dev" => Ok(Profile::Dev),
+            "test" => Ok(Profile::Test),
+            "prod" => Ok(Profile::Prod),
+            _ => Err(format!(
+                "APP_PROFILE: unknown profile {s}, expected dev, test or prod"
+            )),
+        }
+    }
+}
+
+///every problem found while loading the configuration, not only the first one
+#[derive(Debug)]
+pub struct ConfigError {
+    pub errors: Vec<String>,
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "invalid configuration:")?;
+        for error in &self.errors {
+            write!(f, "\n  - {error}")?;
+        }
+        Ok(())
+    }
+}
+
+impl std::error::Error for ConfigError {}
+
+///reads the settings one key at a time, from the first layer that has it:
+///the variable of the key, `local`, `<profile>`, `default` and finally the default of the key.
+///each file layer is a `.toml`, `.yaml` or `.yml` file, missing ones are skipped. invalid values
+///and keys of the files that were never read are collected until [`ConfigLoader::finish`]
+pub struct ConfigLoader {
+    pub profile: Profile,
+    ///lowest priority first
+    files: Vec<(PathBuf, DocumentMut)>,
+    ///every key read so far, the other keys in the files are typos
+    known: HashSet<String>,
+    errors: Vec<String>,
+}
+
+impl ConfigLoader {
+    ///the profile comes from `APP_PROFILE`
+    pub fn new(dir: &Path) -> Self {
+        let (profile, error) = match var("APP_PROFILE") {
+            Ok(profile) => match profile.parse() {
+                Ok(profile) => (profile, None),
+                Err(e) => (Profile::Dev, Some(e)),
+            },
+            Err(_) => (Profile::Dev, None),
+        };
+        let mut loader = Self::with_profile(dir, profile);
+        if let Some(error) = error {
+            loader.errors.insert(0, error);
+        }
+        loader
+    }
+
+    pub fn with_profile(dir: &Path, profile: Profile) -> Self {
+        let mut errors = vec![];
+        let mut files = vec![];
+        for name in ["default", profile.as_str(), "local"] {
+            let paths: Vec<PathBuf> = EXTENSIONS
+                .iter()
+                .map(|extension| dir.join(format!("{name}.{extension}")))
+                .filter(|path| path.is_file())
+                .collect();
+            let path = match paths.as_slice() {
+                [] => continue,
+                [path] => path.clone(),
+                _ => {
+                    let paths: Vec<_> = paths.iter().map(|p| p.display().to_string()).collect();
+                    errors.push(format!("{} all define the {name} layer", paths.join(", ")));
+                    continue;
+                }
+            };
+            let parsed = std::fs::read_to_string(&path)
+                .map_err(|e| e.to_string())
+                .and_then(|content| match path.extension().and_then(|e| e.to_str()) {
+                    Some("toml") => content.parse::<DocumentMut>().map_err(|e| e.to_string()),
+                    _ => yaml_document(&content),
+                });
+            match parsed {
+                Ok(document) => files.push((path, document)),
+                Err(e) => errors.push(format!("{}: {e}", path.display())),
+            }
+        }
+        Self {
+            profile,
+            files,
+            known: HashSet::new(),
+            errors,
+        }
+    }
+
+    ///`key` is the dotted path in the files, `env` the variable overriding it
+    pub fn get<T: ConfigValue>(&mut self, key: &str, env: &str, default: T) -> T {
+        self.read(key, env).ok().flatten().unwrap_or(default)
+    }
+
+    pub fn optional<T: ConfigValue>(&mut self, key: &str, env: &str) -> Option<T> {
+        self.read(key, env).ok().flatten()
+    }
+
+    ///reports it if no layer has it
+    pub fn required<T: ConfigValue>(&mut self, key: &str, env: &str) -> Option<T> {
+        match self.read(key, env) {
+            Ok(Some(value)) => Some(value),
+            Ok(None) => {
+                self.errors
+                    .push(format!("{key} is required, set it or {env}"));
+                None
+            }
+            Err(()) => None,
+        }
+    }
+
+    ///reports `message` unless `valid`
+    pub fn check(&mut self, valid: bool, message: impl Into<String>) {
+        if !valid {
+            self.errors.push(message.into());
+        }
+    }
+
+    ///names of the tables under `key`, in every file
+    pub fn tables(&self, key: &str) -> Vec<String> {
+        let mut names = vec![];
+        for (_, document) in &self.files {
+            let Some(table) = lookup(document, key).and_then(Item::as_table_like) else {
+                continue;
+            };
+            for (name, item) in table.iter() {
+                if item.is_table_like() && !names.iter().any(|n| n == name) {
+                    names.push(name.to_owned());
+                }
+            }
+        }
+        names
+    }
+
+    ///call it once every key was read
+    pub fn finish<T>(mut self, config: T) -> Result<T, ConfigError> {
+        for (path, document) in &self.files {
+            let mut keys = vec![];
+            leaf_keys(document.as_item(), "", &mut keys);
+            let known = |(key, empty_table): &(String, bool)| match empty_table {
+                //a section whose keys are all commented out
+                true => self.known.iter().any(|k| k.starts_with(&format!("{key}."))),
+                false => self.known.contains(key),
+            };
+            for (key, _) in keys.into_iter().filter(|key| !known(key)) {
+                self.errors
+                    .push(format!("{}: unknown key {key}", path.display()));
+            }
+        }
+        match self.errors.is_empty() {
+            true => Ok(config),
+            false => Err(ConfigError {
+                errors: self.errors,
+            }),
+        }
+    }
+
+    ///`Err` once the invalid value is reported, empty variables count as unset
+    fn read<T: ConfigValue>(&mut self, key: &str, env: &str) -> Result<Option<T>, ()> {
+        self.known.insert(key.to_owned());
+        if let Ok(raw) = var(env)
+            && !raw.is_empty()
+        {
+            return T::from_env(&raw)
+                .map(Some)
+                .map_err(|e| self.errors.push(format!("{env}: {e}")));
+        }
+        for (path, document) in self.files.iter().rev() {
+            if let Some(item) = lookup(document, key) {
+                return T::from_toml(item).map(Some).map_err(|e| {
+                    self.errors
+                        .push(format!("{key} in {}: {e}", path.display()))
+                });
+            }
+        }
+        Ok(None)
+    }
+}
+
+fn lookup<'a>(document: &'a DocumentMut, key: &str) -> Option<&'a Item> {
+    key.split('.')
+        .try_fold(document.as_item(), |item, part| item.get(part))
+}
+
+///dotted paths of the values under `item`, arrays are values. empty tables are flagged with `true`
+fn leaf_keys(item: &Item, prefix: &str, keys: &mut Vec<(String, bool)>) {
+    match item.as_table_like() {
+        Some(table) if table.is_empty() && !prefix.is_empty() => {
+            keys.push((prefix.to_owned(), true))
+        }
+        Some(table) => {
+            for (name, item) in table.iter() {
+                let key = match prefix.is_empty() {
+                    true => name.to_owned(),
+                    false => format!("{prefix}.{name}"),
+                };
+                leaf_keys(item, &key, keys);
+            }
+        }
+        None => keys.push((prefix.to_owned(), false)),
+    }
+}
+
+///yaml layers are converted to the tree of the toml ones, nulls count as unset
+fn yaml_document(content: &str) -> Result<DocumentMut, String> {
+    let mut document = DocumentMut::new();
+    match YamlLoader::load_from_str(content)
+        .map_err(|e| e.to_string())?
+        .into_iter()
+        .next()
+    {
+        None | Some(Yaml::Null) => {}
+        Some(Yaml::Hash(hash)) => *document.as_table_mut() = yaml_table(hash)?,
+        Some(_) => return Err("expected a mapping at the top level".to_owned()),
+    }
+    Ok(document)
+}
+
+fn yaml_table(hash: yaml_rust2::yaml::Hash) -> Result<Table, String> {
+    let mut table = Table::new();
+    for (key, value) in hash {
+        let Yaml::String(key) = key else {
+            return Err(format!("expected string keys, got {key:?}"));
+        };
+        match value {
+            Yaml::Null => {}
+            Yaml::Hash(hash) => {
+                table.insert(&key, Item::Table(yaml_table(hash)?));
+            }
+            value => {
+                table.insert(&key, Item::Value(yaml_value(value)?));
+            }
+        }
+    }
+    Ok(table)
+}
+
+fn yaml_value(yaml: Yaml) -> Result<Value, String> {
+    match yaml {
+        Yaml::String(s) => Ok(s.into()),
+        Yaml::Integer(i) => Ok(i.into()),
+        Yaml::Boolean(b) => Ok(b.into()),
+        Yaml::Real(r) => r
+            .parse::<f64>()
+            .map(Value::from)
+            .map_err(|_| format!("invalid number {r}")),
+        Yaml::Array(items) => {
+            let mut array = Array::new();
+            for item in items {
+                array.push(yaml_value(item)?);
+            }
+            Ok(Value::Array(array))
+        }
+        Yaml::Hash(hash) => Ok(Value::InlineTable(yaml_table(hash)?.into_inline_table())),
+        yaml => Err(format!("unsupported value {yaml:?}")),
+    }
+}
+
+///a type a setting can be read as
+pub trait ConfigValue: Sized {
+    fn from_env(raw: &str) -> Result<Self, String>;
+    fn from_toml(item: &Item) -> Result<Self, String>;
+}
+
+impl ConfigValue for String {
+    fn from_env(raw: &str) -> Result<Self, String> {
+        Ok(raw.to_owned())
+    }
+
+    fn from_toml(item: &Item) -> Result<Self, String> {
+        item.as_str()
+            .map(ToOwned::to_owned)
+            .ok_or_else(|| "expected a string".to_owned())
+    }
+}
+
+impl ConfigValue for bool {
+    fn from_env(raw: &str) -> Result<Self, String> {
+        raw.trim()
+            .parse()
+            .map_err(|_| format!("expected true or false, got {raw}"))
+    }
+
+    fn from_toml(item: &Item) -> Result<Self, String> {
+        item.as_bool()
+            .ok_or_else(|| "expected true or false".to_owned())
+    }
+}
+
+macro_rules! impl_config_integer {
+    ($( $t:ty ),+) => {
+        $(
+            impl ConfigValue for $t {
+                fn from_env(raw: &str) -> Result<Self, String> {
+                    raw.trim()
+                        .parse()
+                        .map_err(|_| format!("expected a {}, got {raw}", stringify!($t)))
+                }
+
+                fn from_toml(item: &Item) -> Result<Self, String> {
+                    item.as_integer()
+                        .and_then(|i| <$t>::try_from(i).ok())
+                        .ok_or_else(|| format!("expected a {}", stringify!($t)))
+                }
+            }
+        )+
+    };
+}
+
+impl_config_integer!(u16, u32, u64, usize);
+
+///comma separated in a variable, an array in the files
+impl ConfigValue for Vec<String> {
+    fn from_env(raw: &str) -> Result<Self, String> {
+        Ok(raw
+            .split(',')
+            .map(str::trim)
+            .filter(|v| !v.is_empty())
+            .map(ToOwned::to_owned)
+            .collect())
+    }
+
+    fn from_toml(item: &Item) -> Result<Self, String> {
+        let array = item
+            .as_array()
+            .ok_or_else(|| "expected an array of strings".to_owned())?;
+        array
+            .iter()
+            .map(|v| {
+                v.as_str()
+                    .map(ToOwned::to_owned)
+                    .ok_or_else(|| "expected an array of strings".to_owned())
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+
+    fn dir(files: &[(&str, &str)]) -> TempDir {
+        let dir = tempfile::tempdir().unwrap();
+        for (name, content) in files {
+            std::fs::write(dir.path().join(name), content).unwrap();
+        }
+        dir
+    }
+
+    ///variables are process wide, every test uses its own names
+    fn set_env(name: &str, value: &str) {
+        unsafe { std::env::set_var(name, value) };
+    }
+
+    #[test]
+    fn later_layers_win() {
+        let dir = dir(&[
+            ("default.toml", "[http]\nport = 1\nbuffer = 1\ntimeout = 1"),
+            ("prod.toml", "[http]\nport = 2\nbuffer = 2"),
+            ("local.toml", "[http]\nport = 3"),
+        ]);
+        let mut c = ConfigLoader::with_profile(dir.path(), Profile::Prod);
+        assert_eq!(c.get("http.port", "LAYERS_PORT", 0u16), 3);
+        assert_eq!(c.get("http.buffer", "LAYERS_BUFFER", 0u16), 2);
+        assert_eq!(c.get("http.timeout", "LAYERS_TIMEOUT", 0u16), 1);
+        assert_eq!(c.get("http.missing", "LAYERS_MISSING", 4u16), 4);
+        //the files of the other profiles are ignored
+        let mut c = ConfigLoader::with_profile(dir.path(), Profile::Test);
+        assert_eq!(c.get("http.port", "LAYERS_PORT", 0u16), 3);
+        assert_eq!(c.get("http.buffer", "LAYERS_BUFFER", 0u16), 1);
+        assert_eq!(c.get("http.timeout", "LAYERS_TIMEOUT", 0u16), 1);
+        assert!(c.finish(()).is_ok());
+    }
+
+    #[test]
+    fn variables_override_every_file() {
+        let dir = dir(&[("default.toml", "port = 1\nname = \"file\"")]);
+        set_env("OVERRIDE_PORT", "8080");
+        set_env("OVERRIDE_NAME", "");
+        set_env("OVERRIDE_ORIGINS", "a, b,,c");
+        let mut c = ConfigLoader::with_profile(dir.path(), Profile::Dev);
+        assert_eq!(c.get("port", "OVERRIDE_PORT", 0u16), 8080);
+        //empty variables count as unset
+        assert_eq!(c.get("name", "OVERRIDE_NAME", String::new()), "file");
+        assert_eq!(
+            c.get("origins", "OVERRIDE_ORIGINS", vec![]),
+            vec!["a".to_owned(), "b".to_owned(), "c".to_owned()]
+        );
+        assert!(c.finish(()).is_ok());
+    }
+
+    #[test]
+    fn yaml_layers_mix_with_toml() {
+        let dir = dir(&[
+            ("default.toml", "[cache]\nredis_url = \"redis://default\""),
+            (
+                "prod.yaml",
+                "cache:\n  redis_url: redis://prod\n  named:\n    users:\n      capacity: 5\n\
+                 http:\n  cors_origins: [a, b]\n  port: ~\n",
+            ),
+            ("local.yml", "http:\n  enabled: true\n"),
+        ]);
+        let mut c = ConfigLoader::with_profile(dir.path(), Profile::Prod);
+        let url: String = c.get("cache.redis_url", "YAML_REDIS_URL", String::new());
+        assert_eq!(url, "redis://prod");
+        assert_eq!(
+            c.get("cache.named.users.capacity", "YAML_CAPACITY", 0u64),
+            5
+        );
+        assert_eq!(c.tables("cache.named"), vec!["users".to_owned()]);
+        assert_eq!(
+            c.get("http.cors_origins", "YAML_ORIGINS", vec![]),
+            vec!["a".to_owned(), "b".to_owned()]
+        );
+        assert!(c.get("http.enabled", "YAML_ENABLED", false));
+        //null is unset
+        assert_eq!(c.get("http.port", "YAML_PORT", 7u16), 7);
+        assert!(c.finish(()).is_ok());
+    }
+
+    #[test]
+    fn finish_reports_every_error() {
+        let dir = dir(&[
+            ("default.toml", "port = \"eighty\"\nlist = 3"),
+            ("dev.toml", "not toml ="),
+            ("local.toml", ""),
+            ("local.yaml", ""),
+        ]);
+        set_env("ERRORS_ENABLED", "yes");
+        let mut c = ConfigLoader::with_profile(dir.path(), Profile::Dev);
+        assert_eq!(c.get("port", "ERRORS_PORT", 80u16), 80);
+        let _: Vec<String> = c.get("list", "ERRORS_LIST", vec![]);
+        assert!(!c.get("enabled", "ERRORS_ENABLED", false));
+        let _: Option<String> = c.required("url", "ERRORS_URL");
+        c.check(false, "custom check");
+        let errors = c.finish(()).unwrap_err().errors;
+        assert_eq!(errors.len(), 7, "{errors:#?}");
+        for expected in [
+            "dev.toml:",
+            "all define the local layer",
+            "port in",
+            "list in",
+            "ERRORS_ENABLED: expected true or false, got yes",
+            "url is required, set it or ERRORS_URL",
+            "custom check",
+        ] {
+            assert!(
+                errors.iter().any(|e| e.contains(expected)),
+                "{expected} not in {errors:#?}"
+            );
+        }
+    }
+
+    #[test]
+    fn unknown_keys_are_reported() {
+        let dir = dir(&[
+            (
+                "default.toml",
+                "[databse]\nurl = \"x\"\n[http]\nprot = 1\nport = 2\n[empty]\n[cache.users]",
+            ),
+            (
+                "dev.yaml",
+                "http:\n  port: 3\n  cors_origins: [a]\ncache:\n  users:\n    capcity: 1\n",
+            ),
+        ]);
+        let mut c = ConfigLoader::with_profile(dir.path(), Profile::Dev);
+        assert_eq!(c.get("http.port", "UNKNOWN_PORT", 0u16), 3);
+        let _: Vec<String> = c.get("http.cors_origins", "UNKNOWN_ORIGINS", vec![]);
+        let _: Option<u64> = c.optional("cache.users.capacity", "UNKNOWN_CAPACITY");
+        let errors = c.finish(()).unwrap_err().errors;
+        assert_eq!(errors.len(), 4, "{errors:#?}");
+        for expected in [
+            "default.toml: unknown key databse.url",
+            "default.toml: unknown key http.prot",
+            "default.toml: unknown key empty",
+            "dev.yaml: unknown key cache.users.capcity",
+        ] {
+            assert!(
+                errors.iter().any(|e| e.ends_with(expected)),
+                "{expected} not in {errors:#?}"
+            );
+        }
+    }
+
+    #[test]
+    fn invalid_yaml_is_reported() {
+        let dir = dir(&[("default.yaml", "- a\n- b\n"), ("dev.yml", "a: [b\n")]);
+        let c = ConfigLoader::with_profile(dir.path(), Profile::Dev);
+        let errors = c.finish(()).unwrap_err().errors;
+        assert_eq!(errors.len(), 2, "{errors:#?}");
+        assert!(errors[0].contains("expected a mapping at the top level"));
+        assert!(errors[1].contains("dev.yml"));
+    }
+
+    #[test]
+    fn unknown_profiles_are_reported() {
+        assert!("staging".parse::<Profile>().is_err());
+        assert_eq!("prod".parse::<Profile>(), Ok(Profile::Prod));
+    }
+}
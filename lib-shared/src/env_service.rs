@@ -1,37 +1,404 @@
+use crate::config::{ConfigError, ConfigLoader, Profile};
 use dotenvy::var;
+use std::collections::HashMap;
 use std::ops::Deref;
+use std::path::Path;
 use std::sync::Arc;
+use std::time::Duration;
+
+///only accepted by the `dev` and `test` profiles, `prod` requires `JWT_SECRET`
+const DEV_JWT_SECRET: &str = "RdtcDAYypWNCrbvsDHZa1PaiZ727GAKr8WfVvDGtQj7nPAh9Bi1YjtTQL4IEMQ0E2r9uIGcsePMJM6hp1z8VNZ7k0k2qQHjKfxZQ";
+const MIN_JWT_SECRET_LEN: usize = 32;
+const CACHE_SETTINGS: [&str; 3] = ["capacity", "ttl_secs", "tti_secs"];
 
 #[derive(Clone)]
 pub struct EnvService {
-    inner: Arc<EnvServiceInner>,
+    inner: Arc<AppConfig>,
+}
+
+///every setting of the app, see [`EnvService::load`] for where they come from
+pub struct AppConfig {
+    pub profile: Profile,
+    pub database: DatabaseConfig,
+    pub http: HttpConfig,
+    pub auth: AuthConfig,
+    pub tenant: TenantConfig,
+    pub cache: CacheConfig,
+}
+
+pub struct DatabaseConfig {
+    pub url: String,
+    ///empty if reads go to the primary
+    pub replica_urls: Vec<String>,
+    pub pool: PsqlPoolEnv,
+    pub migrate_on_startup: bool,
+}
+
+pub struct HttpConfig {
+    pub port: u16,
+    ///slower requests get a `408`
+    pub request_timeout: Duration,
+    ///requests waiting for a handler, the next ones are rejected
+    pub buffer_size: usize,
+    ///allowed by cors, every origin if empty
+    pub cors_origins: Vec<String>,
+}
+
+pub struct AuthConfig {
+    pub jwt_secret: String,
+    pub token_ttl: Duration,
+    ///`tenant:identity` entries, they get the `admin` role when logging in to that tenant.
+    ///identities are only unique per tenant, see [`AuthConfig::is_admin`]
+    pub admin_identities: Vec<String>,
 }
-pub struct EnvServiceInner {
-    pub psql_url: String,
-    pub api_port: usize,
+
+impl AuthConfig {
+    pub fn is_admin(&self, tenant: &str, identity: &str) -> bool {
+        self.admin_identities
+            .iter()
+            .filter_map(|entry| entry.split_once(':'))
+            .any(|entry| entry == (tenant, identity))
+    }
+}
+
+pub struct TenantConfig {
+    ///explicit tenant of a request
+    pub header: String,
+    ///with `example.com` a request to `acme.example.com` is for the `acme` tenant
+    pub base_domain: Option<String>,
+}
+
+pub struct CacheConfig {
+    ///any redis compatible server. when set the named caches keep a second tier there, shared
+    ///by every instance
+    pub redis_url: Option<String>,
+    named: HashMap<String, CacheOverride>,
+}
+
+///pool settings shared by the primary and the replicas, durations set to `0` are disabled
+#[derive(Clone, Debug)]
+pub struct PsqlPoolEnv {
+    pub max_connections: u32,
+    pub min_connections: u32,
+    pub acquire_timeout: Duration,
+    pub idle_timeout: Option<Duration>,
+    pub max_lifetime: Option<Duration>,
+    pub statement_cache_capacity: usize,
+    pub application_name: String,
+    pub search_path: Option<String>,
+    pub statement_timeout: Option<Duration>,
+    ///queries slower than this are logged at warn level
+    pub slow_query_threshold: Option<Duration>,
+    ///attempts before giving up on the first connection at startup
+    pub connect_retries: u32,
+    ///doubled after every failed attempt
+    pub connect_backoff: Duration,
+}
+
+///settings of one named cache, see [`CacheConfig::named`]
+#[derive(Clone, Copy, Debug)]
+pub struct CacheEnv {
+    pub capacity: u64,
+    ///since the entry was inserted
+    pub ttl: Option<Duration>,
+    ///since the entry was last read
+    pub tti: Option<Duration>,
+}
+
+struct CacheOverride {
+    capacity: Option<u64>,
+    ttl_secs: Option<u64>,
+    tti_secs: Option<u64>,
+}
+
+impl CacheConfig {
+    ///`[cache.<name>]` in the files or `CACHE_<NAME>_CAPACITY`, `CACHE_<NAME>_TTL_SECS` and
+    ///`CACHE_<NAME>_TTI_SECS` override `default`, `0` disables an expiry
+    pub fn named(&self, name: &str, default: CacheEnv) -> CacheEnv {
+        let Some(config) = self.named.get(name) else {
+            return default;
+        };
+        let expiry = |secs: Option<u64>, default| match secs {
+            Some(0) => None,
+            Some(secs) => Some(Duration::from_secs(secs)),
+            None => default,
+        };
+        CacheEnv {
+            capacity: config.capacity.unwrap_or(default.capacity),
+            ttl: expiry(config.ttl_secs, default.ttl),
+            tti: expiry(config.tti_secs, default.tti),
+        }
+    }
 }
 
 impl EnvService {
+    ///the defaults below, overridden by `default.toml`, `<APP_PROFILE>.toml` and `local.toml` of
+    ///`CONFIG_DIR` (`config` by default), overridden by the variables named next to each key.
+    ///the error lists every invalid setting and every unknown key of the files
     #[tracing::instrument]
-    pub fn new() -> Self {
-        Self {
-            inner: Arc::new(EnvServiceInner {
-                psql_url: var("DATABASE_URL").unwrap().to_owned(),
-                api_port: var("PORT").unwrap().parse().unwrap(),
-            }),
+    pub fn load() -> Result<Self, ConfigError> {
+        let dir = var("CONFIG_DIR").unwrap_or_else(|_| "config".to_owned());
+        let mut c = ConfigLoader::new(Path::new(&dir));
+        let config = AppConfig {
+            profile: c.profile,
+            database: DatabaseConfig {
+                url: c
+                    .required("database.url", "DATABASE_URL")
+                    .unwrap_or_default(),
+                replica_urls: c.get("database.replica_urls", "DATABASE_REPLICA_URLS", vec![]),
+                pool: PsqlPoolEnv {
+                    max_connections: c.get(
+                        "database.pool.max_connections",
+                        "DB_MAX_CONNECTIONS",
+                        10,
+                    ),
+                    min_connections: c.get(
+                        "database.pool.min_connections",
+                        "DB_MIN_CONNECTIONS",
+                        0,
+                    ),
+                    acquire_timeout: Duration::from_secs(c.get(
+                        "database.pool.acquire_timeout_secs",
+                        "DB_ACQUIRE_TIMEOUT_SECS",
+                        30,
+                    )),
+                    idle_timeout: secs(c.get(
+                        "database.pool.idle_timeout_secs",
+                        "DB_IDLE_TIMEOUT_SECS",
+                        600,
+                    )),
+                    max_lifetime: secs(c.get(
+                        "database.pool.max_lifetime_secs",
+                        "DB_MAX_LIFETIME_SECS",
+                        1800,
+                    )),
+                    statement_cache_capacity: c.get(
+                        "database.pool.statement_cache_capacity",
+                        "DB_STATEMENT_CACHE_CAPACITY",
+                        100,
+                    ),
+                    application_name: c.get(
+                        "database.pool.application_name",
+                        "DB_APPLICATION_NAME",
+                        "axum-api-template".to_owned(),
+                    ),
+                    search_path: c.optional("database.pool.search_path", "DB_SEARCH_PATH"),
+                    statement_timeout: millis(c.get(
+                        "database.pool.statement_timeout_ms",
+                        "DB_STATEMENT_TIMEOUT_MS",
+                        0,
+                    )),
+                    slow_query_threshold: millis(c.get(
+                        "database.pool.slow_query_ms",
+                        "DB_SLOW_QUERY_MS",
+                        500,
+                    )),
+                    connect_retries: c.get(
+                        "database.pool.connect_retries",
+                        "DB_CONNECT_RETRIES",
+                        10,
+                    ),
+                    connect_backoff: Duration::from_millis(c.get(
+                        "database.pool.connect_backoff_ms",
+                        "DB_CONNECT_BACKOFF_MS",
+                        500,
+                    )),
+                },
+                migrate_on_startup: c.get(
+                    "database.migrate_on_startup",
+                    "MIGRATE_ON_STARTUP",
+                    false,
+                ),
+            },
+            http: HttpConfig {
+                port: c.get("http.port", "PORT", 8080),
+                request_timeout: Duration::from_secs(c.get(
+                    "http.request_timeout_secs",
+                    "HTTP_REQUEST_TIMEOUT_SECS",
+                    10,
+                )),
+                buffer_size: c.get("http.buffer_size", "HTTP_BUFFER_SIZE", 1024),
+                cors_origins: c.get("http.cors_origins", "HTTP_CORS_ORIGINS", vec![]),
+            },
+            auth: AuthConfig {
+                jwt_secret: match c.profile {
+                    Profile::Prod => c.required("auth.jwt_secret", "JWT_SECRET"),
+                    Profile::Dev | Profile::Test => c.optional("auth.jwt_secret", "JWT_SECRET"),
+                }
+                .unwrap_or_else(|| match c.profile {
+                    Profile::Prod => String::new(),
+                    Profile::Dev | Profile::Test => DEV_JWT_SECRET.to_owned(),
+                }),
+                token_ttl: Duration::from_secs(c.get(
+                    "auth.token_ttl_secs",
+                    "TOKEN_TTL_SECS",
+                    10 * 60 * 60,
+                )),
+                admin_identities: c.get("auth.admin_identities", "ADMIN_IDENTITIES", vec![]),
+            },
+            tenant: TenantConfig {
+                header: c.get("tenant.header", "TENANT_HEADER", "x-tenant-id".to_owned()),
+                base_domain: c.optional("tenant.base_domain", "TENANT_BASE_DOMAIN"),
+            },
+            cache: CacheConfig {
+                redis_url: c.optional("cache.redis_url", "CACHE_REDIS_URL"),
+                named: named_caches(&mut c),
+            },
+        };
+        validate(&mut c, &config);
+        c.finish(Self {
+            inner: Arc::new(config),
+        })
+    }
+}
+
+///the caches named in the files or in a `CACHE_<NAME>_<SETTING>` variable
+fn named_caches(c: &mut ConfigLoader) -> HashMap<String, CacheOverride> {
+    let mut names = c.tables("cache");
+    for (key, _) in std::env::vars() {
+        let Some(rest) = key.strip_prefix("CACHE_") else {
+            continue;
+        };
+        for setting in CACHE_SETTINGS {
+            let suffix = format!("_{}", setting.to_uppercase());
+            if let Some(name) = rest.strip_suffix(&suffix).map(str::to_lowercase)
+                && !names.contains(&name)
+            {
+                names.push(name);
+            }
         }
     }
+    let mut named = HashMap::new();
+    for name in names {
+        let mut read = |setting: &str| {
+            c.optional::<u64>(
+                &format!("cache.{name}.{setting}"),
+                &format!("CACHE_{}_{}", name.to_uppercase(), setting.to_uppercase()),
+            )
+        };
+        let config = CacheOverride {
+            capacity: read("capacity"),
+            ttl_secs: read("ttl_secs"),
+            tti_secs: read("tti_secs"),
+        };
+        named.insert(name, config);
+    }
+    named
 }
 
-impl Default for EnvService {
-    fn default() -> Self {
-        Self::new()
+fn validate(c: &mut ConfigLoader, config: &AppConfig) {
+    let db = &config.database;
+    for url in std::iter::once(&db.url).chain(&db.replica_urls) {
+        c.check(
+            url.is_empty() || url.starts_with("postgres://") || url.starts_with("postgresql://"),
+            format!("database url {url} must start with postgres:// or postgresql://"),
+        );
+    }
+    c.check(
+        db.pool.max_connections > 0,
+        "database.pool.max_connections must be at least 1",
+    );
+    c.check(
+        db.pool.min_connections <= db.pool.max_connections,
+        "database.pool.min_connections can't exceed max_connections",
+    );
+    c.check(
+        !db.pool.acquire_timeout.is_zero(),
+        "database.pool.acquire_timeout_secs must be at least 1",
+    );
+
+    let http = &config.http;
+    c.check(http.port > 0, "http.port must be at least 1");
+    c.check(
+        !http.request_timeout.is_zero(),
+        "http.request_timeout_secs must be at least 1",
+    );
+    c.check(http.buffer_size > 0, "http.buffer_size must be at least 1");
+    for origin in &http.cors_origins {
+        c.check(
+            origin.starts_with("http://") || origin.starts_with("https://"),
+            format!("cors origin {origin} must start with http:// or https://"),
+        );
+    }
+
+    let auth = &config.auth;
+    //a missing one is already reported
+    c.check(
+        auth.jwt_secret.is_empty() || auth.jwt_secret.len() >= MIN_JWT_SECRET_LEN,
+        format!("auth.jwt_secret must be at least {MIN_JWT_SECRET_LEN} characters"),
+    );
+    c.check(
+        config.profile != Profile::Prod || auth.jwt_secret != DEV_JWT_SECRET,
+        "auth.jwt_secret can't be the development secret in prod",
+    );
+    c.check(
+        !auth.token_ttl.is_zero(),
+        "auth.token_ttl_secs must be at least 1",
+    );
+    for entry in &auth.admin_identities {
+        c.check(
+            matches!(entry.split_once(':'), Some((tenant, identity)) if !tenant.is_empty() && !identity.is_empty()),
+            format!("auth.admin_identities entry {entry} must be tenant:identity"),
+        );
+    }
+
+    let header = &config.tenant.header;
+    c.check(
+        !header.is_empty()
+            && header
+                .chars()
+                .all(|ch| ch.is_ascii_alphanumeric() || ch == '-' || ch == '_'),
+        format!("tenant.header {header} isn't a valid header name"),
+    );
+
+    if let Some(url) = &config.cache.redis_url {
+        c.check(
+            ["redis://", "rediss://", "redis+unix://", "unix://"]
+                .iter()
+                .any(|scheme| url.starts_with(scheme)),
+            "cache.redis_url must be a redis://, rediss:// or unix:// url",
+        );
+    }
+    for (name, cache) in &config.cache.named {
+        c.check(
+            cache.capacity != Some(0),
+            format!("cache.{name}.capacity must be at least 1"),
+        );
     }
 }
 
+///`0` disables it
+fn secs(secs: u64) -> Option<Duration> {
+    (secs > 0).then(|| Duration::from_secs(secs))
+}
+
+///`0` disables it
+fn millis(ms: u64) -> Option<Duration> {
+    (ms > 0).then(|| Duration::from_millis(ms))
+}
+
 impl Deref for EnvService {
-    type Target = EnvServiceInner;
+    type Target = AppConfig;
     fn deref(&self) -> &Self::Target {
         &self.inner
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn admins_are_scoped_to_their_tenant() {
+        let auth = AuthConfig {
+            jwt_secret: DEV_JWT_SECRET.to_owned(),
+            token_ttl: Duration::from_secs(60),
+            admin_identities: vec!["acme:admin".to_owned(), "globex:alice".to_owned()],
+        };
+        assert!(auth.is_admin("acme", "admin"));
+        assert!(auth.is_admin("globex", "alice"));
+        assert!(!auth.is_admin("globex", "admin"));
+        assert!(!auth.is_admin("acme", "alice"));
+        assert!(!auth.is_admin("", "admin"));
+    }
+}
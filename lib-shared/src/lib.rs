@@ -1,3 +1,4 @@
+pub mod config;
 pub mod env_service;
 
 use dotenvy::{dotenv, var};
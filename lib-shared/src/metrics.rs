@@ -21,14 +21,40 @@ pub struct Metrics {
     pub ws_connections: Arc<Gauge<u64>>,
     pub db_exec_time: Arc<Histogram<f64>>,
     pub db_call_count: Arc<Counter<u64>>,
+    pub db_metric_dropped: Arc<Counter<u64>>,
+    pub db_replica_status: Arc<Gauge<u64>>,
+    pub db_pool_size: Arc<Gauge<u64>>,
+    pub db_pool_idle: Arc<Gauge<u64>>,
+    pub db_pool_acquire_duration: Arc<Histogram<f64>>,
+    pub db_pool_acquire_timeouts: Arc<Counter<u64>>,
+    pub db_pool_connection_errors: Arc<Counter<u64>>,
     pub signup_count: Arc<Counter<u64>>,
     pub login_count: Arc<Counter<u64>>,
+    pub profile_update_count: Arc<Counter<u64>>,
+    pub job_run_count: Arc<Counter<u64>>,
+    pub job_duration: Arc<Histogram<f64>>,
+    pub job_skip_count: Arc<Counter<u64>>,
+    pub queue_job_count: Arc<Counter<u64>>,
+    pub queue_job_duration: Arc<Histogram<f64>>,
+    pub leader_status: Arc<Gauge<u64>>,
+    pub event_published_count: Arc<Counter<u64>>,
+    pub event_dropped_count: Arc<Counter<u64>>,
+    pub event_handler_duration: Arc<Histogram<f64>>,
+    pub event_handler_failures: Arc<Counter<u64>>,
+    pub outbox_dispatched_count: Arc<Counter<u64>>,
+    pub cache_hits: Arc<Counter<u64>>,
+    pub cache_misses: Arc<Counter<u64>>,
+    pub cache_evictions: Arc<Counter<u64>>,
+    pub cache_entries: Arc<Gauge<u64>>,
+    pub single_flight_coalesced: Arc<Counter<u64>>,
 }
 
 impl Metrics {
     pub fn new() -> Self {
-        let meter = global::meter("axum-api-template-metrics");
+        Self::with_meter(global::meter("axum-api-template-metrics"))
+    }
 
+    pub fn with_meter(meter: Meter) -> Self {
         let request_count = meter.u64_counter("http.requests.count").build();
         let request_duration = meter
             .f64_histogram("http.request.duration")
@@ -45,6 +71,35 @@ impl Metrics {
             .u64_counter("db.calls.count")
             .with_description("Number of database calls")
             .build();
+        let db_metric_dropped = meter
+            .u64_counter("db.metrics.dropped")
+            .with_description("Db call metrics dropped because the collector fell behind")
+            .build();
+        let db_replica_status = meter
+            .u64_gauge("db.replica.status")
+            .with_description("1 if the read replica passed its last health check")
+            .build();
+        let db_pool_size = meter
+            .u64_gauge("db.pool.size")
+            .with_description("Open connections of the pool")
+            .build();
+        let db_pool_idle = meter
+            .u64_gauge("db.pool.idle")
+            .with_description("Idle connections of the pool")
+            .build();
+        let db_pool_acquire_duration = meter
+            .f64_histogram("db.pool.acquire.duration")
+            .with_description("Time waited to acquire a connection")
+            .with_unit("s")
+            .build();
+        let db_pool_acquire_timeouts = meter
+            .u64_counter("db.pool.acquire.timeouts")
+            .with_description("Acquisitions that hit the acquire timeout")
+            .build();
+        let db_pool_connection_errors = meter
+            .u64_counter("db.pool.connection.errors")
+            .with_description("Failed attempts to open or reach a connection")
+            .build();
         let db_exec_time = meter
             .f64_histogram("db.call.duration")
             .with_description("Database call execution time")
@@ -55,15 +110,111 @@ impl Metrics {
             .u64_counter("auth.logins.count")
             .with_description("Number of successful logins")
             .build();
+        let profile_update_count = meter
+            .u64_counter("auth.profile_updates.count")
+            .with_description("Number of profile updates delivered by the outbox")
+            .build();
         let signup_count = meter
             .u64_counter("auth.signups.count")
             .with_description("Number of successful signups")
             .build();
 
+        let job_run_count = meter
+            .u64_counter("jobs.runs.count")
+            .with_description("Number of scheduled job runs by outcome")
+            .build();
+        let job_duration = meter
+            .f64_histogram("jobs.run.duration")
+            .with_description("Scheduled job execution time")
+            .with_unit("s")
+            .build();
+        let job_skip_count = meter
+            .u64_counter("jobs.skips.count")
+            .with_description("Number of job runs skipped due to an active previous run")
+            .build();
+
+        let queue_job_count = meter
+            .u64_counter("queue.jobs.count")
+            .with_description("Number of processed queue jobs by kind and outcome")
+            .build();
+        let queue_job_duration = meter
+            .f64_histogram("queue.job.duration")
+            .with_description("Queue job execution time")
+            .with_unit("s")
+            .build();
+
+        let leader_status = meter
+            .u64_gauge("cluster.leader.status")
+            .with_description("1 if this instance is the current leader")
+            .build();
+
+        let event_published_count = meter
+            .u64_counter("events.published.count")
+            .with_description("Number of published domain events")
+            .build();
+        let event_dropped_count = meter
+            .u64_counter("events.dropped.count")
+            .with_description("Number of events dropped by full or closed subscribers")
+            .build();
+        let event_handler_duration = meter
+            .f64_histogram("events.handler.duration")
+            .with_description("Event subscriber execution time")
+            .with_unit("s")
+            .build();
+        let event_handler_failures = meter
+            .u64_counter("events.handler.failures")
+            .with_description("Number of failed event subscriber runs")
+            .build();
+
+        let outbox_dispatched_count = meter
+            .u64_counter("outbox.dispatched.count")
+            .with_description("Number of relayed outbox messages by sink and outcome")
+            .build();
+
+        let cache_hits = meter
+            .u64_counter("cache.hits")
+            .with_description("Number of cache lookups that found an entry, by cache")
+            .build();
+        let cache_misses = meter
+            .u64_counter("cache.misses")
+            .with_description("Number of cache lookups that found nothing, by cache")
+            .build();
+        let cache_evictions = meter
+            .u64_counter("cache.evictions")
+            .with_description("Number of entries evicted for size or expiry, by cache and cause")
+            .build();
+        let cache_entries = meter
+            .u64_gauge("cache.entries")
+            .with_description("Approximate number of entries, by cache")
+            .build();
+        let single_flight_coalesced = meter
+            .u64_counter("single_flight.coalesced")
+            .with_description(
+                "Number of calls that waited for the same computation of another, by flight",
+            )
+            .build();
+
         let sys = Arc::new(Mutex::new(System::new_all()));
         Self {
             signup_count: Arc::new(signup_count),
             login_count: Arc::new(login_count),
+            profile_update_count: Arc::new(profile_update_count),
+            job_run_count: Arc::new(job_run_count),
+            job_duration: Arc::new(job_duration),
+            job_skip_count: Arc::new(job_skip_count),
+            queue_job_count: Arc::new(queue_job_count),
+            queue_job_duration: Arc::new(queue_job_duration),
+            leader_status: Arc::new(leader_status),
+            event_published_count: Arc::new(event_published_count),
+            event_dropped_count: Arc::new(event_dropped_count),
+            event_handler_duration: Arc::new(event_handler_duration),
+            event_handler_failures: Arc::new(event_handler_failures),
+            outbox_dispatched_count: Arc::new(outbox_dispatched_count),
+            cache_hits: Arc::new(cache_hits),
+            cache_misses: Arc::new(cache_misses),
+            cache_evictions: Arc::new(cache_evictions),
+            cache_entries: Arc::new(cache_entries),
+            single_flight_coalesced: Arc::new(single_flight_coalesced),
             request_count: Arc::new(request_count),
             request_duration: Arc::new(request_duration),
             active_requests: Arc::new(active_requests),
@@ -73,6 +224,13 @@ impl Metrics {
             ws_connections: Arc::new(ws_connections),
             db_exec_time: Arc::new(db_exec_time),
             db_call_count: Arc::new(db_call_count),
+            db_metric_dropped: Arc::new(db_metric_dropped),
+            db_replica_status: Arc::new(db_replica_status),
+            db_pool_size: Arc::new(db_pool_size),
+            db_pool_idle: Arc::new(db_pool_idle),
+            db_pool_acquire_duration: Arc::new(db_pool_acquire_duration),
+            db_pool_acquire_timeouts: Arc::new(db_pool_acquire_timeouts),
+            db_pool_connection_errors: Arc::new(db_pool_connection_errors),
             meter,
             sys,
         }
@@ -111,3 +269,115 @@ impl Default for Metrics {
         Self::new()
     }
 }
+
+///reads the metrics recorded through a [`Metrics`] on demand, for the tests
+#[cfg(feature = "test-util")]
+pub mod testing {
+    use super::Metrics;
+    use opentelemetry::KeyValue;
+    use opentelemetry::metrics::MeterProvider;
+    use opentelemetry_sdk::error::OTelSdkResult;
+    use opentelemetry_sdk::metrics::data::{AggregatedMetrics, MetricData, ResourceMetrics};
+    use opentelemetry_sdk::metrics::reader::MetricReader;
+    use opentelemetry_sdk::metrics::{
+        InstrumentKind, ManualReader, Pipeline, SdkMeterProvider, Temporality,
+    };
+    use std::sync::{Arc, Weak};
+    use std::time::Duration;
+
+    #[derive(Clone, Debug)]
+    pub struct MetricsReader {
+        reader: Arc<ManualReader>,
+        _provider: SdkMeterProvider,
+    }
+
+    ///the provider owns its reader, this one shares it with the [`MetricsReader`]
+    #[derive(Debug)]
+    struct SharedReader(Arc<ManualReader>);
+
+    impl MetricReader for SharedReader {
+        fn register_pipeline(&self, pipeline: Weak<Pipeline>) {
+            self.0.register_pipeline(pipeline)
+        }
+        fn collect(&self, rm: &mut ResourceMetrics) -> OTelSdkResult {
+            self.0.collect(rm)
+        }
+        fn force_flush(&self) -> OTelSdkResult {
+            self.0.force_flush()
+        }
+        fn shutdown_with_timeout(&self, timeout: Duration) -> OTelSdkResult {
+            self.0.shutdown_with_timeout(timeout)
+        }
+        fn temporality(&self, kind: InstrumentKind) -> Temporality {
+            self.0.temporality(kind)
+        }
+    }
+
+    impl MetricsReader {
+        ///metrics that aren't exported anywhere, only read by the returned reader
+        pub fn new() -> (Metrics, Self) {
+            let reader = Arc::new(ManualReader::builder().build());
+            let provider = SdkMeterProvider::builder()
+                .with_reader(SharedReader(reader.clone()))
+                .build();
+            let metrics = Metrics::with_meter(provider.meter("test"));
+            let reader = Self {
+                reader,
+                _provider: provider,
+            };
+            (metrics, reader)
+        }
+
+        ///total of the counter `name` over the points carrying every label of `labels`
+        pub fn sum(&self, name: &str, labels: &[KeyValue]) -> u64 {
+            self.points(name, labels, |data| match data {
+                AggregatedMetrics::U64(MetricData::Sum(sum)) => sum
+                    .data_points()
+                    .map(|p| (p.attributes().cloned().collect(), p.value()))
+                    .collect(),
+                _ => vec![],
+            })
+        }
+
+        ///last value of the gauge `name`, same filter as [`MetricsReader::sum`]
+        pub fn gauge(&self, name: &str, labels: &[KeyValue]) -> u64 {
+            self.points(name, labels, |data| match data {
+                AggregatedMetrics::U64(MetricData::Gauge(gauge)) => gauge
+                    .data_points()
+                    .map(|p| (p.attributes().cloned().collect(), p.value()))
+                    .collect(),
+                _ => vec![],
+            })
+        }
+
+        ///number of values recorded by the histogram `name`, same filter as [`MetricsReader::sum`]
+        pub fn count(&self, name: &str, labels: &[KeyValue]) -> u64 {
+            self.points(name, labels, |data| match data {
+                AggregatedMetrics::F64(MetricData::Histogram(histogram)) => histogram
+                    .data_points()
+                    .map(|p| (p.attributes().cloned().collect(), p.count()))
+                    .collect(),
+                _ => vec![],
+            })
+        }
+
+        fn points(
+            &self,
+            name: &str,
+            labels: &[KeyValue],
+            points: impl Fn(&AggregatedMetrics) -> Vec<(Vec<KeyValue>, u64)>,
+        ) -> u64 {
+            let mut rm = ResourceMetrics::default();
+            self.reader
+                .collect(&mut rm)
+                .expect("failed to collect metrics");
+            rm.scope_metrics()
+                .flat_map(|scope| scope.metrics())
+                .filter(|metric| metric.name() == name)
+                .flat_map(|metric| points(metric.data()))
+                .filter(|(attributes, _)| labels.iter().all(|l| attributes.contains(l)))
+                .map(|(_, value)| value)
+                .sum()
+        }
+    }
+}